@@ -1,6 +1,7 @@
 use crate::lexer::Token;
+use crate::visitor::{walk_expr, Scope, Visitor};
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum Expr {
     Number(f64),
     Variable(String),
@@ -33,14 +34,128 @@ pub enum Expr {
         varnames: Vec<(String, Option<Expr>)>,
         body: Box<Expr>,
     },
+    Lambda {
+        params: Vec<String>,
+        body: Box<Expr>,
+    },
+    // assert(cond) with one argument, assert_eq(a, b) with two. Failures are reported with the
+    // source text of the assertion and where it is
+    Assert {
+        args: Vec<Expr>,
+        text: String,
+        location: String,
+    },
+    // Expressions evaluated in order, with the value of the last one (0 if empty). Only the
+    // bodies of test blocks are blocks
+    Block(Vec<Expr>),
+    // Where in the source the wrapped expression starts. The parser only adds these when
+    // generating debug information, see ParserContext::debug_info
+    Located {
+        line: u32,
+        col: u32,
+        expr: Box<Expr>,
+    },
     None,
 }
 
-#[derive(Debug)]
+impl Expr {
+    // Names this expression refers to that are not bound inside it, in order of first use.
+    // Call targets are included, since a call may go through a variable holding a closure
+    pub fn free_variables(&self) -> Vec<String> {
+        let mut collector = FreeVariables::default();
+        collector.visit_expr(self);
+        collector.free
+    }
+}
+
+#[derive(Default)]
+struct FreeVariables {
+    bound: Scope,
+    free: Vec<String>,
+}
+
+impl Visitor for FreeVariables {
+    fn visit_expr(&mut self, e: &Expr) {
+        if let Expr::Variable(name) | Expr::Call { identifier: name, .. } = e
+            && !self.bound.contains(name)
+            && !self.free.contains(name)
+        {
+            self.free.push(name.clone());
+        }
+        walk_expr(self, e);
+    }
+
+    fn bind(&mut self, name: &str) {
+        self.bound.bind(name);
+    }
+
+    fn unbind(&mut self, name: &str) {
+        self.bound.unbind(name);
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub struct Function {
     pub name: String,
     pub args: Vec<String>,
     pub body: Expr,
     pub is_operator: bool,
     pub precedence: Option<f64>,
+    // Module the function was imported from (None for the main program), and whether it
+    // may be used from other modules
+    pub module: Option<String>,
+    pub is_pub: bool,
+    // Shared library an extern is resolved from, as in extern "libfoo" name(args)
+    pub library: Option<String>,
+    // C signature of an extern declared with types, as in extern abs(n: i32) -> i32
+    pub signature: Option<Signature>,
+    // Name of a test "name" { ... } block, which is compiled as a function taking no arguments
+    pub test: Option<String>,
+    // Whether this is a const NAME = expr declaration. Its body is evaluated when the program is
+    // folded, which replaces the uses of the name with the value, see fold.rs
+    pub is_const: bool,
+    // Whether the function was parsed from the prelude, whose definitions a program may replace
+    pub from_prelude: bool,
+}
+
+// C types usable in extern signatures. Values are converted from and to doubles at the call
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CType {
+    I32,
+    I64,
+    F32,
+    F64,
+    Ptr,
+    Void,
+}
+
+impl CType {
+    pub fn from_name(name: &str) -> Option<CType> {
+        match name {
+            "i32" => Some(CType::I32),
+            "i64" => Some(CType::I64),
+            "f32" => Some(CType::F32),
+            "f64" => Some(CType::F64),
+            "ptr" => Some(CType::Ptr),
+            "void" => Some(CType::Void),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            CType::I32 => "i32",
+            CType::I64 => "i64",
+            CType::F32 => "f32",
+            CType::F64 => "f64",
+            CType::Ptr => "ptr",
+            CType::Void => "void",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Signature {
+    pub params: Vec<CType>,
+    pub ret: CType,
 }
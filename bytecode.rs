@@ -0,0 +1,827 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+use crate::ast::{CType, Expr, Function, Signature};
+use crate::lexer::Token;
+use crate::resolver;
+
+// A compact bytecode for a stack machine, compiled from the AST and run by the VM in vm.rs. A
+// compiled program can be saved to a .kbc file and run later without the source, the parser or
+// LLVM. Names are resolved when compiling, with the same rules and errors as code generation,
+// so running the bytecode only needs the externs resolved against an FFI registry
+
+// Every instruction pushes or pops doubles on the value stack. Locals are slots of the running
+// function's frame, and its arguments are its first slots
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Op {
+    Const(f64),
+    Load(u32),
+    // Store the top of the stack in a slot, leaving it on the stack
+    Store(u32),
+    Pop,
+    Add,
+    Sub,
+    Mul,
+    Div,
+    // Unordered comparisons, true if either side is NaN
+    Less,
+    Greater,
+    Jump(u32),
+    // Pop a condition and jump if it is 0 or NaN
+    JumpIfFalse(u32),
+    // Call a function with the arguments on top of the stack
+    Call(u32, u32),
+    // Call the function value below the arguments
+    CallValue(u32),
+    // Push a function as a value
+    Function(u32),
+    // Pop the values a lambda captures and push a closure of it
+    Closure(u32, u32),
+    // assert or assert_eq of the arguments, described by a string of the program
+    Assert(u32, u32),
+    Return,
+}
+
+// A compiled function. Externs have no code, and are resolved when the program is loaded
+#[derive(Debug, Clone, PartialEq)]
+pub struct Chunk {
+    pub name: String,
+    pub arity: u32,
+    // Slots of a frame, including the arguments and the values a lambda captures, which come
+    // first
+    pub locals: u32,
+    pub captures: u32,
+    pub code: Vec<Op>,
+    pub test: Option<String>,
+    // The extern declaration of a chunk without code
+    pub native: Option<Function>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Program {
+    pub chunks: Vec<Chunk>,
+    pub strings: Vec<String>,
+    // Chunk of the last top-level expression, which is what running the program runs
+    pub main: Option<u32>,
+}
+
+impl Program {
+    // Test blocks of the program, as (test name, chunk)
+    pub fn tests(&self) -> Vec<(String, u32)> {
+        self.chunks
+            .iter()
+            .enumerate()
+            .filter_map(|(i, c)| Some((c.test.clone()?, i as u32)))
+            .collect()
+    }
+}
+
+// Compile a parsed program. Chunks are numbered like the functions, followed by the lambdas
+pub fn compile(functions: &[Function]) -> Result<Program, String> {
+    resolver::resolve(functions)?;
+    let mut last = HashMap::new();
+    for (i, f) in functions.iter().enumerate() {
+        if f.name != "_top_level_expr" {
+            last.insert(f.name.as_str(), i);
+        }
+    }
+    let main = functions.iter().rposition(|f| f.name == "_top_level_expr");
+
+    let mut compiler = Compiler {
+        last,
+        function_count: functions.len(),
+        chunks: Vec::new(),
+        lambdas: Vec::new(),
+        strings: Vec::new(),
+    };
+    for (i, f) in functions.iter().enumerate() {
+        let mut chunk = Chunk {
+            name: f.name.clone(),
+            arity: f.args.len() as u32,
+            locals: f.args.len() as u32,
+            captures: 0,
+            code: Vec::new(),
+            test: f.test.clone(),
+            native: None,
+        };
+        if matches!(f.body, Expr::None) {
+            chunk.native = Some(extern_declaration(f));
+        } else if f.name != "_top_level_expr" || Some(i) == main {
+            // Only the last top-level expression is compiled, as it is the only one that runs
+            let mut scope = Scope::new(&f.args);
+            compiler.expr(&f.body, &mut scope)?;
+            scope.code.push(Op::Return);
+            chunk.locals = scope.locals;
+            chunk.code = scope.code;
+        }
+        compiler.chunks.push(chunk);
+    }
+    compiler.chunks.append(&mut compiler.lambdas);
+
+    Ok(Program {
+        chunks: compiler.chunks,
+        strings: compiler.strings,
+        main: main.map(|m| m as u32),
+    })
+}
+
+// Only what resolving an extern needs is kept
+fn extern_declaration(f: &Function) -> Function {
+    Function {
+        name: f.name.clone(),
+        args: f.args.clone(),
+        body: Expr::None,
+        is_operator: false,
+        precedence: None,
+        module: None,
+        is_pub: false,
+        library: f.library.clone(),
+        signature: f.signature.clone(),
+        test: None,
+        is_const: false,
+        from_prelude: false,
+    }
+}
+
+struct Compiler<'a> {
+    // Index of the last definition of each name, which is the one that is used, see
+    // resolver.rs
+    last: HashMap<&'a str, usize>,
+    function_count: usize,
+    chunks: Vec<Chunk>,
+    // Lambdas compiled so far, numbered after the functions
+    lambdas: Vec<Chunk>,
+    strings: Vec<String>,
+}
+
+// The function being compiled
+struct Scope {
+    vars: HashMap<String, u32>,
+    locals: u32,
+    code: Vec<Op>,
+}
+
+impl Scope {
+    fn new(args: &[String]) -> Self {
+        let mut scope = Scope {
+            vars: HashMap::new(),
+            locals: 0,
+            code: Vec::new(),
+        };
+        for arg in args {
+            scope.declare(arg);
+        }
+        scope
+    }
+
+    // Like the allocas of compiled code, each declaration gets a new slot
+    fn declare(&mut self, name: &str) -> Option<u32> {
+        let slot = self.temporary();
+        self.vars.insert(name.to_string(), slot)
+    }
+
+    fn temporary(&mut self) -> u32 {
+        self.locals += 1;
+        self.locals - 1
+    }
+
+    fn here(&self) -> u32 {
+        self.code.len() as u32
+    }
+
+    // Point a jump emitted earlier at the current position
+    fn patch(&mut self, jump: u32) {
+        let target = self.here();
+        match &mut self.code[jump as usize] {
+            Op::Jump(t) | Op::JumpIfFalse(t) => *t = target,
+            op => unreachable!("patching {:?}", op),
+        }
+    }
+}
+
+impl<'a> Compiler<'a> {
+    fn lookup(&self, name: &str) -> Option<u32> {
+        self.last.get(name).map(|i| *i as u32)
+    }
+
+    fn expr(&mut self, expr: &'a Expr, scope: &mut Scope) -> Result<(), String> {
+        match expr {
+            Expr::Number(value) => scope.code.push(Op::Const(*value)),
+            Expr::Variable(name) => match scope.vars.get(name) {
+                Some(slot) => scope.code.push(Op::Load(*slot)),
+                None => {
+                    // Function names used as values evaluate to that function
+                    let index = self
+                        .lookup(name)
+                        .ok_or_else(|| format!("Unknown variable: {}", name))?;
+                    scope.code.push(Op::Function(index));
+                }
+            },
+            Expr::BinOp { left, op, right } => {
+                if let (Token::Assign(_), Expr::Variable(name)) = (op, left.as_ref()) {
+                    let slot = *scope
+                        .vars
+                        .get(name)
+                        .ok_or_else(|| format!("Unknown variable: {}", name))?;
+                    self.expr(right, scope)?;
+                    scope.code.push(Op::Store(slot));
+                    return Ok(());
+                }
+
+                let c = match op {
+                    Token::Plus(c)
+                    | Token::Minus(c)
+                    | Token::Star(c)
+                    | Token::Slash(c)
+                    | Token::Less(c)
+                    | Token::Greater(c)
+                    | Token::Bang(c)
+                    | Token::Pipe(c)
+                    | Token::Ampersand(c)
+                    | Token::Caret(c)
+                    | Token::Percent(c)
+                    | Token::Dollar(c)
+                    | Token::At(c)
+                    | Token::Tilde(c) => *c,
+                    _ => return Err(format!("Unknown token type: {:?}", op)),
+                };
+                self.expr(left, scope)?;
+                self.expr(right, scope)?;
+
+                // User-defined operators take precedence over the built-in ones
+                let instruction = match self.lookup(&format!("binary{}", c)) {
+                    Some(index) => Op::Call(index, 2),
+                    None => match op {
+                        Token::Plus(_) => Op::Add,
+                        Token::Minus(_) => Op::Sub,
+                        Token::Star(_) => Op::Mul,
+                        Token::Slash(_) => Op::Div,
+                        Token::Less(_) => Op::Less,
+                        Token::Greater(_) => Op::Greater,
+                        _ => return Err(format!("Unknown binary operator: {:?}", op)),
+                    },
+                };
+                scope.code.push(instruction);
+            }
+            Expr::Unary { op, left } => {
+                let name = format!("unary{}", op);
+                let index = self
+                    .lookup(&name)
+                    .ok_or_else(|| format!("Unknown unary operator: {}", name))?;
+                self.expr(left, scope)?;
+                scope.code.push(Op::Call(index, 1));
+            }
+            Expr::If {
+                condition,
+                then,
+                els,
+            } => {
+                self.expr(condition, scope)?;
+                let to_else = scope.here();
+                scope.code.push(Op::JumpIfFalse(0));
+                self.expr(then, scope)?;
+                let to_end = scope.here();
+                scope.code.push(Op::Jump(0));
+                scope.patch(to_else);
+                self.expr(els, scope)?;
+                scope.patch(to_end);
+            }
+            Expr::For {
+                ident,
+                start,
+                end,
+                step,
+                body,
+            } => {
+                // The end condition is checked before each iteration, so the body may not run
+                // at all
+                self.expr(start, scope)?;
+                let old = scope.vars.get(ident).copied();
+                scope.declare(ident);
+                let slot = scope.vars[ident];
+                scope.code.extend([Op::Store(slot), Op::Pop]);
+
+                let top = scope.here();
+                self.expr(end, scope)?;
+                let to_exit = scope.here();
+                scope.code.push(Op::JumpIfFalse(0));
+                self.expr(body, scope)?;
+                scope.code.push(Op::Pop);
+                match step {
+                    Some(s) => self.expr(s, scope)?,
+                    None => scope.code.push(Op::Const(1.0)),
+                }
+                scope.code.extend([
+                    Op::Load(slot),
+                    Op::Add,
+                    Op::Store(slot),
+                    Op::Pop,
+                    Op::Jump(top),
+                ]);
+                scope.patch(to_exit);
+                scope.code.push(Op::Const(0.0));
+
+                match old {
+                    Some(old) => scope.vars.insert(ident.clone(), old),
+                    None => scope.vars.remove(ident),
+                };
+            }
+            Expr::Var { varnames, body } => {
+                // The names are bound in the body only. Shadowed ones are restored in
+                // reverse, so a name declared twice gets back what it had before both
+                let mut old_bindings = Vec::new();
+                for (name, init) in varnames {
+                    match init {
+                        Some(e) => self.expr(e, scope)?,
+                        None => scope.code.push(Op::Const(0.0)),
+                    }
+                    old_bindings.push((name, scope.declare(name)));
+                    scope.code.extend([Op::Store(scope.vars[name]), Op::Pop]);
+                }
+
+                self.expr(body, scope)?;
+                for (name, old) in old_bindings.into_iter().rev() {
+                    match old {
+                        Some(old) => scope.vars.insert(name.clone(), old),
+                        None => scope.vars.remove(name),
+                    };
+                }
+            }
+            Expr::Call { identifier, args } => {
+                // A variable in scope shadows any function of the same name
+                if let Some(slot) = scope.vars.get(identifier) {
+                    scope.code.push(Op::Load(*slot));
+                    for arg in args {
+                        self.expr(arg, scope)?;
+                    }
+                    scope.code.push(Op::CallValue(args.len() as u32));
+                    return Ok(());
+                }
+
+                let index = self
+                    .lookup(identifier)
+                    .ok_or_else(|| format!("Unknown function: {}", identifier))?;
+                for arg in args {
+                    self.expr(arg, scope)?;
+                }
+                scope.code.push(Op::Call(index, args.len() as u32));
+            }
+            Expr::Lambda { params, body } => {
+                // Captured variables are copied when the lambda is created
+                let captures: Vec<String> = expr
+                    .free_variables()
+                    .into_iter()
+                    .filter(|name| scope.vars.contains_key(name))
+                    .collect();
+                for name in &captures {
+                    scope.code.push(Op::Load(scope.vars[name]));
+                }
+
+                let mut lambda = Scope::new(&captures);
+                for param in params {
+                    lambda.declare(param);
+                }
+                self.expr(body, &mut lambda)?;
+                lambda.code.push(Op::Return);
+
+                let index = (self.function_count + self.lambdas.len()) as u32;
+                self.lambdas.push(Chunk {
+                    name: format!("__lambda.{}", self.lambdas.len()),
+                    arity: params.len() as u32,
+                    locals: lambda.locals,
+                    captures: captures.len() as u32,
+                    code: lambda.code,
+                    test: None,
+                    native: None,
+                });
+                scope.code.push(Op::Closure(index, captures.len() as u32));
+            }
+            Expr::Assert {
+                args,
+                text,
+                location,
+            } => {
+                for arg in args {
+                    self.expr(arg, scope)?;
+                }
+                self.strings.push(format!("{}: {}", location, text));
+                let what = self.strings.len() as u32 - 1;
+                scope.code.push(Op::Assert(what, args.len() as u32));
+            }
+            Expr::Block(exprs) => {
+                if exprs.is_empty() {
+                    scope.code.push(Op::Const(0.0));
+                }
+                for (i, expr) in exprs.iter().enumerate() {
+                    if i > 0 {
+                        scope.code.push(Op::Pop);
+                    }
+                    self.expr(expr, scope)?;
+                }
+            }
+            Expr::Located { expr, .. } => self.expr(expr, scope)?,
+            Expr::None => return Err(format!("Unhandled expression: {:?}", expr)),
+        }
+        Ok(())
+    }
+}
+
+// .kbc files start with this, followed by a version number
+const MAGIC: &[u8; 3] = b"KBC";
+const VERSION: u8 = 1;
+
+// The file format is little-endian. Strings are a u32 length and UTF-8 bytes, optional values a
+// 0 or 1 byte followed by the value, and lists a u32 count followed by the items
+impl Program {
+    pub fn save(&self, path: &Path) -> Result<(), String> {
+        fs::write(path, self.to_bytes())
+            .map_err(|e| format!("Failed to write {}: {}", path.display(), e))
+    }
+
+    pub fn load(path: &Path) -> Result<Program, String> {
+        let bytes =
+            fs::read(path).map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+        Program::from_bytes(&bytes).map_err(|e| format!("{}: {}", path.display(), e))
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut w = Writer(Vec::new());
+        w.0.extend(MAGIC);
+        w.u8(VERSION);
+        w.option(self.main, |w, m| w.u32(m));
+        w.u32(self.strings.len() as u32);
+        for string in &self.strings {
+            w.str(string);
+        }
+
+        w.u32(self.chunks.len() as u32);
+        for chunk in &self.chunks {
+            w.str(&chunk.name);
+            w.u32(chunk.arity);
+            w.u32(chunk.locals);
+            w.u32(chunk.captures);
+            w.option(chunk.test.as_deref(), |w, t| w.str(t));
+            w.option(chunk.native.as_ref(), |w, f| {
+                w.u32(f.args.len() as u32);
+                for arg in &f.args {
+                    w.str(arg);
+                }
+                w.option(f.library.as_deref(), |w, l| w.str(l));
+                w.option(f.signature.as_ref(), |w, sig| {
+                    w.u32(sig.params.len() as u32);
+                    for param in &sig.params {
+                        w.u8(ctype_tag(*param));
+                    }
+                    w.u8(ctype_tag(sig.ret));
+                });
+            });
+            w.u32(chunk.code.len() as u32);
+            for op in &chunk.code {
+                w.op(op);
+            }
+        }
+        w.0
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Program, String> {
+        let mut r = Reader { bytes, pos: 0 };
+        if r.take(MAGIC.len())? != MAGIC {
+            return Err(String::from("Not a bytecode file"));
+        }
+        let version = r.u8()?;
+        if version != VERSION {
+            return Err(format!(
+                "Unsupported bytecode version {} (expected {})",
+                version, VERSION
+            ));
+        }
+        let main = r.option(|r| r.u32())?;
+        let strings = r.list(|r| r.str())?;
+
+        let chunks = r.list(|r| {
+            let name = r.str()?;
+            let arity = r.u32()?;
+            let locals = r.u32()?;
+            let captures = r.u32()?;
+            let test = r.option(|r| r.str())?;
+            let native = r.option(|r| {
+                let args = r.list(|r| r.str())?;
+                let library = r.option(|r| r.str())?;
+                let signature = r.option(|r| {
+                    Ok(Signature {
+                        params: r.list(|r| ctype(r.u8()?))?,
+                        ret: ctype(r.u8()?)?,
+                    })
+                })?;
+                Ok(extern_declaration(&Function {
+                    name: name.clone(),
+                    args,
+                    body: Expr::None,
+                    is_operator: false,
+                    precedence: None,
+                    module: None,
+                    is_pub: false,
+                    library,
+                    signature,
+                    test: None,
+                    is_const: false,
+                    from_prelude: false,
+                }))
+            })?;
+            let code = r.list(|r| r.op())?;
+            Ok(Chunk {
+                name,
+                arity,
+                locals,
+                captures,
+                code,
+                test,
+                native,
+            })
+        })?;
+        if r.pos != bytes.len() {
+            return Err(String::from("Invalid bytecode: trailing data"));
+        }
+
+        let program = Program {
+            chunks,
+            strings,
+            main,
+        };
+        program.validate()?;
+        Ok(program)
+    }
+
+    // Check the references between chunks and how each chunk uses the stack, so a damaged file
+    // can't make the VM index out of bounds
+    fn validate(&self) -> Result<(), String> {
+        let invalid = |what: String| Err(format!("Invalid bytecode: {}", what));
+        let chunks = self.chunks.len() as u32;
+        if self.main.is_some_and(|m| m >= chunks) {
+            return invalid(String::from("main is not a chunk"));
+        }
+        for chunk in &self.chunks {
+            if chunk.captures > chunk.locals || chunk.arity + chunk.captures > chunk.locals {
+                return invalid(format!("{} has more arguments than slots", chunk.name));
+            }
+            for op in &chunk.code {
+                let ok = match *op {
+                    Op::Load(slot) | Op::Store(slot) => slot < chunk.locals,
+                    Op::Jump(target) | Op::JumpIfFalse(target) => {
+                        (target as usize) < chunk.code.len()
+                    }
+                    // Only lambdas have captures, and only closures provide them
+                    Op::Call(index, _) | Op::Function(index) => {
+                        index < chunks && self.chunks[index as usize].captures == 0
+                    }
+                    Op::Closure(index, captures) => {
+                        index < chunks && self.chunks[index as usize].captures == captures
+                    }
+                    Op::Assert(what, argc) => {
+                        (what as usize) < self.strings.len() && (1..=2).contains(&argc)
+                    }
+                    _ => true,
+                };
+                if !ok {
+                    return invalid(format!("{:?} in {}", op, chunk.name));
+                }
+            }
+            if !chunk.code.is_empty() {
+                check_stack(chunk).or_else(invalid)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+// Follow every path through the code of a chunk with the depth of its stack, checking that no
+// op pops more values than there are, that each op is always reached with the same depth, and
+// that every path ends in a Return instead of running off the end of the code
+fn check_stack(chunk: &Chunk) -> Result<(), String> {
+    let mut depths: Vec<Option<u32>> = vec![None; chunk.code.len()];
+    let mut pending = vec![(0, 0)];
+    while let Some((pc, depth)) = pending.pop() {
+        let op = match chunk.code.get(pc) {
+            Some(op) => *op,
+            None => return Err(format!("{} runs past the end of its code", chunk.name)),
+        };
+        match depths[pc] {
+            Some(known) if known == depth => continue,
+            Some(_) => return Err(format!("{:?} in {} is reached with different stacks", op, chunk.name)),
+            None => depths[pc] = Some(depth),
+        }
+
+        let (pops, pushes) = match op {
+            Op::Const(_) | Op::Load(_) | Op::Function(_) => (0, 1),
+            Op::Store(_) => (1, 1),
+            Op::Pop | Op::JumpIfFalse(_) | Op::Return => (1, 0),
+            Op::Add | Op::Sub | Op::Mul | Op::Div | Op::Less | Op::Greater => (2, 1),
+            Op::Jump(_) => (0, 0),
+            Op::Call(_, argc) | Op::Closure(_, argc) | Op::Assert(_, argc) => (argc, 1),
+            Op::CallValue(argc) => (argc.saturating_add(1), 1),
+        };
+        if depth < pops {
+            return Err(format!("{:?} in {} pops more values than the stack has", op, chunk.name));
+        }
+        let depth = depth - pops + pushes;
+        match op {
+            Op::Return => {}
+            Op::Jump(target) => pending.push((target as usize, depth)),
+            Op::JumpIfFalse(target) => pending.extend([(target as usize, depth), (pc + 1, depth)]),
+            _ => pending.push((pc + 1, depth)),
+        }
+    }
+    Ok(())
+}
+
+fn ctype_tag(ty: CType) -> u8 {
+    match ty {
+        CType::I32 => 0,
+        CType::I64 => 1,
+        CType::F32 => 2,
+        CType::F64 => 3,
+        CType::Ptr => 4,
+        CType::Void => 5,
+    }
+}
+
+fn ctype(tag: u8) -> Result<CType, String> {
+    Ok(match tag {
+        0 => CType::I32,
+        1 => CType::I64,
+        2 => CType::F32,
+        3 => CType::F64,
+        4 => CType::Ptr,
+        5 => CType::Void,
+        _ => return Err(format!("Invalid bytecode: unknown C type {}", tag)),
+    })
+}
+
+struct Writer(Vec<u8>);
+
+impl Writer {
+    fn u8(&mut self, value: u8) {
+        self.0.push(value);
+    }
+
+    fn u32(&mut self, value: u32) {
+        self.0.extend(value.to_le_bytes());
+    }
+
+    fn f64(&mut self, value: f64) {
+        self.0.extend(value.to_le_bytes());
+    }
+
+    fn str(&mut self, value: &str) {
+        self.u32(value.len() as u32);
+        self.0.extend(value.as_bytes());
+    }
+
+    fn option<T>(&mut self, value: Option<T>, write: impl FnOnce(&mut Self, T)) {
+        match value {
+            Some(v) => {
+                self.u8(1);
+                write(self, v);
+            }
+            None => self.u8(0),
+        }
+    }
+
+    fn op(&mut self, op: &Op) {
+        match *op {
+            Op::Const(value) => {
+                self.u8(0);
+                self.f64(value);
+            }
+            Op::Load(slot) => {
+                self.u8(1);
+                self.u32(slot);
+            }
+            Op::Store(slot) => {
+                self.u8(2);
+                self.u32(slot);
+            }
+            Op::Pop => self.u8(3),
+            Op::Add => self.u8(4),
+            Op::Sub => self.u8(5),
+            Op::Mul => self.u8(6),
+            Op::Div => self.u8(7),
+            Op::Less => self.u8(8),
+            Op::Greater => self.u8(9),
+            Op::Jump(target) => {
+                self.u8(10);
+                self.u32(target);
+            }
+            Op::JumpIfFalse(target) => {
+                self.u8(11);
+                self.u32(target);
+            }
+            Op::Call(index, argc) => {
+                self.u8(12);
+                self.u32(index);
+                self.u32(argc);
+            }
+            Op::CallValue(argc) => {
+                self.u8(13);
+                self.u32(argc);
+            }
+            Op::Function(index) => {
+                self.u8(14);
+                self.u32(index);
+            }
+            Op::Closure(index, captures) => {
+                self.u8(15);
+                self.u32(index);
+                self.u32(captures);
+            }
+            Op::Assert(what, argc) => {
+                self.u8(16);
+                self.u32(what);
+                self.u32(argc);
+            }
+            Op::Return => self.u8(17),
+        }
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl Reader<'_> {
+    fn take(&mut self, n: usize) -> Result<&[u8], String> {
+        let bytes = self
+            .bytes
+            .get(self.pos..self.pos + n)
+            .ok_or("Invalid bytecode: unexpected end of file")?;
+        self.pos += n;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8, String> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> Result<u32, String> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn f64(&mut self) -> Result<f64, String> {
+        Ok(f64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    fn str(&mut self) -> Result<String, String> {
+        let len = self.u32()? as usize;
+        String::from_utf8(self.take(len)?.to_vec())
+            .map_err(|_| String::from("Invalid bytecode: string is not UTF-8"))
+    }
+
+    fn option<T>(
+        &mut self,
+        read: impl FnOnce(&mut Self) -> Result<T, String>,
+    ) -> Result<Option<T>, String> {
+        match self.u8()? {
+            0 => Ok(None),
+            1 => read(self).map(Some),
+            tag => Err(format!("Invalid bytecode: bad option tag {}", tag)),
+        }
+    }
+
+    fn list<T>(
+        &mut self,
+        mut read: impl FnMut(&mut Self) -> Result<T, String>,
+    ) -> Result<Vec<T>, String> {
+        let len = self.u32()? as usize;
+        // Every item takes at least a byte, which bounds what a damaged count can allocate
+        if len > self.bytes.len() - self.pos {
+            return Err(String::from("Invalid bytecode: unexpected end of file"));
+        }
+        (0..len).map(|_| read(self)).collect()
+    }
+
+    fn op(&mut self) -> Result<Op, String> {
+        Ok(match self.u8()? {
+            0 => Op::Const(self.f64()?),
+            1 => Op::Load(self.u32()?),
+            2 => Op::Store(self.u32()?),
+            3 => Op::Pop,
+            4 => Op::Add,
+            5 => Op::Sub,
+            6 => Op::Mul,
+            7 => Op::Div,
+            8 => Op::Less,
+            9 => Op::Greater,
+            10 => Op::Jump(self.u32()?),
+            11 => Op::JumpIfFalse(self.u32()?),
+            12 => Op::Call(self.u32()?, self.u32()?),
+            13 => Op::CallValue(self.u32()?),
+            14 => Op::Function(self.u32()?),
+            15 => Op::Closure(self.u32()?, self.u32()?),
+            16 => Op::Assert(self.u32()?, self.u32()?),
+            17 => Op::Return,
+            tag => return Err(format!("Invalid bytecode: unknown instruction {}", tag)),
+        })
+    }
+}
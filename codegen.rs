@@ -1,38 +1,72 @@
 // Standard library
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
 
 // Our crate
-use crate::ast::{Expr, Function};
-use crate::externs::FfiRegistry;
+use crate::ast::{CType, Expr, Function, Signature};
+use crate::debuginfo::DebugInfo;
+use crate::externs::{self, FfiRegistry, HostFunction, ResolvedExtern};
 use crate::lexer::Token;
 use crate::parser::ParserContext;
 
 // Inkwell
 use inkwell::{
-    builder::Builder, context::Context, module::Module, values::BasicMetadataValueEnum,
+    builder::Builder, context::Context, execution_engine::ExecutionEngine, intrinsics::Intrinsic, module::Linkage, module::Module,
+    targets::CodeModel, targets::FileType, targets::InitializationConfig, targets::RelocMode, targets::Target,
+    targets::TargetMachine, OptimizationLevel, passes::PassBuilderOptions,
+    types::BasicMetadataTypeEnum, types::BasicType, types::BasicTypeEnum, types::FunctionType, values::BasicMetadataValueEnum,
     values::BasicValueEnum, values::FloatValue, values::FunctionValue, values::PointerValue,
+    AddressSpace,
 };
 
 pub type CGResult<'ctx> = Result<Option<BasicValueEnum<'ctx>>, String>;
 
+// Math externs that LLVM knows as intrinsics, so the optimizer can fold calls with constant
+// arguments. The rest of the math library is only reachable through the FFI registry
+const MATH_INTRINSICS: [(&str, usize, &str); 9] = [
+    ("sin", 1, "llvm.sin"),
+    ("cos", 1, "llvm.cos"),
+    ("sqrt", 1, "llvm.sqrt"),
+    ("exp", 1, "llvm.exp"),
+    ("log", 1, "llvm.log"),
+    ("pow", 2, "llvm.pow"),
+    ("floor", 1, "llvm.floor"),
+    ("ceil", 1, "llvm.ceil"),
+    ("fabs", 1, "llvm.fabs"),
+];
+
 pub struct CodegenContext<'ctx> {
     pub context: &'ctx Context,
     pub builder: Builder<'ctx>,
     pub module: Module<'ctx>,
     pub vars: HashMap<String, PointerValue<'ctx>>,
+    // LLVM names of functions whose own name is taken in the execution engine by an earlier
+    // definition, which they replace, see Engine. Other functions keep their names
+    pub symbols: HashMap<String, String>,
+    // Externs that may become math intrinsics: declared without a C signature or a library, and
+    // not registered as host closures
+    math_externs: HashSet<String>,
     main_entry: inkwell::basic_block::BasicBlock<'ctx>,
     last_result: Option<BasicValueEnum<'ctx>>,
+    // Set by enable_debug_info
+    debug: Option<DebugInfo<'ctx>>,
 }
 
 impl<'ctx> CodegenContext<'ctx> {
     pub fn new(context: &'ctx Context, module_name: &str) -> Self {
+        Self::with_entry(context, module_name, "main")
+    }
+
+    // Like new(), but with top-level expressions going into a function called `entry_name`, so
+    // several modules can live in the same execution engine
+    pub fn with_entry(context: &'ctx Context, module_name: &str, entry_name: &str) -> Self {
         let builder = context.create_builder();
         let module = context.create_module(module_name);
 
         // Create main() function upfront to hold all top-level expressions
         let f64_type = context.f64_type();
         let main_fn_type = f64_type.fn_type(&[], false);
-        let main_func = module.add_function("main", main_fn_type, None);
+        let main_func = module.add_function(entry_name, main_fn_type, None);
         let main_entry = context.append_basic_block(main_func, "entry");
         builder.position_at_end(main_entry);
 
@@ -41,8 +75,64 @@ impl<'ctx> CodegenContext<'ctx> {
             builder,
             module,
             vars: HashMap::new(),
+            symbols: HashMap::new(),
+            math_externs: HashSet::new(),
             main_entry,
             last_result: None,
+            debug: None,
+        }
+    }
+
+    // Generate DWARF for the functions generated from now on, with `path` as the source file.
+    // Line information comes from the Expr::Located nodes of a parser with debug_info set
+    pub fn enable_debug_info(&mut self, path: &Path) -> Result<(), String> {
+        let mut debug = DebugInfo::new(&self.module, path)?;
+        let main_func = self.main_entry.get_parent().unwrap();
+        let name = main_func.get_name().to_string_lossy().into_owned();
+        debug.enter_function(self.context, main_func, &name, 0);
+        self.debug = Some(debug);
+        self.restore_debug_location();
+        Ok(())
+    }
+
+    // Start the debug information of a function with `body`. Only bodies from the source file
+    // have a location, the code of the prelude and of imported modules gets no debug information.
+    // Returns whether the function has any, for leave_debug_function
+    fn enter_debug_function(&mut self, func: FunctionValue<'ctx>, name: &str, body: &Expr) -> bool {
+        match (&mut self.debug, body) {
+            (Some(debug), Expr::Located { line, .. }) => {
+                debug.enter_function(self.context, func, name, *line);
+                self.restore_debug_location();
+                true
+            }
+            _ => {
+                self.builder.unset_current_debug_location();
+                false
+            }
+        }
+    }
+
+    fn leave_debug_function(&mut self, entered: bool) {
+        if let Some(debug) = &mut self.debug
+            && entered
+        {
+            debug.leave_function();
+        }
+        self.restore_debug_location();
+    }
+
+    fn set_debug_location(&mut self, line: u32, col: u32) {
+        if let Some(debug) = &mut self.debug {
+            debug.set_location(self.context, line, col);
+            self.restore_debug_location();
+        }
+    }
+
+    // Point the builder back at the current location in the function being generated, after
+    // building code that has no debug information
+    fn restore_debug_location(&self) {
+        if let Some(location) = self.debug.as_ref().and_then(|d| d.location()) {
+            self.builder.set_current_debug_location(location);
         }
     }
 
@@ -51,7 +141,31 @@ impl<'ctx> CodegenContext<'ctx> {
         f: &FunctionValue,
         name: String,
     ) -> Result<PointerValue<'ctx>, String> {
-        let entry = f.get_last_basic_block().unwrap();
+        let alloc = self.build_entryblock_alloca(f, &name)?;
+        self.declare_debug_variable(alloc, &name, None);
+        Ok(alloc)
+    }
+
+    // Like create_entryblock_alloc, for the slot of parameter number `arg`, starting at 1
+    pub fn create_parameter_alloc(
+        &mut self,
+        f: &FunctionValue,
+        name: String,
+        arg: u32,
+    ) -> Result<PointerValue<'ctx>, String> {
+        let alloc = self.build_entryblock_alloca(f, &name)?;
+        self.declare_debug_variable(alloc, &name, Some(arg));
+        Ok(alloc)
+    }
+
+    fn declare_debug_variable(&self, alloc: PointerValue<'ctx>, name: &str, arg: Option<u32>) {
+        if let (Some(debug), Some(block)) = (&self.debug, self.builder.get_insert_block()) {
+            debug.declare_variable(alloc, name, arg, block);
+        }
+    }
+
+    fn build_entryblock_alloca(&self, f: &FunctionValue, name: &str) -> Result<PointerValue<'ctx>, String> {
+        let entry = f.get_first_basic_block().unwrap();
 
         let entry_builder = self.context.create_builder();
 
@@ -61,12 +175,283 @@ impl<'ctx> CodegenContext<'ctx> {
             entry_builder.position_at_end(entry);
         }
 
-        match entry_builder.build_alloca(self.context.f64_type(), name.as_str()) {
+        match entry_builder.build_alloca(self.context.f64_type(), name) {
             Ok(r) => Ok(r),
             Err(e) => Err(e.to_string()),
         }
     }
 
+    // Every value in the language is a double, so function values are passed around as the bit
+    // pattern of a pointer to a closure record, reinterpreted as an f64. A record starts with a
+    // code pointer followed by any captured values, and the code takes the record itself as a
+    // hidden first argument
+    pub fn closure_to_f64(&self, record: PointerValue<'ctx>) -> Result<FloatValue<'ctx>, String> {
+        let addr = self
+            .builder
+            .build_ptr_to_int(record, self.context.i64_type(), "closureaddr")
+            .map_err(|e| format!("Failed to build ptrtoint: {}", e))?;
+        self.builder
+            .build_bit_cast(addr, self.context.f64_type(), "closure")
+            .map(|v| v.into_float_value())
+            .map_err(|e| format!("Failed to build bitcast: {}", e))
+    }
+
+    pub fn f64_to_closure(&self, val: FloatValue<'ctx>) -> Result<PointerValue<'ctx>, String> {
+        let addr = self
+            .builder
+            .build_bit_cast(val, self.context.i64_type(), "closureaddr")
+            .map_err(|e| format!("Failed to build bitcast: {}", e))?
+            .into_int_value();
+        self.builder
+            .build_int_to_ptr(
+                addr,
+                self.context.ptr_type(AddressSpace::default()),
+                "env",
+            )
+            .map_err(|e| format!("Failed to build inttoptr: {}", e))
+    }
+
+    // Named functions get a constant closure record with no captures, whose code is a trampoline
+    // that drops the environment argument and forwards to the function
+    pub fn function_closure(
+        &mut self,
+        func: FunctionValue<'ctx>,
+    ) -> Result<PointerValue<'ctx>, String> {
+        let name = format!("{}.closure", func.get_name().to_string_lossy());
+        if let Some(global) = self.module.get_global(&name) {
+            return Ok(global.as_pointer_value());
+        }
+
+        let f64 = self.context.f64_type();
+        let ptr = self.context.ptr_type(AddressSpace::default());
+        let mut param_types: Vec<BasicMetadataTypeEnum> = vec![ptr.into()];
+        param_types.extend(vec![BasicMetadataTypeEnum::from(f64); func.count_params() as usize]);
+        let trampoline = self.module.add_function(
+            &format!("{}.code", name),
+            f64.fn_type(&param_types, false),
+            Some(Linkage::Private),
+        );
+
+        // Come back to the current insertion point once the trampoline is built. Trampolines
+        // have no debug information
+        let saved_block = self.builder.get_insert_block();
+        self.builder.unset_current_debug_location();
+        self.builder
+            .position_at_end(self.context.append_basic_block(trampoline, "entry"));
+        let args: Vec<FloatValue> = trampoline
+            .get_param_iter()
+            .skip(1)
+            .map(|p| p.into_float_value())
+            .collect();
+        let ret = self.build_direct_call(func, &args)?;
+        self.builder
+            .build_return(Some(&ret))
+            .map_err(|e| format!("Failed to build return: {}", e))?;
+        if let Some(block) = saved_block {
+            self.builder.position_at_end(block);
+        }
+        self.restore_debug_location();
+
+        let record_ty = self.context.struct_type(&[ptr.into()], false);
+        let global = self.module.add_global(record_ty, None, &name);
+        global.set_initializer(&self.context.const_struct(
+            &[trampoline.as_global_value().as_pointer_value().into()],
+            false,
+        ));
+        global.set_constant(true);
+        global.set_linkage(Linkage::Private);
+        Ok(global.as_pointer_value())
+    }
+
+    // Call a function by name, converting the double arguments to its parameter types and the
+    // result back to a double. Only externs with a C signature need any conversion
+    pub fn build_direct_call(
+        &self,
+        func: FunctionValue<'ctx>,
+        args: &[FloatValue<'ctx>],
+    ) -> Result<FloatValue<'ctx>, String> {
+        let param_types = func.get_type().get_param_types();
+        let mut cargs: Vec<BasicMetadataValueEnum> = Vec::new();
+        for (arg, ty) in args.iter().zip(param_types) {
+            cargs.push(self.f64_to_ctype(*arg, ty.try_into().map_err(|_| {
+                format!("Unsupported parameter type in call to {:?}", func.get_name())
+            })?)?);
+        }
+
+        let call = self
+            .builder
+            .build_call(func, &cargs, "calltmp")
+            .map_err(|e| format!("Failed to build call: {}", e))?;
+        self.ctype_to_f64(call.try_as_basic_value().left())
+    }
+
+    fn f64_to_ctype(
+        &self,
+        val: FloatValue<'ctx>,
+        ty: BasicTypeEnum<'ctx>,
+    ) -> Result<BasicMetadataValueEnum<'ctx>, String> {
+        let converted: BasicValueEnum = match ty {
+            BasicTypeEnum::FloatType(t) if t == self.context.f64_type() => val.into(),
+            BasicTypeEnum::FloatType(t) => self
+                .builder
+                .build_float_trunc(val, t, "ffiarg")
+                .map_err(|e| format!("Failed to build fptrunc: {}", e))?
+                .into(),
+            BasicTypeEnum::IntType(t) => self
+                .builder
+                .build_float_to_signed_int(val, t, "ffiarg")
+                .map_err(|e| format!("Failed to build fptosi: {}", e))?
+                .into(),
+            // Pointers are passed around as their address, as a number
+            BasicTypeEnum::PointerType(t) => {
+                let addr = self
+                    .builder
+                    .build_float_to_signed_int(val, self.context.i64_type(), "ffiaddr")
+                    .map_err(|e| format!("Failed to build fptosi: {}", e))?;
+                self.builder
+                    .build_int_to_ptr(addr, t, "ffiarg")
+                    .map_err(|e| format!("Failed to build inttoptr: {}", e))?
+                    .into()
+            }
+            t => return Err(format!("Unsupported FFI parameter type: {:?}", t)),
+        };
+        Ok(converted.into())
+    }
+
+    fn ctype_to_f64(&self, val: Option<BasicValueEnum<'ctx>>) -> Result<FloatValue<'ctx>, String> {
+        let f64 = self.context.f64_type();
+        match val {
+            // void functions evaluate to 0
+            None => Ok(f64.const_float(0.0)),
+            Some(BasicValueEnum::FloatValue(v)) if v.get_type() == f64 => Ok(v),
+            Some(BasicValueEnum::FloatValue(v)) => self
+                .builder
+                .build_float_ext(v, f64, "ffiret")
+                .map_err(|e| format!("Failed to build fpext: {}", e)),
+            Some(BasicValueEnum::IntValue(v)) => self
+                .builder
+                .build_signed_int_to_float(v, f64, "ffiret")
+                .map_err(|e| format!("Failed to build sitofp: {}", e)),
+            Some(BasicValueEnum::PointerValue(v)) => {
+                let addr = self
+                    .builder
+                    .build_ptr_to_int(v, self.context.i64_type(), "ffiaddr")
+                    .map_err(|e| format!("Failed to build ptrtoint: {}", e))?;
+                self.builder
+                    .build_signed_int_to_float(addr, f64, "ffiret")
+                    .map_err(|e| format!("Failed to build sitofp: {}", e))
+            }
+            Some(v) => Err(format!("Unsupported FFI return value: {:?}", v)),
+        }
+    }
+
+    fn c_function_type(&self, sig: &Signature) -> FunctionType<'ctx> {
+        let ptr = self.context.ptr_type(AddressSpace::default());
+        let basic = |ty: CType| -> BasicTypeEnum<'ctx> {
+            match ty {
+                CType::I32 => self.context.i32_type().into(),
+                CType::I64 => self.context.i64_type().into(),
+                CType::F32 => self.context.f32_type().into(),
+                CType::F64 | CType::Void => self.context.f64_type().into(),
+                CType::Ptr => ptr.into(),
+            }
+        };
+        let params: Vec<BasicMetadataTypeEnum> =
+            sig.params.iter().map(|t| basic(*t).into()).collect();
+        match sig.ret {
+            CType::Void => self.context.void_type().fn_type(&params, false),
+            ret => basic(ret).fn_type(&params, false),
+        }
+    }
+
+    pub fn build_closure_call(
+        &self,
+        closure: FloatValue<'ctx>,
+        args: &[BasicMetadataValueEnum<'ctx>],
+    ) -> Result<FloatValue<'ctx>, String> {
+        let f64 = self.context.f64_type();
+        let ptr = self.context.ptr_type(AddressSpace::default());
+
+        let env = self.f64_to_closure(closure)?;
+        let code = self
+            .builder
+            .build_load(ptr, env, "code")
+            .map_err(|e| format!("Failed to build load: {}", e))?
+            .into_pointer_value();
+
+        let mut param_types: Vec<BasicMetadataTypeEnum> = vec![ptr.into()];
+        param_types.extend(vec![BasicMetadataTypeEnum::from(f64); args.len()]);
+        let mut call_args: Vec<BasicMetadataValueEnum> = vec![env.into()];
+        call_args.extend_from_slice(args);
+
+        self.builder
+            .build_indirect_call(f64.fn_type(&param_types, false), code, &call_args, "calltmp")
+            .map_err(|e| format!("Failed to build call: {}", e))?
+            .try_as_basic_value()
+            .left()
+            .map(|v| v.into_float_value())
+            .ok_or_else(|| "Function call didn't return a value".to_string())
+    }
+
+    // Lower a call to a math extern to the equivalent intrinsic or instruction, if there is one
+    fn build_math_intrinsic(
+        &self,
+        name: &str,
+        args: &[BasicMetadataValueEnum<'ctx>],
+    ) -> Result<Option<FloatValue<'ctx>>, String> {
+        let f64 = self.context.f64_type();
+
+        if name == "fmod" && args.len() == 2 {
+            return self
+                .builder
+                .build_float_rem(
+                    args[0].into_float_value(),
+                    args[1].into_float_value(),
+                    "fmodtmp",
+                )
+                .map(Some)
+                .map_err(|e| format!("Failed to build rem: {}", e));
+        }
+
+        let intrinsic = match MATH_INTRINSICS
+            .iter()
+            .find(|(n, arity, _)| *n == name && *arity == args.len())
+            .and_then(|(_, _, intrinsic)| Intrinsic::find(intrinsic))
+        {
+            Some(i) => i,
+            None => return Ok(None),
+        };
+        let func = intrinsic
+            .get_declaration(&self.module, &[f64.into()])
+            .ok_or_else(|| format!("Failed to declare intrinsic for {}", name))?;
+        self.builder
+            .build_call(func, args, "calltmp")
+            .map_err(|e| format!("Failed to build call: {}", e))?
+            .try_as_basic_value()
+            .left()
+            .map(|v| Some(v.into_float_value()))
+            .ok_or_else(|| "Intrinsic call didn't return a value".to_string())
+    }
+
+    // Declare a runtime function taking `arity` doubles and a C string, see
+    // externs::RUNTIME_FUNCTIONS. codegen_functions maps them into the JIT
+    fn runtime_function(&self, name: &str, arity: usize) -> FunctionValue<'ctx> {
+        self.module.get_function(name).unwrap_or_else(|| {
+            let f64 = self.context.f64_type();
+            let mut params = vec![BasicMetadataTypeEnum::from(f64); arity];
+            params.push(self.context.ptr_type(AddressSpace::default()).into());
+            self.module.add_function(name, f64.fn_type(&params, false), None)
+        })
+    }
+
+    fn malloc_function(&self) -> FunctionValue<'ctx> {
+        self.module.get_function("malloc").unwrap_or_else(|| {
+            let ptr = self.context.ptr_type(AddressSpace::default());
+            let fn_ty = ptr.fn_type(&[self.context.i64_type().into()], false);
+            self.module.add_function("malloc", fn_ty, None)
+        })
+    }
+
     pub fn codegen_top_level_expr(&mut self, expr: &Expr) -> Result<(), String> {
         if let Some(result) = expr.codegen(self)? {
             self.last_result = Some(result);
@@ -89,39 +474,223 @@ impl<'ctx> CodegenContext<'ctx> {
         &mut self,
         parser: &ParserContext,
         ffi_registry: &FfiRegistry,
-        execution_engine: &inkwell::execution_engine::ExecutionEngine,
+        execution_engine: &ExecutionEngine<'ctx>,
+    ) -> Result<(), String> {
+        self.codegen_functions(&parser.functions, ffi_registry, execution_engine)
+    }
+
+    pub fn codegen_functions(
+        &mut self,
+        functions: &[Function],
+        ffi_registry: &FfiRegistry,
+        execution_engine: &ExecutionEngine<'ctx>,
+    ) -> Result<(), String> {
+        self.generate(functions, Some((ffi_registry, execution_engine)))
+    }
+
+    // Generate the program for an object file, leaving externs and the runtime functions for
+    // the linker to resolve
+    pub fn codegen_object(&mut self, functions: &[Function]) -> Result<(), String> {
+        self.generate(functions, None)
+    }
+
+    fn generate(
+        &mut self,
+        functions: &[Function],
+        jit: Option<(&FfiRegistry, &ExecutionEngine<'ctx>)>,
     ) -> Result<(), String> {
         // First, find the last top-level expression to use as main's return value
-        let last_top_level = parser
-            .functions
+        let last_top_level = functions
             .iter()
             .rposition(|f| f.name == "_top_level_expr");
 
-        for (i, f) in parser.functions.iter().enumerate() {
+        // A later definition replaces any before it (see resolver.rs), so only the last one of
+        // each name is compiled
+        let mut last = HashMap::new();
+        for (i, f) in functions.iter().enumerate() {
+            if f.name != "_top_level_expr" {
+                last.insert(f.name.as_str(), i);
+            }
+        }
+
+        // Declare every function before generating any code, so calls may come before the
+        // definition they call
+        for (i, f) in functions.iter().enumerate() {
+            if last.get(f.name.as_str()) == Some(&i) {
+                if self.get_function(&f.name).is_none() {
+                    self.declare_function(f);
+                }
+                self.note_math_extern(f, jit.map(|(ffi_registry, _)| ffi_registry));
+            }
+        }
+
+        for (i, f) in functions.iter().enumerate() {
             if f.name == "_top_level_expr" {
                 // Only codegen the last top-level expression into main
                 if Some(i) == last_top_level {
                     self.codegen_top_level_expr(&f.body)?;
                 }
-            } else {
+            } else if last.get(f.name.as_str()) == Some(&i) {
                 // Codegen regular function
                 self.codegen_function(f)?;
-
-                // If this is an extern, register it with JIT if available in FFI registry
-                if matches!(f.body, Expr::None) {
-                    if let Some(func_ptr) = ffi_registry.get(&f.name) {
-                        let llvm_func = self.module.get_function(&f.name).unwrap();
-                        execution_engine.add_global_mapping(&llvm_func, func_ptr);
-                    }
+                if let Some((ffi_registry, execution_engine)) = jit {
+                    self.map_extern(f, ffi_registry, execution_engine)?;
                 }
             }
         }
 
+        // Point the runtime functions the generated code calls at their native definitions
+        for name in externs::RUNTIME_FUNCTIONS {
+            if let (Some(func), Some(addr), Some((_, execution_engine))) = (
+                self.module.get_function(name),
+                externs::runtime_function(name),
+                jit,
+            ) {
+                execution_engine.add_global_mapping(&func, addr);
+            }
+        }
+
         // Finalize main function with return statement
         self.finalize()?;
         Ok(())
     }
 
+    // Target the host with the generated module
+    fn host_machine(&self, level: OptimizationLevel) -> Result<TargetMachine, String> {
+        Target::initialize_native(&InitializationConfig::default())?;
+        let triple = TargetMachine::get_default_triple();
+        let target = Target::from_triple(&triple).map_err(|e| e.to_string())?;
+        let machine = target
+            .create_target_machine(&triple, "generic", "", level, RelocMode::PIC, CodeModel::Default)
+            .ok_or_else(|| format!("No target machine for {}", triple))?;
+        self.module.set_triple(&triple);
+        self.module
+            .set_data_layout(&machine.get_target_data().get_data_layout());
+        Ok(machine)
+    }
+
+    // Write the generated module as an object file for the host
+    pub fn write_object(&self, path: &Path) -> Result<(), String> {
+        let machine = self.host_machine(OptimizationLevel::None)?;
+        machine
+            .write_to_file(&self.module, FileType::Object, path)
+            .map_err(|e| format!("Failed to write {}: {}", path.display(), e))
+    }
+
+    // Run LLVM's default -O2 pipeline over the generated module
+    pub fn optimize(&self) -> Result<(), String> {
+        let machine = self.host_machine(OptimizationLevel::Default)?;
+        self.module
+            .run_passes("default<O2>", &machine, PassBuilderOptions::create())
+            .map_err(|e| format!("Failed to optimize: {}", e))
+    }
+
+    // Declare a function whose body was generated into another module of the same execution
+    // engine, so this module can call it
+    pub fn declare_external(
+        &mut self,
+        f: &Function,
+        ffi_registry: &FfiRegistry,
+        execution_engine: &ExecutionEngine<'ctx>,
+    ) -> Result<(), String> {
+        if self.get_function(&f.name).is_none() {
+            self.declare_function(f);
+        }
+        self.note_math_extern(f, Some(ffi_registry));
+        self.map_extern(f, ffi_registry, execution_engine)
+    }
+
+    // Remember whether calls to `f` may be lowered to a math intrinsic, which only an extern
+    // that resolves to the C library may be. This is decided from the declaration, since a
+    // function defined later has no code yet when its callers are generated
+    fn note_math_extern(&mut self, f: &Function, ffi_registry: Option<&FfiRegistry>) {
+        let math = matches!(f.body, Expr::None)
+            && f.signature.is_none()
+            && f.library.is_none()
+            && ffi_registry.is_none_or(|r| r.host(&f.name).is_none());
+        if math {
+            self.math_externs.insert(f.name.clone());
+        } else {
+            self.math_externs.remove(&f.name);
+        }
+    }
+
+    // If this is an extern, point it at the native function the FFI registry resolves it to.
+    // Externs the registry doesn't know, or declares with another arity, are errors
+    fn map_extern(
+        &self,
+        f: &Function,
+        ffi_registry: &FfiRegistry,
+        execution_engine: &ExecutionEngine<'ctx>,
+    ) -> Result<(), String> {
+        if !matches!(f.body, Expr::None) {
+            return Ok(());
+        }
+        match ffi_registry.resolve_extern(f)? {
+            ResolvedExtern::Host(host) => self.define_host_extern(f, host),
+            ResolvedExtern::Native(func_ptr) => {
+                let llvm_func = self.get_function(&f.name).unwrap();
+                execution_engine.add_global_mapping(&llvm_func, func_ptr);
+                Ok(())
+            }
+        }
+    }
+
+    // Give an extern backed by a host closure a body that calls the closure's trampoline. The body
+    // is private, so every module of an engine can carry its own copy
+    fn define_host_extern(&self, f: &Function, host: &HostFunction) -> Result<(), String> {
+        let f64 = self.context.f64_type();
+        let ptr = self.context.ptr_type(AddressSpace::default());
+        let i64 = self.context.i64_type();
+        let func = self.get_function(&f.name).unwrap();
+        func.set_linkage(Linkage::Private);
+
+        let saved_block = self.builder.get_insert_block();
+        self.builder.unset_current_debug_location();
+        self.builder
+            .position_at_end(self.context.append_basic_block(func, "entry"));
+        let code = i64.const_int(host.code as u64, false).const_to_pointer(ptr);
+        let env = i64.const_int(host.env as u64, false).const_to_pointer(ptr);
+
+        let mut param_types: Vec<BasicMetadataTypeEnum> = vec![ptr.into()];
+        param_types.extend(vec![BasicMetadataTypeEnum::from(f64); host.arity]);
+        let mut args: Vec<BasicMetadataValueEnum> = vec![env.into()];
+        args.extend(func.get_param_iter().map(BasicMetadataValueEnum::from));
+        let ret = self
+            .builder
+            .build_indirect_call(f64.fn_type(&param_types, false), code, &args, "hostcall")
+            .map_err(|e| format!("Failed to build call: {}", e))?
+            .try_as_basic_value()
+            .left()
+            .ok_or_else(|| "Host function call didn't return a value".to_string())?;
+        self.builder
+            .build_return(Some(&ret))
+            .map_err(|e| format!("Failed to build return: {}", e))?;
+        if let Some(block) = saved_block {
+            self.builder.position_at_end(block);
+        }
+        self.restore_debug_location();
+        Ok(())
+    }
+
+    // The function a name of the program refers to
+    pub fn get_function(&self, name: &str) -> Option<FunctionValue<'ctx>> {
+        let symbol = self.symbols.get(name).map(|s| s.as_str()).unwrap_or(name);
+        self.module.get_function(symbol)
+    }
+
+    pub fn declare_function(&self, f: &Function) -> FunctionValue<'ctx> {
+        let f64 = self.context.f64_type();
+        let param_types = vec![f64.into(); f.args.len()];
+
+        let fn_ty = match &f.signature {
+            Some(sig) => self.c_function_type(sig),
+            None => f64.fn_type(&param_types, false),
+        };
+        let symbol = self.symbols.get(&f.name).unwrap_or(&f.name);
+        self.module.add_function(symbol, fn_ty, None)
+    }
+
     fn finalize(&mut self) -> Result<(), String> {
         // Add return statement to main with the last result
         // The builder is positioned wherever the last expression left it
@@ -137,23 +706,23 @@ impl<'ctx> CodegenContext<'ctx> {
                 .build_return(Some(&zero))
                 .map_err(|e| format!("Failed to build return: {}", e))?;
         }
+        if let Some(debug) = &self.debug {
+            debug.finalize();
+        }
         Ok(())
     }
 }
 
 impl Function {
     pub fn codegen(&self, cg: &mut CodegenContext) -> Result<(), String> {
-        // Check if function already exists (skip redefinition)
-        if cg.module.get_function(self.name.as_str()).is_some() {
-            return Ok(());
-        }
-
-        // Create function signature
-        let f64 = cg.context.f64_type();
-        let param_types = vec![f64.into(); self.args.len()];
-
-        let fn_ty = f64.fn_type(&param_types, false);
-        let func = cg.module.add_function(self.name.as_str(), fn_ty, None);
+        // Functions are usually declared before any code is generated, see generate()
+        let func = match cg.get_function(&self.name) {
+            Some(func) if func.count_basic_blocks() > 0 => {
+                return Err(format!("Redefinition of function: {}", self.name));
+            }
+            Some(func) => func,
+            None => cg.declare_function(self),
+        };
 
         // Externs have no body - just the function declaration, so we're done
         if matches!(self.body, Expr::None) {
@@ -163,12 +732,13 @@ impl Function {
         // Generate function body
         let entry = cg.context.append_basic_block(func, "entry");
         cg.builder.position_at_end(entry);
+        let debug_info = cg.enter_debug_function(func, &self.name, &self.body);
 
         // Set up parameters in the symbol table
         cg.vars.clear();
-        for (p, name) in func.get_param_iter().zip(self.args.iter()) {
+        for (i, (p, name)) in func.get_param_iter().zip(self.args.iter()).enumerate() {
             p.set_name(name);
-            let d = cg.create_entryblock_alloc(&func, name.clone())?;
+            let d = cg.create_parameter_alloc(&func, name.clone(), i as u32 + 1)?;
             cg.builder.build_store(d, p).map_err(|e| e.to_string())?;
             cg.vars.insert(name.clone(), d);
         }
@@ -181,6 +751,7 @@ impl Function {
                 .build_return(None)
                 .map_err(|e| format!("Failed to build empty return: {}", e))?;
         }
+        cg.leave_debug_function(debug_info);
         Ok(())
     }
 }
@@ -207,8 +778,11 @@ impl Expr {
                 // Get current function
                 let f = cg.builder.get_insert_block().unwrap().get_parent().unwrap();
 
-                // Create loop block and branch to it
+                // The end condition is checked before each iteration, so the body may not run
+                // at all
                 let loop_bb = cg.context.append_basic_block(f, "loop");
+                let body_bb = cg.context.append_basic_block(f, "loopbody");
+                let after_bb = cg.context.append_basic_block(f, "afterloop");
                 cg.builder
                     .build_unconditional_branch(loop_bb)
                     .map_err(|e| format!("Failed to branch to loop: {}", e))?;
@@ -220,7 +794,23 @@ impl Expr {
                 let old_val = cg.vars.get(ident).cloned();
                 cg.vars.insert(ident.clone(), alloc);
 
+                // Compute end condition
+                let end_cond_val = end.codegen(cg)?.unwrap();
+                let end_cond = cg
+                    .builder
+                    .build_float_compare(
+                        inkwell::FloatPredicate::ONE,
+                        end_cond_val.into_float_value(),
+                        cg.context.f64_type().const_float(0.0),
+                        "loopcond",
+                    )
+                    .map_err(|e| format!("Failed to build endcond: {}", e))?;
+                cg.builder
+                    .build_conditional_branch(end_cond, body_bb, after_bb)
+                    .map_err(|e| format!("Failed to build cond branch: {}", e))?;
+
                 // Generate body
+                cg.builder.position_at_end(body_bb);
                 body.codegen(cg)?;
 
                 // Compute step value
@@ -230,9 +820,6 @@ impl Expr {
                 }
                 .into_float_value();
 
-                // Compute end condition
-                let end_cond_val = end.codegen(cg)?.unwrap();
-
                 let cur_var = cg
                     .builder
                     .build_load(cg.context.f64_type(), alloc, ident)
@@ -245,24 +832,9 @@ impl Expr {
                 cg.builder
                     .build_store(alloc, next_var)
                     .map_err(|e| format!("Failed to build store: {}", e))?;
-
-                let end_cond = cg
-                    .builder
-                    .build_float_compare(
-                        inkwell::FloatPredicate::ONE,
-                        end_cond_val.into_float_value(),
-                        cg.context.f64_type().const_float(0.0),
-                        "loopcond",
-                    )
-                    .map_err(|e| format!("Failed to build endcond: {}", e))?;
-
-                // Create after-loop block
-                let after_bb = cg.context.append_basic_block(f, "afterloop");
-
-                // Conditional branch
                 cg.builder
-                    .build_conditional_branch(end_cond, loop_bb, after_bb)
-                    .map_err(|e| format!("Failed to build cond branch: {}", e))?;
+                    .build_unconditional_branch(loop_bb)
+                    .map_err(|e| format!("Failed to branch to loop: {}", e))?;
 
                 // Position in after block
                 cg.builder.position_at_end(after_bb);
@@ -281,7 +853,7 @@ impl Expr {
             Expr::Var { varnames, body } => {
                 let f = cg.builder.get_insert_block().unwrap().get_parent().unwrap();
 
-                let mut old_bindings: Vec<(String, PointerValue)> = Vec::new();
+                let mut old_bindings: Vec<(String, Option<PointerValue>)> = Vec::new();
                 for (name, expr) in varnames {
                     let init_val = match expr {
                         Some(e) => e.codegen(cg)?.unwrap(),
@@ -293,19 +865,166 @@ impl Expr {
                         .build_store(alloc, init_val)
                         .map_err(|e| e.to_string())?;
 
-                    if let Some(val) = cg.vars.get(name).cloned() {
-                        old_bindings.push((name.clone(), val));
-                    }
-                    cg.vars.insert(name.clone(), alloc);
+                    old_bindings.push((name.clone(), cg.vars.insert(name.clone(), alloc)));
                 }
 
                 let bval = body.codegen(cg)?.unwrap();
-                for  (name, val) in old_bindings {
-                    cg.vars.insert(name, val);
+                // In reverse, so a name declared twice gets back what it had before both
+                for (name, old) in old_bindings.into_iter().rev() {
+                    if let Some(old) = old {
+                        cg.vars.insert(name, old);
+                    } else {
+                        cg.vars.remove(&name);
+                    }
                 }
 
                 Ok(Some(bval))
             }
+            Expr::Lambda { params, body } => {
+                // Captured variables are copied into the closure record when the lambda is
+                // created, so later assignments on either side are not shared. Records are
+                // heap allocated and never freed
+                let captures: Vec<(String, PointerValue)> = self
+                    .free_variables()
+                    .into_iter()
+                    .filter_map(|name| cg.vars.get(&name).map(|p| (name.clone(), *p)))
+                    .collect();
+
+                let f64 = cg.context.f64_type();
+                let ptr = cg.context.ptr_type(AddressSpace::default());
+                let mut param_types: Vec<BasicMetadataTypeEnum> = vec![ptr.into()];
+                param_types.extend(vec![BasicMetadataTypeEnum::from(f64); params.len()]);
+                let func = cg.module.add_function(
+                    "lambda",
+                    f64.fn_type(&param_types, false),
+                    Some(Linkage::Private),
+                );
+
+                // Allocate and fill the closure record
+                let mut fields: Vec<BasicTypeEnum> = vec![ptr.into()];
+                fields.extend(vec![BasicTypeEnum::from(f64); captures.len()]);
+                let record_ty = cg.context.struct_type(&fields, false);
+                let record = cg
+                    .builder
+                    .build_call(
+                        cg.malloc_function(),
+                        &[record_ty.size_of().unwrap().into()],
+                        "closurerec",
+                    )
+                    .map_err(|e| format!("Failed to build call: {}", e))?
+                    .try_as_basic_value()
+                    .left()
+                    .ok_or("malloc didn't return a value")?
+                    .into_pointer_value();
+                cg.builder
+                    .build_store(record, func.as_global_value().as_pointer_value())
+                    .map_err(|e| e.to_string())?;
+                for (i, (name, var)) in captures.iter().enumerate() {
+                    let val = cg
+                        .builder
+                        .build_load(f64, *var, name)
+                        .map_err(|e| format!("Failed to build load: {}", e))?;
+                    let slot = cg
+                        .builder
+                        .build_struct_gep(record_ty, record, i as u32 + 1, name)
+                        .map_err(|e| format!("Failed to build gep: {}", e))?;
+                    cg.builder.build_store(slot, val).map_err(|e| e.to_string())?;
+                }
+                let value = cg.closure_to_f64(record)?;
+
+                // Generate the lambda body with only its captures and parameters in scope
+                let saved_block = cg.builder.get_insert_block().unwrap();
+                let saved_vars = std::mem::take(&mut cg.vars);
+                cg.builder
+                    .position_at_end(cg.context.append_basic_block(func, "entry"));
+                let debug_info = cg.enter_debug_function(func, "lambda", body);
+
+                let env = func.get_first_param().unwrap().into_pointer_value();
+                env.set_name("env");
+                for (i, (name, _)) in captures.iter().enumerate() {
+                    let slot = cg
+                        .builder
+                        .build_struct_gep(record_ty, env, i as u32 + 1, name)
+                        .map_err(|e| format!("Failed to build gep: {}", e))?;
+                    let val = cg
+                        .builder
+                        .build_load(f64, slot, name)
+                        .map_err(|e| format!("Failed to build load: {}", e))?;
+                    let alloc = cg.create_entryblock_alloc(&func, name.clone())?;
+                    cg.builder.build_store(alloc, val).map_err(|e| e.to_string())?;
+                    cg.vars.insert(name.clone(), alloc);
+                }
+                for (i, (p, name)) in func.get_param_iter().skip(1).zip(params.iter()).enumerate() {
+                    p.set_name(name);
+                    let alloc = cg.create_parameter_alloc(&func, name.clone(), i as u32 + 1)?;
+                    cg.builder.build_store(alloc, p).map_err(|e| e.to_string())?;
+                    cg.vars.insert(name.clone(), alloc);
+                }
+
+                let ret = body
+                    .codegen(cg)?
+                    .ok_or_else(|| "Lambda body produced no value".to_string())?;
+                cg.builder
+                    .build_return(Some(&ret))
+                    .map_err(|e| format!("Failed to build return: {}", e))?;
+
+                cg.leave_debug_function(debug_info);
+                cg.vars = saved_vars;
+                cg.builder.position_at_end(saved_block);
+                Ok(Some(value.into()))
+            }
+            Expr::Assert {
+                args,
+                text,
+                location,
+            } => {
+                let mut cargs: Vec<BasicMetadataValueEnum> = Vec::new();
+                for arg in args {
+                    let val = arg
+                        .codegen(cg)?
+                        .ok_or_else(|| format!("Can not codegen argument of {}", text))?;
+                    cargs.push(val.into_float_value().into());
+                }
+                let what = cg
+                    .builder
+                    .build_global_string_ptr(&format!("{}: {}", location, text), "assertmsg")
+                    .map_err(|e| format!("Failed to build string: {}", e))?;
+                cargs.push(what.as_pointer_value().into());
+
+                let name = if args.len() == 1 {
+                    "__kls_assert"
+                } else {
+                    "__kls_assert_eq"
+                };
+                let func = cg.runtime_function(name, args.len());
+                cg.builder
+                    .build_call(func, &cargs, "asserttmp")
+                    .map_err(|e| format!("Failed to build call: {}", e))?
+                    .try_as_basic_value()
+                    .left()
+                    .map(Some)
+                    .ok_or_else(|| "Assertion didn't return a value".to_string())
+            }
+            Expr::Located { line, col, expr } => {
+                // Code after the operands of an expression, like a call after its arguments,
+                // belongs to the expression itself
+                let outer = cg.debug.as_ref().and_then(|d| d.location());
+                cg.set_debug_location(*line, *col);
+                let val = expr.codegen(cg)?;
+                if let Some(outer) = outer {
+                    cg.set_debug_location(outer.get_line(), outer.get_column());
+                }
+                Ok(val)
+            }
+            Expr::Block(exprs) => {
+                let mut last = cg.context.f64_type().const_float(0.0).into();
+                for expr in exprs {
+                    if let Some(val) = expr.codegen(cg)? {
+                        last = val;
+                    }
+                }
+                Ok(Some(last))
+            }
             Expr::Unary { op, left } => {
                 let operand = left
                     .codegen(cg)?
@@ -314,7 +1033,6 @@ impl Expr {
 
                 let func_name = format!("unary{}", op);
                 let func = cg
-                    .module
                     .get_function(&func_name)
                     .ok_or_else(|| format!("Unknown unary operator: {}", func_name))?;
 
@@ -379,10 +1097,25 @@ impl Expr {
             }
 
             Expr::Call { identifier, args } => {
-                let callee: FunctionValue = cg
-                    .module
-                    .get_function(identifier.as_str())
-                    .ok_or_else(|| format!("Unknown function: {}", identifier))?;
+                // A variable in scope shadows any function of the same name, and holds a closure
+                // that we call indirectly
+                let closure = match cg.vars.get(identifier).cloned() {
+                    Some(var) => Some(
+                        cg.builder
+                            .build_load(cg.context.f64_type(), var, identifier.as_str())
+                            .map_err(|e| format!("Failed to build load: {}", e))?
+                            .into_float_value(),
+                    ),
+                    None => None,
+                };
+
+                let callee: Option<FunctionValue> = match closure {
+                    Some(_) => None,
+                    None => Some(
+                        cg.get_function(identifier)
+                            .ok_or_else(|| format!("Unknown function: {}", identifier))?,
+                    ),
+                };
                 let mut cargs: Vec<BasicMetadataValueEnum> = Vec::new();
                 for arg in args {
                     let val = arg
@@ -397,16 +1130,39 @@ impl Expr {
                         .into_float_value();
                     cargs.push(val.into());
                 }
-                let call = cg.builder.build_call(callee, &cargs, "calltmp").unwrap();
-                let ret: FloatValue = call.try_as_basic_value().left().unwrap().into_float_value();
-                Ok(Some(ret.into()))
+
+                let callee = match (callee, closure) {
+                    (Some(callee), _) => callee,
+                    (None, Some(closure)) => {
+                        return Ok(Some(cg.build_closure_call(closure, &cargs)?.into()));
+                    }
+                    (None, None) => unreachable!(),
+                };
+
+                // Externs from the math library become intrinsics where LLVM has them, unless
+                // they were declared with a C signature
+                if cg.math_externs.contains(identifier)
+                    && let Some(val) = cg.build_math_intrinsic(identifier, &cargs)?
+                {
+                    return Ok(Some(val.into()));
+                }
+
+                let fargs: Vec<FloatValue> = cargs.iter().map(|a| a.into_float_value()).collect();
+                Ok(Some(cg.build_direct_call(callee, &fargs)?.into()))
             }
             Expr::Number(value) => Ok(Some(cg.context.f64_type().const_float(*value).into())),
             Expr::Variable(name) => {
-                let val = cg
-                    .vars
-                    .get(name)
-                    .ok_or_else(|| format!("Unknown variable: {}", name))?;
+                // Function names used as values evaluate to a pointer to that function
+                let val = match cg.vars.get(name) {
+                    Some(v) => v,
+                    None => {
+                        let func = cg
+                            .get_function(name)
+                            .ok_or_else(|| format!("Unknown variable: {}", name))?;
+                        let record = cg.function_closure(func)?;
+                        return Ok(Some(cg.closure_to_f64(record)?.into()));
+                    }
+                };
 
                 match cg
                     .builder
@@ -419,23 +1175,23 @@ impl Expr {
             Expr::BinOp { left, op, right } => {
                 // For assignments we don't want to codegen the LHS so it's a special case
 
-                match (op, left.as_ref()) {
-                    // If it's an assignment, we don't want to generate the LHS, we just want to
-                    // generate the variable
-                    (Token::Assign(_), Expr::Variable(s)) => {
-                        let val = right
-                            .codegen(cg)?
-                            .ok_or_else(|| "Right operand produced no value".to_string())?
-                            .into_float_value();
-                        let var = cg.vars.get(s).cloned().unwrap();
+                // If it's an assignment, we don't want to generate the LHS, we just want to
+                // generate the variable
+                if let (Token::Assign(_), Expr::Variable(s)) = (op, left.as_ref()) {
+                    let val = right
+                        .codegen(cg)?
+                        .ok_or_else(|| "Right operand produced no value".to_string())?
+                        .into_float_value();
+                    let var = *cg
+                        .vars
+                        .get(s)
+                        .ok_or_else(|| format!("Unknown variable: {}", s))?;
 
-                        cg.builder
-                            .build_store(var, val)
-                            .map_err(|e| e.to_string())?;
-                        return Ok(Some(val.into()));
-                    }
-                    _ => {}
-                };
+                    cg.builder
+                        .build_store(var, val)
+                        .map_err(|e| e.to_string())?;
+                    return Ok(Some(val.into()));
+                }
 
                 let lhs = left
                     .codegen(cg)?
@@ -462,7 +1218,7 @@ impl Expr {
                     | Token::Dollar(c)
                     | Token::At(c)
                     | Token::Tilde(c) => {
-                        if let Some(func) = cg.module.get_function(&format!("binary{}", c)) {
+                        if let Some(func) = cg.get_function(&format!("binary{}", c)) {
                             // User-defined binary operator - call the function
                             let args = [lhs.into(), rhs.into()];
                             cg.builder
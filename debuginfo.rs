@@ -0,0 +1,171 @@
+use std::path::Path;
+
+use inkwell::basic_block::BasicBlock;
+use inkwell::context::Context;
+use inkwell::debug_info::{
+    AsDIScope, DIFile, DIFlags, DIFlagsConstants, DILocation, DISubprogram, DIType,
+    DWARFEmissionKind, DWARFSourceLanguage, DebugInfoBuilder,
+};
+use inkwell::module::{FlagBehavior, Module};
+use inkwell::values::{FunctionValue, PointerValue};
+
+// DWARF for the code of one source file: a compile unit, a subprogram for each generated
+// function, and a variable for each stack slot. Every value is a double, so that is the only
+// type there is. Kaleidoscope has no DWARF language code, C is the closest one debuggers know
+
+const DW_ATE_FLOAT: u32 = 0x04;
+
+pub struct DebugInfo<'ctx> {
+    builder: DebugInfoBuilder<'ctx>,
+    file: DIFile<'ctx>,
+    double: DIType<'ctx>,
+    // Functions being generated, innermost last, each with where in it code is being generated.
+    // Lambdas are generated in the middle of the function they appear in
+    scopes: Vec<(DISubprogram<'ctx>, DILocation<'ctx>)>,
+}
+
+impl<'ctx> DebugInfo<'ctx> {
+    pub fn new(module: &Module<'ctx>, path: &Path) -> Result<Self, String> {
+        let context = module.get_context();
+        let i32 = context.i32_type();
+        module.add_basic_value_flag(
+            "Debug Info Version",
+            FlagBehavior::Warning,
+            i32.const_int(inkwell::debug_info::debug_metadata_version() as u64, false),
+        );
+        module.add_basic_value_flag("Dwarf Version", FlagBehavior::Warning, i32.const_int(4, false));
+
+        let filename = path
+            .file_name()
+            .map(|f| f.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let directory = path
+            .parent()
+            .and_then(|d| std::path::absolute(d).ok())
+            .map(|d| d.display().to_string())
+            .unwrap_or_default();
+        let (builder, unit) = module.create_debug_info_builder(
+            true,
+            DWARFSourceLanguage::C,
+            &filename,
+            &directory,
+            "rust_kaleidoscope",
+            false,
+            "",
+            0,
+            "",
+            DWARFEmissionKind::Full,
+            0,
+            false,
+            false,
+            "",
+            "",
+        );
+        let double = builder
+            .create_basic_type("double", 64, DW_ATE_FLOAT, DIFlags::ZERO)
+            .map_err(|e| format!("Failed to create debug type: {}", e))?
+            .as_type();
+
+        Ok(DebugInfo {
+            builder,
+            file: unit.get_file(),
+            double,
+            scopes: Vec::new(),
+        })
+    }
+
+    // Start generating code for a function whose body starts at `line` (0 if it isn't known)
+    pub fn enter_function(&mut self, context: &'ctx Context, func: FunctionValue<'ctx>, name: &str, line: u32) {
+        let params = vec![self.double; func.count_params() as usize];
+        let ty = self
+            .builder
+            .create_subroutine_type(self.file, Some(self.double), &params, DIFlags::ZERO);
+        let subprogram = self.builder.create_function(
+            self.file.as_debug_info_scope(),
+            name,
+            None,
+            self.file,
+            line,
+            ty,
+            false,
+            true,
+            line,
+            DIFlags::ZERO,
+            false,
+        );
+        func.set_subprogram(subprogram);
+        let location = self.builder.create_debug_location(
+            context,
+            line,
+            0,
+            subprogram.as_debug_info_scope(),
+            None,
+        );
+        self.scopes.push((subprogram, location));
+    }
+
+    pub fn leave_function(&mut self) {
+        self.scopes.pop();
+    }
+
+    // Move to another place in the current function
+    pub fn set_location(&mut self, context: &'ctx Context, line: u32, col: u32) {
+        if let Some((subprogram, location)) = self.scopes.last_mut() {
+            *location = self.builder.create_debug_location(
+                context,
+                line,
+                col,
+                subprogram.as_debug_info_scope(),
+                None,
+            );
+        }
+    }
+
+    pub fn location(&self) -> Option<DILocation<'ctx>> {
+        self.scopes.last().map(|(_, location)| *location)
+    }
+
+    // Describe the stack slot of a variable of the function being generated into `block`,
+    // declared at the current location. `arg` is the 1-based position of parameters. Nothing is
+    // described for functions without debug information
+    pub fn declare_variable(&self, slot: PointerValue<'ctx>, name: &str, arg: Option<u32>, block: BasicBlock<'ctx>) {
+        let (subprogram, location) = match self.scopes.last() {
+            Some(scope) => *scope,
+            None => return,
+        };
+        if block.get_parent().and_then(|f| f.get_subprogram()) != Some(subprogram) {
+            return;
+        }
+        let scope = subprogram.as_debug_info_scope();
+        let line = location.get_line();
+        let var = match arg {
+            Some(arg) => self.builder.create_parameter_variable(
+                scope,
+                name,
+                arg,
+                self.file,
+                line,
+                self.double,
+                true,
+                DIFlags::ZERO,
+            ),
+            None => self.builder.create_auto_variable(
+                scope,
+                name,
+                self.file,
+                line,
+                self.double,
+                true,
+                DIFlags::ZERO,
+                0,
+            ),
+        };
+        self.builder
+            .insert_declare_at_end(slot, Some(var), None, location, block);
+    }
+
+    // Resolve everything created so far. Must happen before the module is compiled
+    pub fn finalize(&self) {
+        self.builder.finalize();
+    }
+}
@@ -0,0 +1,336 @@
+use inkwell::context::Context;
+use inkwell::OptimizationLevel;
+
+use crate::ast::{Expr, Function};
+use crate::bytecode;
+use crate::codegen::CodegenContext;
+use crate::externs::{self, FfiRegistry, OutputSink};
+use crate::interp::Interpreter;
+use crate::lexer::Token;
+use crate::resolver;
+use crate::vm::Vm;
+
+// Differential testing of the backends. A program is run on the JIT, the VM and the interpreter
+// and checked against a small reference evaluator that exists only for this, see Reference, and
+// any difference in the result, the output or the error is reported. Programs the reference
+// doesn't cover are checked against the interpreter. Function values are represented
+// differently by the JIT, so programs whose result is a function value always differ
+
+// What running a program on one backend produced
+#[derive(Debug, PartialEq)]
+pub struct Execution {
+    pub result: Result<f64, String>,
+    pub output: String,
+    pub assert_failures: Vec<String>,
+}
+
+impl Execution {
+    fn agrees_with(&self, other: &Execution) -> bool {
+        let same_result = match (&self.result, &other.result) {
+            (Ok(a), Ok(b)) => a.to_bits() == b.to_bits() || (a.is_nan() && b.is_nan()),
+            (Err(a), Err(b)) => a == b,
+            _ => false,
+        };
+        same_result && self.output == other.output && self.assert_failures == other.assert_failures
+    }
+}
+
+// Run a program on every backend. Returns the JIT's execution if they all agree with the
+// reference, and a report of each otherwise. Programs the reference doesn't cover are checked
+// against the interpreter
+pub fn compare(functions: &[Function], ffi_registry: &FfiRegistry) -> Result<Execution, String> {
+    let reference = run_reference(functions, ffi_registry);
+    let interp = run_interp(functions, ffi_registry);
+    let jit = run_jit(functions, ffi_registry);
+    let vm = run_vm(functions, ffi_registry);
+    let expected = reference.as_ref().unwrap_or(&interp);
+    if jit.agrees_with(expected) && vm.agrees_with(expected) && interp.agrees_with(expected) {
+        return Ok(jit);
+    }
+    let reference = match &reference {
+        Some(execution) => describe(execution),
+        None => String::from("not covered"),
+    };
+    Err(format!(
+        "the backends disagree\n  reference:   {}\n  interpreter: {}\n  JIT:         {}\n  VM:          {}",
+        reference,
+        describe(&interp),
+        describe(&jit),
+        describe(&vm)
+    ))
+}
+
+fn describe(execution: &Execution) -> String {
+    let result = match &execution.result {
+        Ok(value) => format!("result {}", value),
+        Err(e) => format!("error {:?}", e),
+    };
+    format!(
+        "{}, output {:?}, failed assertions {:?}",
+        result, execution.output, execution.assert_failures
+    )
+}
+
+pub fn run_jit(functions: &[Function], ffi_registry: &FfiRegistry) -> Execution {
+    let previous = externs::set_output(OutputSink::Buffer(Vec::new()));
+    let result = jit_result(functions, ffi_registry);
+    let output = externs::set_output(previous).contents().unwrap_or_default();
+    Execution {
+        result,
+        output,
+        assert_failures: externs::take_assert_failures(),
+    }
+}
+
+fn jit_result(functions: &[Function], ffi_registry: &FfiRegistry) -> Result<f64, String> {
+    resolver::resolve(functions)?;
+    let context = Context::create();
+    let mut cg = CodegenContext::new(&context, "main");
+    let execution_engine = cg
+        .module
+        .create_jit_execution_engine(OptimizationLevel::None)
+        .map_err(|e| format!("Failed to create JIT: {}", e))?;
+    cg.codegen_functions(functions, ffi_registry, &execution_engine)?;
+
+    unsafe {
+        let main_fn = execution_engine
+            .get_function::<unsafe extern "C" fn() -> f64>("main")
+            .map_err(|e| format!("Failed to get main: {}", e))?;
+        Ok(main_fn.call())
+    }
+}
+
+pub fn run_interp(functions: &[Function], ffi_registry: &FfiRegistry) -> Execution {
+    let previous = externs::set_output(OutputSink::Buffer(Vec::new()));
+    let result = Interpreter::new(functions, ffi_registry).and_then(|mut i| i.run());
+    let output = externs::set_output(previous).contents().unwrap_or_default();
+    Execution {
+        result,
+        output,
+        assert_failures: externs::take_assert_failures(),
+    }
+}
+
+pub fn run_vm(functions: &[Function], ffi_registry: &FfiRegistry) -> Execution {
+    let previous = externs::set_output(OutputSink::Buffer(Vec::new()));
+    let result = bytecode::compile(functions)
+        .and_then(|program| Vm::new(&program, ffi_registry)?.run());
+    let output = externs::set_output(previous).contents().unwrap_or_default();
+    Execution {
+        result,
+        output,
+        assert_failures: externs::take_assert_failures(),
+    }
+}
+
+// Run a program on the reference evaluator, or None if it uses something the reference doesn't
+// cover
+pub fn run_reference(functions: &[Function], ffi_registry: &FfiRegistry) -> Option<Execution> {
+    let previous = externs::set_output(OutputSink::Buffer(Vec::new()));
+    let result = Reference::run(functions, ffi_registry);
+    let output = externs::set_output(previous).contents().unwrap_or_default();
+    let result = match result {
+        Err(Stop::Unsupported) => return None,
+        Err(Stop::Error(e)) => Err(e),
+        Ok(value) => Ok(value),
+    };
+    Some(Execution {
+        result,
+        output,
+        assert_failures: externs::take_assert_failures(),
+    })
+}
+
+// Why the reference stopped evaluating a program
+enum Stop {
+    Error(String),
+    Unsupported,
+}
+
+impl From<String> for Stop {
+    fn from(e: String) -> Self {
+        Stop::Error(e)
+    }
+}
+
+// The oracle the backends are checked against: an evaluator kept small enough to check by
+// reading, written from the language's semantics and sharing no code with the backends. It
+// covers numbers, variables, operators, calls to functions and externs, if, for and var, which
+// is everything the generator produces. Function values, lambdas and assertions are left out
+struct Reference<'a> {
+    functions: &'a [Function],
+    ffi_registry: &'a FfiRegistry,
+    // Variables in scope, innermost last
+    scope: Vec<(&'a str, f64)>,
+}
+
+impl<'a> Reference<'a> {
+    fn run(functions: &'a [Function], ffi_registry: &'a FfiRegistry) -> Result<f64, Stop> {
+        resolver::resolve(functions)?;
+        for f in functions.iter().filter(|f| matches!(f.body, Expr::None)) {
+            ffi_registry.native_function(f)?;
+        }
+        let mut reference = Reference {
+            functions,
+            ffi_registry,
+            scope: Vec::new(),
+        };
+        // Only the last top-level expression runs
+        match functions.iter().rfind(|f| f.name == "_top_level_expr") {
+            Some(f) => reference.eval(&f.body),
+            None => Ok(0.0),
+        }
+    }
+
+    fn function(&self, name: &str) -> Option<&'a Function> {
+        self.functions.iter().rfind(|f| f.name == name)
+    }
+
+    fn variable(&mut self, name: &str) -> Option<&mut f64> {
+        self.scope
+            .iter_mut()
+            .rev()
+            .find(|(n, _)| *n == name)
+            .map(|(_, v)| v)
+    }
+
+    fn call(&mut self, f: &'a Function, args: Vec<f64>) -> Result<f64, Stop> {
+        if matches!(f.body, Expr::None) {
+            return Ok(self.ffi_registry.native_function(f)?.call(&f.name, &args)?);
+        }
+        // A function only sees its own parameters
+        let outer = std::mem::take(&mut self.scope);
+        self.scope = f.args.iter().map(|a| a.as_str()).zip(args).collect();
+        let value = self.eval(&f.body);
+        self.scope = outer;
+        value
+    }
+
+    // Each kind of expression is evaluated by a function of its own, which keeps the frames of
+    // this recursion small enough for the deep recursion of some examples
+    fn eval(&mut self, e: &'a Expr) -> Result<f64, Stop> {
+        match e {
+            Expr::Number(v) => Ok(*v),
+            Expr::Variable(name) => self.variable(name).copied().ok_or(Stop::Unsupported),
+            Expr::Located { expr, .. } => self.eval(expr),
+            Expr::Block(exprs) => {
+                let mut value = 0.0;
+                for e in exprs {
+                    value = self.eval(e)?;
+                }
+                Ok(value)
+            }
+            Expr::If {
+                condition,
+                then,
+                els,
+            } => match truthy(self.eval(condition)?) {
+                true => self.eval(then),
+                false => self.eval(els),
+            },
+            Expr::For {
+                ident,
+                start,
+                end,
+                step,
+                body,
+            } => self.eval_for(ident, start, end, step.as_deref(), body),
+            Expr::Var { varnames, body } => self.eval_var(varnames, body),
+            Expr::Call { identifier, args } => self.eval_call(identifier, args),
+            Expr::Unary { op, left } => self.eval_unary(*op, left),
+            Expr::BinOp { left, op, right } => self.eval_binop(left, op, right),
+            Expr::Lambda { .. } | Expr::Assert { .. } | Expr::None => Err(Stop::Unsupported),
+        }
+    }
+
+    // The end condition is checked before each iteration, and the step is added after it
+    fn eval_for(
+        &mut self,
+        ident: &'a str,
+        start: &'a Expr,
+        end: &'a Expr,
+        step: Option<&'a Expr>,
+        body: &'a Expr,
+    ) -> Result<f64, Stop> {
+        let start = self.eval(start)?;
+        self.scope.push((ident, start));
+        while truthy(self.eval(end)?) {
+            self.eval(body)?;
+            let step = match step {
+                Some(step) => self.eval(step)?,
+                None => 1.0,
+            };
+            *self.variable(ident).unwrap() += step;
+        }
+        self.scope.pop();
+        Ok(0.0)
+    }
+
+    // Each name is in scope for the initializers after it and for the body, and no longer
+    fn eval_var(&mut self, varnames: &'a [(String, Option<Expr>)], body: &'a Expr) -> Result<f64, Stop> {
+        for (name, init) in varnames {
+            let value = match init {
+                Some(init) => self.eval(init)?,
+                None => 0.0,
+            };
+            self.scope.push((name, value));
+        }
+        let value = self.eval(body)?;
+        self.scope.truncate(self.scope.len() - varnames.len());
+        Ok(value)
+    }
+
+    fn eval_call(&mut self, identifier: &str, args: &'a [Expr]) -> Result<f64, Stop> {
+        if self.variable(identifier).is_some() {
+            return Err(Stop::Unsupported);
+        }
+        let f = self.function(identifier).ok_or(Stop::Unsupported)?;
+        let mut values = Vec::new();
+        for arg in args {
+            values.push(self.eval(arg)?);
+        }
+        self.call(f, values)
+    }
+
+    fn eval_unary(&mut self, op: char, operand: &'a Expr) -> Result<f64, Stop> {
+        let f = self
+            .function(&format!("unary{}", op))
+            .ok_or(Stop::Unsupported)?;
+        let value = self.eval(operand)?;
+        self.call(f, vec![value])
+    }
+
+    fn eval_binop(&mut self, left: &'a Expr, op: &Token, right: &'a Expr) -> Result<f64, Stop> {
+        if let Token::Assign(_) = op {
+            let Expr::Variable(name) = left else {
+                return Err(Stop::Unsupported);
+            };
+            let value = self.eval(right)?;
+            *self.variable(name).ok_or(Stop::Unsupported)? = value;
+            return Ok(value);
+        }
+
+        let c = op.operator_char().ok_or(Stop::Unsupported)?;
+        let (lhs, rhs) = (self.eval(left)?, self.eval(right)?);
+        // User-defined operators come before the built-in ones
+        if let Some(f) = self.function(&format!("binary{}", c)) {
+            return self.call(f, vec![lhs, rhs]);
+        }
+        // Comparisons are unordered, so true when either side is NaN
+        let unordered = lhs.is_nan() || rhs.is_nan();
+        match c {
+            '+' => Ok(lhs + rhs),
+            '-' => Ok(lhs - rhs),
+            '*' => Ok(lhs * rhs),
+            '/' => Ok(lhs / rhs),
+            '<' => Ok(f64::from(u8::from(lhs < rhs || unordered))),
+            '>' => Ok(f64::from(u8::from(lhs > rhs || unordered))),
+            _ => Err(Stop::Unsupported),
+        }
+    }
+}
+
+// Conditions are true when they compare ordered and not equal to 0, so NaN is false
+fn truthy(value: f64) -> bool {
+    value != 0.0 && !value.is_nan()
+}
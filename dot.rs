@@ -0,0 +1,79 @@
+use std::fmt::Write;
+
+use inkwell::basic_block::BasicBlock;
+use inkwell::module::Module;
+use inkwell::values::{AnyValue, FunctionValue, InstructionOpcode};
+
+// Control flow graphs of generated code as Graphviz DOT, one graph per function. Each basic
+// block is a node listing its instructions as the printed IR has them, and each branch is an
+// edge, labelled true or false when the branch is conditional. `dot -Tsvg f.dot -o f.svg`
+// renders one
+
+// The name and graph of every function the module defines
+pub fn module_graphs(module: &Module) -> Vec<(String, String)> {
+    module
+        .get_functions()
+        .filter(|f| f.count_basic_blocks() > 0)
+        .map(|f| (f.get_name().to_string_lossy().into_owned(), function_graph(f)))
+        .collect()
+}
+
+pub fn function_graph(function: FunctionValue) -> String {
+    let blocks = function.get_basic_blocks();
+    let node = |block: BasicBlock| blocks.iter().position(|b| *b == block).unwrap_or(0);
+
+    let mut out = String::new();
+    let name = function.get_name().to_string_lossy();
+    let _ = writeln!(out, "digraph \"{}\" {{", escape(&name));
+    out.push_str("  node [shape=box, fontname=\"monospace\"];\n");
+    for (i, block) in blocks.iter().enumerate() {
+        // \l ends a left-aligned line
+        let mut label = format!("{}:\\l", escape(&block.get_name().to_string_lossy()));
+        for instruction in block.get_instructions() {
+            let text = instruction.print_to_string().to_string();
+            let _ = write!(label, "  {}\\l", escape(text.trim()));
+        }
+        let _ = writeln!(out, "  b{} [label=\"{}\"];", i, label);
+    }
+
+    for (i, block) in blocks.iter().enumerate() {
+        let Some(terminator) = block.get_terminator() else {
+            continue;
+        };
+        let targets: Vec<BasicBlock> = terminator
+            .get_operands()
+            .flatten()
+            .filter_map(|operand| operand.right())
+            .collect();
+        // The operands of a conditional branch hold the false target before the true one
+        let conditional = terminator.get_opcode() == InstructionOpcode::Br && targets.len() == 2;
+        for (k, target) in targets.iter().enumerate() {
+            let label = match (conditional, k) {
+                (false, _) => "",
+                (true, 0) => " [label=\"false\"]",
+                (true, _) => " [label=\"true\"]",
+            };
+            let _ = writeln!(out, "  b{} -> b{}{};", i, node(*target), label);
+        }
+    }
+    out.push_str("}\n");
+    out
+}
+
+// A file name for the graph of `function`. Operator names have characters that are awkward in
+// paths, so anything but letters, digits, `_`, `-` and `.` is written as _ and its code in hex
+pub fn file_name(function: &str) -> String {
+    let mut name = String::new();
+    for c in function.chars() {
+        if c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.') {
+            name.push(c);
+        } else {
+            let _ = write!(name, "_{:x}", c as u32);
+        }
+    }
+    format!("{}.dot", name)
+}
+
+fn escape(text: &str) -> String {
+    text.replace('\\', "\\\\").replace('"', "\\\"")
+}
@@ -0,0 +1,265 @@
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use inkwell::context::Context;
+use inkwell::execution_engine::{ExecutionEngine, JitFunction, UnsafeFunctionPointer};
+use inkwell::module::Module;
+use inkwell::OptimizationLevel;
+
+use crate::ast::Expr;
+use crate::codegen::CodegenContext;
+use crate::externs::{self, FfiRegistry, OutputSink};
+use crate::fold;
+use crate::lexer::LexerContext;
+use crate::modules::ModuleLoader;
+use crate::parser::ParserContext;
+use crate::prelude;
+use crate::resolver::{self, Redefinition};
+
+// Signatures a compiled function can be fetched as with Engine::get_function. Every def takes
+// and returns doubles, so only the number of parameters has to match
+pub trait KlsFunction: UnsafeFunctionPointer {
+    const ARITY: usize;
+}
+
+macro_rules! kls_function {
+    ($arity:expr; $($arg:ty),*) => {
+        impl KlsFunction for unsafe extern "C" fn($($arg),*) -> f64 {
+            const ARITY: usize = $arity;
+        }
+    };
+}
+
+kls_function!(0;);
+kls_function!(1; f64);
+kls_function!(2; f64, f64);
+kls_function!(3; f64, f64, f64);
+kls_function!(4; f64, f64, f64, f64);
+kls_function!(5; f64, f64, f64, f64, f64);
+kls_function!(6; f64, f64, f64, f64, f64, f64);
+
+type Fn0 = unsafe extern "C" fn() -> f64;
+type Fn1 = unsafe extern "C" fn(f64) -> f64;
+type Fn2 = unsafe extern "C" fn(f64, f64) -> f64;
+type Fn3 = unsafe extern "C" fn(f64, f64, f64) -> f64;
+type Fn4 = unsafe extern "C" fn(f64, f64, f64, f64) -> f64;
+type Fn5 = unsafe extern "C" fn(f64, f64, f64, f64, f64) -> f64;
+type Fn6 = unsafe extern "C" fn(f64, f64, f64, f64, f64, f64) -> f64;
+
+// A compilation session for embedding the compiler in a Rust program. Sources passed to
+// compile_str and eval build on each other: functions and operators defined by one are
+// available to the next, and a source may define a function again to replace it. Code compiled
+// before keeps calling the definition it was compiled with. Each source is compiled into its
+// own module in a shared JIT
+//
+//     let context = Context::create();
+//     let mut engine = Engine::new(&context)?;
+//     engine.compile_str("def sq(x) x * x;")?;
+//     let sq = engine.get_function::<unsafe extern "C" fn(f64) -> f64>("sq")?;
+//     assert_eq!(engine.eval("sq(3) + 1")?, 10.0);
+pub struct Engine<'ctx> {
+    context: &'ctx Context,
+    parser: ParserContext,
+    execution_engine: ExecutionEngine<'ctx>,
+    modules: Vec<Module<'ctx>>,
+    // Number of parsed functions that already have code in one of the modules
+    compiled: usize,
+    // LLVM names of definitions that replaced earlier ones, see CodegenContext::symbols
+    symbols: HashMap<String, String>,
+    // Output of the programs this engine runs, installed for the duration of each eval and call
+    output: RefCell<OutputSink>,
+    pub registry: FfiRegistry,
+    // Where eval_file looks for imports that aren't next to the importing file
+    pub search_paths: Vec<PathBuf>,
+}
+
+impl<'ctx> Engine<'ctx> {
+    pub fn new(context: &'ctx Context) -> Result<Self, String> {
+        let mut engine = Self::without_prelude(context)?;
+        prelude::load(&mut engine.parser)?;
+        Ok(engine)
+    }
+
+    pub fn without_prelude(context: &'ctx Context) -> Result<Self, String> {
+        let module = context.create_module("kls");
+        let execution_engine = module
+            .create_jit_execution_engine(OptimizationLevel::None)
+            .map_err(|e| format!("Failed to create JIT: {}", e))?;
+
+        Ok(Engine {
+            context,
+            parser: ParserContext::new(),
+            execution_engine,
+            modules: vec![module],
+            compiled: 0,
+            symbols: HashMap::new(),
+            output: RefCell::new(OutputSink::Stdout),
+            registry: FfiRegistry::new(),
+            search_paths: Vec::new(),
+        })
+    }
+
+    // Compile the definitions in `source`. Top-level expressions are compiled but not run
+    pub fn compile_str(&mut self, source: &str) -> Result<(), String> {
+        self.compile(source, None).map(|_| ())
+    }
+
+    // Compile `source` and run its top-level expressions, returning the value of the last one
+    // (0 if there are none)
+    pub fn eval(&mut self, source: &str) -> Result<f64, String> {
+        self.eval_source(source, None)
+    }
+
+    fn eval_source(&mut self, source: &str, file: Option<&Path>) -> Result<f64, String> {
+        let entry = self.compile(source, file)?;
+        let main_fn = unsafe { self.execution_engine.get_function::<Fn0>(&entry) }
+            .map_err(|e| format!("Failed to get {}: {}", entry, e))?;
+        Ok(self.with_output(|| unsafe { main_fn.call() }))
+    }
+
+    // Like eval, for a program read from a file. Its imports are resolved relative to it
+    pub fn eval_file(&mut self, path: &Path) -> Result<f64, String> {
+        let source = fs::read_to_string(path)
+            .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+        self.parser.loader = Some(ModuleLoader::new(self.search_paths.clone(), Some(path)));
+        let result = self.eval_source(&source, Some(path));
+        self.parser.loader = None;
+        result
+    }
+
+    // The test "name" { ... } blocks compiled so far, as (test name, function name) pairs. Run
+    // one with call(function, &[]) and check externs::take_assert_failures afterwards
+    pub fn tests(&self) -> Vec<(String, String)> {
+        self.parser.functions[..self.compiled]
+            .iter()
+            .filter_map(|f| f.test.as_ref().map(|t| (t.clone(), f.name.clone())))
+            .collect()
+    }
+
+    // Route what programs print through putchard and printd to `sink`. The default is stdout
+    pub fn set_output(&self, sink: OutputSink) {
+        self.output.replace(sink);
+    }
+
+    // Take back the current sink (with everything written to it), leaving stdout in its place
+    pub fn take_output(&self) -> OutputSink {
+        self.output.replace(OutputSink::Stdout)
+    }
+
+    // Install this engine's sink while running JIT code, then store it back
+    fn with_output<T>(&self, run: impl FnOnce() -> T) -> T {
+        let previous = externs::set_output(self.output.replace(OutputSink::Stdout));
+        let result = run();
+        self.output.replace(externs::set_output(previous));
+        result
+    }
+
+    // Look up a compiled def. Fails if it doesn't take as many parameters as F. Calling the
+    // returned function directly writes output to the thread's sink (externs::set_output)
+    // rather than the engine's
+    pub fn get_function<F: KlsFunction>(&self, name: &str) -> Result<JitFunction<'ctx, F>, String> {
+        let f = self
+            .parser
+            .functions
+            .iter()
+            .rev()
+            .find(|f| f.name == name && !matches!(f.body, Expr::None))
+            .ok_or_else(|| format!("Unknown function: {}", name))?;
+        if f.args.len() != F::ARITY {
+            return Err(format!(
+                "{} takes {} arguments, but was requested with {}",
+                name,
+                f.args.len(),
+                F::ARITY
+            ));
+        }
+
+        // Defs are always compiled as double(double, ...), which is what F describes
+        let symbol = self.symbols.get(name).map(|s| s.as_str()).unwrap_or(name);
+        unsafe { self.execution_engine.get_function::<F>(symbol) }
+            .map_err(|e| format!("Failed to get {}: {}", name, e))
+    }
+
+    // Call a compiled def with the given arguments
+    pub fn call(&self, name: &str, args: &[f64]) -> Result<f64, String> {
+        // The arity check in get_function makes these calls sound
+        self.with_output(|| unsafe {
+            Ok(match *args {
+                [] => self.get_function::<Fn0>(name)?.call(),
+                [a] => self.get_function::<Fn1>(name)?.call(a),
+                [a, b] => self.get_function::<Fn2>(name)?.call(a, b),
+                [a, b, c] => self.get_function::<Fn3>(name)?.call(a, b, c),
+                [a, b, c, d] => self.get_function::<Fn4>(name)?.call(a, b, c, d),
+                [a, b, c, d, e] => self.get_function::<Fn5>(name)?.call(a, b, c, d, e),
+                [a, b, c, d, e, f] => self.get_function::<Fn6>(name)?.call(a, b, c, d, e, f),
+                _ => return Err(format!("Can not call {} with more than 6 arguments", name)),
+            })
+        })
+    }
+
+    // Parse and generate a new module for `source`, returning the name of the function holding
+    // its top-level expressions. On error the session is left as it was: the functions and
+    // operators the source defined are forgotten
+    fn compile(&mut self, source: &str, file: Option<&Path>) -> Result<String, String> {
+        let parsed = self.parser.functions.len();
+        let precedence = self.parser.binop_precedence.clone();
+        let result = self.compile_new(source, file);
+        if result.is_err() {
+            self.parser.functions.truncate(parsed);
+            self.parser.binop_precedence = precedence;
+        }
+        result
+    }
+
+    fn compile_new(&mut self, source: &str, file: Option<&Path>) -> Result<String, String> {
+        let mut lexer = LexerContext::new();
+        lexer.file = file.map(|p| p.display().to_string());
+        lexer.lex(source)?;
+        self.parser.parse(&mut lexer)?;
+
+        // Definitions in a file must not clash with earlier ones, but sources given directly
+        // may replace them
+        let redefinition = match file {
+            Some(_) => Redefinition::Error,
+            None => Redefinition::Replace,
+        };
+        resolver::resolve_from(&self.parser.functions, self.compiled, redefinition)?;
+        fold::fold_from(&mut self.parser.functions, self.compiled)?;
+
+        let id = self.modules.len();
+        let entry = format!("kls.entry.{}", id);
+        let mut cg = CodegenContext::with_entry(self.context, &format!("kls.{}", id), &entry);
+
+        // A name defined again gets a new LLVM name, as the old definition stays in its module
+        // for the code compiled against it
+        let (earlier, new) = self.parser.functions.split_at(self.compiled);
+        let mut symbols = self.symbols.clone();
+        let mut redefined = HashSet::new();
+        for f in new.iter().filter(|f| earlier.iter().any(|e| e.name == f.name)) {
+            symbols.insert(f.name.clone(), format!("{}#{}", f.name, id));
+            redefined.insert(f.name.as_str());
+        }
+        cg.symbols = symbols.clone();
+
+        // Functions from earlier sources live in other modules, so the latest definition of each
+        // is only declared here
+        let mut declared = HashSet::new();
+        earlier
+            .iter()
+            .rev()
+            .filter(|f| f.name != "_top_level_expr" && !redefined.contains(f.name.as_str()))
+            .filter(|f| declared.insert(f.name.as_str()))
+            .try_for_each(|f| cg.declare_external(f, &self.registry, &self.execution_engine))?;
+        cg.codegen_functions(new, &self.registry, &self.execution_engine)?;
+
+        self.execution_engine
+            .add_module(&cg.module)
+            .map_err(|_| String::from("Failed to add module to the JIT"))?;
+        self.modules.push(cg.module);
+        self.compiled = self.parser.functions.len();
+        self.symbols = symbols;
+        Ok(entry)
+    }
+}
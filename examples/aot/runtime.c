@@ -0,0 +1,46 @@
+// What an object file from --emit-object needs to become a program: a main() calling the
+// top-level code, the default externs and the assertion runtime. Build and debug with:
+//   cargo run -- -g --emit-object fib.o examples/itefib.kls
+//   cc -g -o fib fib.o examples/aot/runtime.c -lm
+//   gdb ./fib
+// Externs of the math library come from libm. Other externs need a definition here or in a
+// library linked in
+
+#include <stdio.h>
+
+double kls_main(void);
+
+static int assert_failures = 0;
+
+double putchard(double x) {
+    putchar((char)x);
+    return 0;
+}
+
+double printd(double x) {
+    printf("%g\n", x);
+    return 0;
+}
+
+double __kls_assert(double cond, const char *what) {
+    if (cond == 0) {
+        fprintf(stderr, "%s failed\n", what);
+        assert_failures++;
+        return 0;
+    }
+    return 1;
+}
+
+double __kls_assert_eq(double left, double right, const char *what) {
+    if (left != right) {
+        fprintf(stderr, "%s failed: %g != %g\n", what, left, right);
+        assert_failures++;
+        return 0;
+    }
+    return 1;
+}
+
+int main(void) {
+    printf("\nResult: %g\n", kls_main());
+    return assert_failures > 0;
+}
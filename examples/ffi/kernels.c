@@ -0,0 +1,12 @@
+// Native kernels called from kernels.kls. Build with:
+//   cc -shared -fPIC -o examples/ffi/libkernels.so examples/ffi/kernels.c
+// and run with:
+//   cargo run -- --link-lib examples/ffi/libkernels.so examples/ffi/kernels.kls
+
+double dot3(double ax, double ay, double az, double bx, double by, double bz) {
+    return ax * bx + ay * by + az * bz;
+}
+
+double clamp(double x, double lo, double hi) {
+    return x < lo ? lo : (x > hi ? hi : x);
+}
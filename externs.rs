@@ -1,35 +1,639 @@
+use std::any::Any;
+use std::cell::RefCell;
 use std::collections::HashMap;
+use std::ffi::{c_char, CStr, CString};
+use std::fs::File;
 use std::io::{self, Write};
+use std::path::Path;
+
+use libffi::middle::{Arg, Cif, CodePtr, Type};
+
+use crate::ast::{CType, Function, Signature};
+
+// Where putchard and printd write to. The sink is per thread, so each execution can route its
+// output without affecting others running elsewhere
+pub enum OutputSink {
+    Stdout,
+    Buffer(Vec<u8>),
+    File(File),
+    Writer(Box<dyn Write>),
+}
+
+impl OutputSink {
+    // Everything written so far, if this is a buffer
+    pub fn contents(&self) -> Option<String> {
+        match self {
+            OutputSink::Buffer(buf) => Some(String::from_utf8_lossy(buf).to_string()),
+            _ => None,
+        }
+    }
+}
+
+impl Write for OutputSink {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            OutputSink::Stdout => io::stdout().write(buf),
+            OutputSink::Buffer(v) => v.write(buf),
+            OutputSink::File(f) => f.write(buf),
+            OutputSink::Writer(w) => w.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            OutputSink::Stdout => io::stdout().flush(),
+            OutputSink::Buffer(_) => Ok(()),
+            OutputSink::File(f) => f.flush(),
+            OutputSink::Writer(w) => w.flush(),
+        }
+    }
+}
+
+thread_local! {
+    static OUTPUT: RefCell<OutputSink> = const { RefCell::new(OutputSink::Stdout) };
+}
+
+// Install `sink` for code run on this thread, returning the previous one
+pub fn set_output(sink: OutputSink) -> OutputSink {
+    OUTPUT.with(|o| o.replace(sink))
+}
+
+// Write errors can't be reported back to the program, so they are dropped
+pub fn write_output(text: &str) {
+    OUTPUT.with(|o| {
+        let mut sink = o.borrow_mut();
+        let _ = sink.write_all(text.as_bytes()).and_then(|_| sink.flush());
+    });
+}
+
+// Runtime support for assert and assert_eq. A failure doesn't stop the program: failures are
+// collected per thread, and whoever ran the code reports them
+
+thread_local! {
+    static ASSERT_FAILURES: RefCell<Vec<String>> = const { RefCell::new(Vec::new()) };
+}
+
+// Messages of the assertions that failed on this thread since the last call
+pub fn take_assert_failures() -> Vec<String> {
+    ASSERT_FAILURES.with(|f| f.take())
+}
+
+fn assert_failed(message: String) -> f64 {
+    ASSERT_FAILURES.with(|f| f.borrow_mut().push(message));
+    0.0
+}
+
+// Check assert(cond) or assert_eq(left, right), depending on the number of values. `what` is
+// the location and source text of the assertion. Returns 1 if it holds, 0 if it failed
+pub fn check_assert(what: &str, values: &[f64]) -> f64 {
+    match *values {
+        [0.0] => assert_failed(format!("{} failed", what)),
+        [left, right] if left != right => {
+            assert_failed(format!("{} failed: {} != {}", what, left, right))
+        }
+        _ => 1.0,
+    }
+}
+
+extern "C" fn kls_assert(cond: f64, what: *const c_char) -> f64 {
+    check_assert(&unsafe { CStr::from_ptr(what) }.to_string_lossy(), &[cond])
+}
+
+extern "C" fn kls_assert_eq(left: f64, right: f64, what: *const c_char) -> f64 {
+    check_assert(
+        &unsafe { CStr::from_ptr(what) }.to_string_lossy(),
+        &[left, right],
+    )
+}
+
+// Functions generated code calls directly, without an extern declaration
+pub const RUNTIME_FUNCTIONS: [&str; 2] = ["__kls_assert", "__kls_assert_eq"];
+
+pub fn runtime_function(name: &str) -> Option<usize> {
+    match name {
+        "__kls_assert" => Some(kls_assert as *const () as usize),
+        "__kls_assert_eq" => Some(kls_assert_eq as *const () as usize),
+        _ => None,
+    }
+}
 
 // Define all available extern functions here
 
 extern "C" fn putchard(x: f64) -> f64 {
-    print!("{}", x as u8 as char);
-    io::stdout().flush().unwrap();
+    write_output(&(x as u8 as char).to_string());
     0.0
 }
 
 extern "C" fn printd(x: f64) -> f64 {
-    println!("{}", x);
+    write_output(&format!("{}\n", x));
     0.0
 }
 
+// Math library, backed by libm through std's f64 methods
+
+extern "C" fn sin(x: f64) -> f64 {
+    x.sin()
+}
+
+extern "C" fn cos(x: f64) -> f64 {
+    x.cos()
+}
+
+extern "C" fn tan(x: f64) -> f64 {
+    x.tan()
+}
+
+extern "C" fn sqrt(x: f64) -> f64 {
+    x.sqrt()
+}
+
+extern "C" fn exp(x: f64) -> f64 {
+    x.exp()
+}
+
+extern "C" fn log(x: f64) -> f64 {
+    x.ln()
+}
+
+extern "C" fn pow(x: f64, y: f64) -> f64 {
+    x.powf(y)
+}
+
+extern "C" fn floor(x: f64) -> f64 {
+    x.floor()
+}
+
+extern "C" fn ceil(x: f64) -> f64 {
+    x.ceil()
+}
+
+extern "C" fn fabs(x: f64) -> f64 {
+    x.abs()
+}
+
+extern "C" fn fmod(x: f64, y: f64) -> f64 {
+    x % y
+}
+
+extern "C" fn atan2(y: f64, x: f64) -> f64 {
+    y.atan2(x)
+}
+
+extern "C" fn hypot(x: f64, y: f64) -> f64 {
+    x.hypot(y)
+}
+
+fn last_dl_error() -> String {
+    let err = unsafe { libc::dlerror() };
+    if err.is_null() {
+        String::from("unknown error")
+    } else {
+        unsafe { CStr::from_ptr(err) }.to_string_lossy().to_string()
+    }
+}
+
+// A Rust closure registered with FfiRegistry::register. Generated code calls it like a closure
+// record: `code` is a trampoline taking the boxed closure `env` as a hidden first argument,
+// followed by `arity` doubles
+pub struct HostFunction {
+    pub code: usize,
+    pub env: usize,
+    pub arity: usize,
+}
+
+// What an extern declaration resolves to
+pub enum ResolvedExtern<'a> {
+    Native(usize),
+    Host(&'a HostFunction),
+}
+
+// An extern resolved for calling from Rust, by backends that don't generate code
+pub enum NativeFunction<'a> {
+    Address(usize),
+    Host { code: usize, env: usize },
+    // Externs with a C signature, whose arguments and result are converted like compiled code
+    // converts them
+    Typed(usize, &'a Signature),
+}
+
+impl NativeFunction<'_> {
+    // Call the extern `name` resolved to. The registry checked the arity of registered functions
+    // and host closures. Symbols from libraries are trusted to match their declaration, as they
+    // are when compiled
+    pub fn call(&self, name: &str, args: &[f64]) -> Result<f64, String> {
+        type C0 = extern "C" fn() -> f64;
+        type C1 = extern "C" fn(f64) -> f64;
+        type C2 = extern "C" fn(f64, f64) -> f64;
+        type C3 = extern "C" fn(f64, f64, f64) -> f64;
+        type C4 = extern "C" fn(f64, f64, f64, f64) -> f64;
+        type C5 = extern "C" fn(f64, f64, f64, f64, f64) -> f64;
+        type C6 = extern "C" fn(f64, f64, f64, f64, f64, f64) -> f64;
+        type H0 = extern "C" fn(usize) -> f64;
+        type H1 = extern "C" fn(usize, f64) -> f64;
+        type H2 = extern "C" fn(usize, f64, f64) -> f64;
+        type H3 = extern "C" fn(usize, f64, f64, f64) -> f64;
+        type H4 = extern "C" fn(usize, f64, f64, f64, f64) -> f64;
+        type H5 = extern "C" fn(usize, f64, f64, f64, f64, f64) -> f64;
+        type H6 = extern "C" fn(usize, f64, f64, f64, f64, f64, f64) -> f64;
+
+        let too_many = || format!("Can not call extern {} with more than 6 arguments", name);
+
+        match self {
+            NativeFunction::Address(addr) => unsafe {
+                let addr = *addr;
+                Ok(match *args {
+                    [] => std::mem::transmute::<usize, C0>(addr)(),
+                    [a] => std::mem::transmute::<usize, C1>(addr)(a),
+                    [a, b] => std::mem::transmute::<usize, C2>(addr)(a, b),
+                    [a, b, c] => std::mem::transmute::<usize, C3>(addr)(a, b, c),
+                    [a, b, c, d] => std::mem::transmute::<usize, C4>(addr)(a, b, c, d),
+                    [a, b, c, d, e] => std::mem::transmute::<usize, C5>(addr)(a, b, c, d, e),
+                    [a, b, c, d, e, f] => std::mem::transmute::<usize, C6>(addr)(a, b, c, d, e, f),
+                    _ => return Err(too_many()),
+                })
+            },
+            NativeFunction::Host { code, env } => unsafe {
+                let (code, env) = (*code, *env);
+                Ok(match *args {
+                    [] => std::mem::transmute::<usize, H0>(code)(env),
+                    [a] => std::mem::transmute::<usize, H1>(code)(env, a),
+                    [a, b] => std::mem::transmute::<usize, H2>(code)(env, a, b),
+                    [a, b, c] => std::mem::transmute::<usize, H3>(code)(env, a, b, c),
+                    [a, b, c, d] => std::mem::transmute::<usize, H4>(code)(env, a, b, c, d),
+                    [a, b, c, d, e] => std::mem::transmute::<usize, H5>(code)(env, a, b, c, d, e),
+                    [a, b, c, d, e, f] => {
+                        std::mem::transmute::<usize, H6>(code)(env, a, b, c, d, e, f)
+                    }
+                    _ => return Err(too_many()),
+                })
+            },
+            NativeFunction::Typed(addr, sig) => Ok(call_typed(*addr, sig, args)),
+        }
+    }
+}
+
+// An argument converted to the C type of its parameter
+enum CValue {
+    I32(i32),
+    I64(i64),
+    F32(f32),
+    F64(f64),
+    Ptr(usize),
+}
+
+// Externs with a C signature are called through libffi, which passes every argument where the
+// platform's calling convention puts a value of its type. Arguments and results are converted
+// the way compiled code converts them
+fn call_typed(addr: usize, sig: &Signature, args: &[f64]) -> f64 {
+    let ffi_type = |ty: &CType| match ty {
+        CType::I32 => Type::i32(),
+        CType::I64 => Type::i64(),
+        CType::F32 => Type::f32(),
+        CType::F64 => Type::f64(),
+        CType::Ptr => Type::pointer(),
+        CType::Void => Type::void(),
+    };
+    let values: Vec<CValue> = sig
+        .params
+        .iter()
+        .zip(args)
+        .map(|(ty, arg)| match ty {
+            CType::I32 => CValue::I32(*arg as i32),
+            // Pointers are passed around as their address, as a number
+            CType::I64 => CValue::I64(*arg as i64),
+            CType::Ptr => CValue::Ptr(*arg as i64 as usize),
+            CType::F32 => CValue::F32(*arg as f32),
+            CType::F64 | CType::Void => CValue::F64(*arg),
+        })
+        .collect();
+    let ffi_args: Vec<Arg> = values
+        .iter()
+        .map(|value| match value {
+            CValue::I32(v) => Arg::new(v),
+            CValue::I64(v) => Arg::new(v),
+            CValue::F32(v) => Arg::new(v),
+            CValue::F64(v) => Arg::new(v),
+            CValue::Ptr(v) => Arg::new(v),
+        })
+        .collect();
+
+    let cif = Cif::new(sig.params.iter().map(ffi_type), ffi_type(&sig.ret));
+    let code = CodePtr(addr as *mut libc::c_void);
+    unsafe {
+        match sig.ret {
+            CType::I32 => cif.call::<i32>(code, &ffi_args) as f64,
+            CType::I64 => cif.call::<i64>(code, &ffi_args) as f64,
+            CType::Ptr => cif.call::<usize>(code, &ffi_args) as i64 as f64,
+            CType::F32 => cif.call::<f32>(code, &ffi_args) as f64,
+            CType::F64 => cif.call::<f64>(code, &ffi_args),
+            // void functions evaluate to 0
+            CType::Void => {
+                cif.call::<()>(code, &ffi_args);
+                0.0
+            }
+        }
+    }
+}
+
+// Closures that can be registered as externs. Args is the tuple of parameter types, all f64, so
+// the arity a closure is called with is fixed by its type. Closures are Fn rather than FnMut, as
+// one may call back into the engine and so end up running again before it returns: state they
+// keep goes in a Cell or RefCell
+pub trait IntoHostFunction<Args> {
+    const ARITY: usize;
+
+    // Box the closure, returning how to call it and the box that keeps it alive
+    fn into_host(self) -> (HostFunction, Box<dyn Any>);
+}
+
+macro_rules! host_function {
+    (@f64 $arg:ident) => { f64 };
+    ($arity:expr; $($arg:ident),*) => {
+        impl<F> IntoHostFunction<($(host_function!(@f64 $arg),)*)> for F
+        where
+            F: Fn($(host_function!(@f64 $arg)),*) -> f64 + 'static,
+        {
+            const ARITY: usize = $arity;
+
+            fn into_host(self) -> (HostFunction, Box<dyn Any>) {
+                unsafe extern "C" fn trampoline<F>(env: *const libc::c_void, $($arg: f64),*) -> f64
+                where
+                    F: Fn($(host_function!(@f64 $arg)),*) -> f64,
+                {
+                    unsafe { (*(env as *const F))($($arg),*) }
+                }
+
+                let boxed = Box::new(self);
+                let env = &*boxed as *const F as usize;
+                let host = HostFunction {
+                    code: trampoline::<F> as *const () as usize,
+                    env,
+                    arity: Self::ARITY,
+                };
+                (host, boxed)
+            }
+        }
+    };
+}
+
+host_function!(0;);
+host_function!(1; x0);
+host_function!(2; x0, x1);
+host_function!(3; x0, x1, x2);
+host_function!(4; x0, x1, x2, x3);
+host_function!(5; x0, x1, x2, x3, x4);
+host_function!(6; x0, x1, x2, x3, x4, x5);
+
+// Levenshtein distance between two names
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut prev: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut cur = vec![i + 1];
+        for (j, cb) in b.iter().enumerate() {
+            let substitute = prev[j] + usize::from(ca != *cb);
+            cur.push(substitute.min(prev[j + 1] + 1).min(cur[j] + 1));
+        }
+        prev = cur;
+    }
+    prev[b.len()]
+}
+
 pub struct FfiRegistry {
-    functions: HashMap<String, usize>,
+    // Native functions by name, with their address and number of parameters
+    functions: HashMap<String, (usize, usize)>,
+    // Closures registered by the embedder. They shadow native functions of the same name
+    hosts: HashMap<String, HostFunction>,
+    // Every closure ever registered, including replaced ones, since code compiled earlier still
+    // points at them
+    host_owners: Vec<Box<dyn Any>>,
+    // Shared libraries opened with load_library in the order they were loaded, named by file
+    // name without extension (libfoo for path/to/libfoo.so). A symbol several of them export
+    // comes from the first one, as when linking. Handles are never closed, since JIT code may
+    // still call into them
+    libraries: Vec<(String, *mut libc::c_void)>,
+}
+
+impl Default for FfiRegistry {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl FfiRegistry {
     pub fn new() -> Self {
         let mut functions = HashMap::new();
+        let mut add = |name: &str, addr: *const (), arity: usize| {
+            functions.insert(name.to_string(), (addr as usize, arity));
+        };
 
         // Register available extern functions
-        functions.insert("putchard".to_string(), putchard as usize);
-        functions.insert("printd".to_string(), printd as usize);
+        add("putchard", putchard as *const (), 1);
+        add("printd", printd as *const (), 1);
+        add("sin", sin as *const (), 1);
+        add("cos", cos as *const (), 1);
+        add("tan", tan as *const (), 1);
+        add("sqrt", sqrt as *const (), 1);
+        add("exp", exp as *const (), 1);
+        add("log", log as *const (), 1);
+        add("pow", pow as *const (), 2);
+        add("floor", floor as *const (), 1);
+        add("ceil", ceil as *const (), 1);
+        add("fabs", fabs as *const (), 1);
+        add("fmod", fmod as *const (), 2);
+        add("atan2", atan2 as *const (), 2);
+        add("hypot", hypot as *const (), 2);
 
-        FfiRegistry { functions }
+        FfiRegistry {
+            functions,
+            hosts: HashMap::new(),
+            host_owners: Vec::new(),
+            libraries: Vec::new(),
+        }
     }
 
     pub fn get(&self, name: &str) -> Option<usize> {
-        self.functions.get(name).copied()
+        self.functions.get(name).map(|(addr, _)| *addr)
+    }
+
+    // Number of parameters of a registered function or host closure. Symbols found in shared
+    // libraries have no known signature
+    pub fn arity(&self, name: &str) -> Option<usize> {
+        self.hosts
+            .get(name)
+            .map(|h| h.arity)
+            .or_else(|| self.functions.get(name).map(|(_, arity)| *arity))
+    }
+
+    // Registered functions and host closures with their arity, sorted by name
+    pub fn list(&self) -> Vec<(String, usize)> {
+        let mut names: Vec<(String, usize)> = self
+            .functions
+            .iter()
+            .map(|(name, (_, arity))| (name.clone(), *arity))
+            .filter(|(name, _)| !self.hosts.contains_key(name))
+            .chain(self.hosts.iter().map(|(name, h)| (name.clone(), h.arity)))
+            .collect();
+        names.sort();
+        names
+    }
+
+    // Names of the loaded shared libraries, in the order they were loaded
+    pub fn library_names(&self) -> Vec<String> {
+        self.libraries.iter().map(|(name, _)| name.clone()).collect()
+    }
+
+    fn library(&self, name: &str) -> Option<*mut libc::c_void> {
+        self.libraries
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, handle)| *handle)
+    }
+
+    // Registered names close enough to `name` to be likely typos of it, best first
+    pub fn suggest(&self, name: &str) -> Vec<String> {
+        let max_distance = (name.len() / 3).max(1);
+        let mut candidates: Vec<(usize, String)> = self
+            .list()
+            .into_iter()
+            .map(|(candidate, _)| (edit_distance(name, &candidate), candidate))
+            .filter(|(d, _)| *d <= max_distance)
+            .collect();
+        candidates.sort();
+        candidates.into_iter().take(3).map(|(_, c)| c).collect()
+    }
+
+    // Make a Rust closure callable as `extern name(...)`. An extern declared with a different
+    // number of parameters than the closure takes is a compile error, as is declaring it with a
+    // C signature. Registering a name again replaces the closure for code compiled afterwards
+    //
+    //     let count = Cell::new(0.0);
+    //     registry.register("tick", move |step: f64| {
+    //         count.set(count.get() + step);
+    //         count.get()
+    //     });
+    pub fn register<Args, F: IntoHostFunction<Args>>(&mut self, name: &str, f: F) {
+        let (host, owner) = f.into_host();
+        self.hosts.insert(name.to_string(), host);
+        self.host_owners.push(owner);
+    }
+
+    pub fn host(&self, name: &str) -> Option<&HostFunction> {
+        self.hosts.get(name)
+    }
+
+    pub fn load_library(&mut self, path: &Path) -> Result<(), String> {
+        let file_name = path
+            .file_name()
+            .map(|s| s.to_string_lossy().to_string())
+            .ok_or_else(|| format!("Invalid library path: {}", path.display()))?;
+        let name = file_name.split('.').next().unwrap_or(&file_name).to_string();
+
+        let c_path = CString::new(path.to_string_lossy().as_bytes())
+            .map_err(|_| format!("Invalid library path: {}", path.display()))?;
+        let handle = unsafe { libc::dlopen(c_path.as_ptr(), libc::RTLD_NOW | libc::RTLD_LOCAL) };
+        if handle.is_null() {
+            return Err(format!(
+                "Failed to load library {}: {}",
+                path.display(),
+                last_dl_error()
+            ));
+        }
+
+        self.libraries.push((name, handle));
+        Ok(())
+    }
+
+    // Resolve an extern declaration, checking it against what it resolves to: host closures
+    // take doubles and a fixed number of them, and registered functions have a known arity
+    pub fn resolve_extern(&self, f: &Function) -> Result<ResolvedExtern<'_>, String> {
+        if f.library.is_none()
+            && let Some(host) = self.host(&f.name)
+        {
+            if f.signature.is_some() {
+                return Err(format!(
+                    "extern {} is registered as a host function, which can not have a C signature",
+                    f.name
+                ));
+            }
+            if f.args.len() != host.arity {
+                return Err(format!(
+                    "extern {} is declared with {} arguments, but its host function takes {}",
+                    f.name,
+                    f.args.len(),
+                    host.arity
+                ));
+            }
+            return Ok(ResolvedExtern::Host(host));
+        }
+
+        let addr = self.resolve(f.library.as_deref(), &f.name, f.signature.is_some())?;
+        if f.library.is_none()
+            && let Some(arity) = self.arity(&f.name)
+            && arity != f.args.len()
+        {
+            return Err(format!(
+                "extern {} is declared with {} arguments, but the native function takes {}",
+                f.name,
+                f.args.len(),
+                arity
+            ));
+        }
+        Ok(ResolvedExtern::Native(addr))
+    }
+
+    // Resolve an extern declaration for calling it from Rust
+    pub fn native_function<'f>(&self, f: &'f Function) -> Result<NativeFunction<'f>, String> {
+        Ok(match (self.resolve_extern(f)?, &f.signature) {
+            (ResolvedExtern::Native(addr), Some(sig)) => NativeFunction::Typed(addr, sig),
+            (ResolvedExtern::Native(addr), None) => NativeFunction::Address(addr),
+            (ResolvedExtern::Host(host), _) => NativeFunction::Host {
+                code: host.code,
+                env: host.env,
+            },
+        })
+    }
+
+    // Find the native address for an extern. Externs naming a library are looked up only in
+    // that library (which must have been loaded), others in the registered functions, then in
+    // every loaded library. Externs with a C signature (`typed`) are finally looked up in the
+    // process itself, the C library and anything it links, whose functions rarely take and
+    // return only doubles. A name found nowhere is an error
+    pub fn resolve(&self, library: Option<&str>, name: &str, typed: bool) -> Result<usize, String> {
+        let c_name = CString::new(name).map_err(|_| format!("Invalid symbol name: {}", name))?;
+        let lookup = |handle: *mut libc::c_void| {
+            let addr = unsafe { libc::dlsym(handle, c_name.as_ptr()) };
+            (!addr.is_null()).then_some(addr as usize)
+        };
+
+        match library {
+            Some(lib) => {
+                let handle = self
+                    .library(lib)
+                    .or_else(|| self.library(&format!("lib{}", lib)))
+                    .ok_or_else(|| {
+                        format!(
+                            "Library \"{}\" is not loaded (pass --link-lib path/to/{}.so)",
+                            lib, lib
+                        )
+                    })?;
+                lookup(handle)
+                    .ok_or_else(|| format!("Symbol {} not found in library \"{}\"", name, lib))
+            }
+            None => self
+                .get(name)
+                .or_else(|| self.libraries.iter().find_map(|(_, h)| lookup(*h)))
+                .or_else(|| typed.then(|| lookup(libc::RTLD_DEFAULT)).flatten())
+                .ok_or_else(|| {
+                    let mut msg = format!("Unknown extern: {}", name);
+                    let suggestions = self.suggest(name);
+                    if !suggestions.is_empty() {
+                        msg.push_str(&format!(" (did you mean {}?)", suggestions.join(", ")));
+                    } else if lookup(libc::RTLD_DEFAULT).is_some() {
+                        msg.push_str(" (declare it with C types to call the C function)");
+                    }
+                    msg
+                }),
+        }
     }
 }
@@ -0,0 +1,398 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::ast::{Expr, Function};
+use crate::lexer::Token;
+use crate::resolver;
+use crate::visitor::{fold_expr_children, fold_function_children, Folder, Scope};
+
+// Constant folding, run on a resolved program before it is compiled. Operators applied to
+// numbers, an if whose condition is a number, and calls with numbers for arguments are replaced
+// by their value, computed the way the backends would compute it. Evaluation only goes as far
+// as nothing a program could observe happens: functions may loop and declare variables, but
+// calling an extern, asserting, or making or calling a closure leaves the expression as it is.
+// So does running out of fuel, which keeps long or endless calls for run time.
+//
+// Folding also evaluates const declarations, which must be constant in the same sense, and
+// replaces every use of a const name that no variable shadows with its value. Backends compile
+// a const as a function without arguments, so programs with consts must be folded before they
+// run
+
+// Evaluation steps allowed for folding one expression
+const FUEL: usize = 100_000;
+// Nested calls allowed for folding one expression, to stay well within the stack
+const MAX_DEPTH: usize = 200;
+
+pub fn fold_program(functions: &mut [Function]) -> Result<(), String> {
+    fold_from(functions, 0)
+}
+
+// Fold the functions from index `start` on, for programs that grow, like those of an Engine.
+// Uses of a name refer to its last definition
+pub fn fold_from(functions: &mut [Function], start: usize) -> Result<(), String> {
+    let mut evaluator = Evaluator::new(functions, start);
+    for (i, f) in functions.iter().enumerate().filter(|(_, f)| f.is_const) {
+        if evaluator.lookup(&f.name) != Some(i) {
+            continue;
+        }
+        evaluator.fuel = FUEL;
+        evaluator.cycle = false;
+        if evaluator.constant(&f.name).is_none() {
+            return Err(match evaluator.cycle {
+                true => format!("const {} depends on its own value", f.name),
+                false => format!("The value of const {} is not known when compiling", f.name),
+            });
+        }
+    }
+
+    let mut folder = ConstantFolder {
+        evaluator: &mut evaluator,
+        bound: Scope::default(),
+    };
+    let bodies: Vec<Expr> = functions[start..]
+        .iter()
+        .enumerate()
+        .map(|(i, f)| match f.body {
+            Expr::None => Expr::None,
+            _ if f.is_const => Expr::Number(folder.evaluator.constants[&f.name]),
+            _ => {
+                folder.evaluator.context = start + i;
+                folder.fold_function(f.clone()).body
+            }
+        })
+        .collect();
+    for (f, body) in functions[start..].iter_mut().zip(bodies) {
+        f.body = body;
+    }
+    Ok(())
+}
+
+fn truthy(value: f64) -> bool {
+    !value.is_nan() && value != 0.0
+}
+
+fn number(e: &Expr) -> Option<f64> {
+    match e {
+        Expr::Number(value) => Some(*value),
+        Expr::Located { expr, .. } => number(expr),
+        _ => None,
+    }
+}
+
+// Variables of a call being evaluated, kept in slots the way the interpreter keeps them.
+// `outer` holds names bound around the expression being folded, whose values are unknown
+struct Frame<'o> {
+    vars: HashMap<String, usize>,
+    slots: Vec<f64>,
+    outer: &'o [String],
+}
+
+impl<'o> Frame<'o> {
+    fn new(outer: &'o [String]) -> Self {
+        Frame {
+            vars: HashMap::new(),
+            slots: Vec::new(),
+            outer,
+        }
+    }
+
+    fn declare(&mut self, name: &str, value: f64) -> Option<usize> {
+        self.slots.push(value);
+        self.vars.insert(name.to_string(), self.slots.len() - 1)
+    }
+
+    fn get(&self, name: &str) -> Option<f64> {
+        self.vars.get(name).map(|slot| self.slots[*slot])
+    }
+}
+
+// Evaluates expressions at compile time, giving None for anything that is not constant
+struct Evaluator<'a> {
+    functions: &'a [Function],
+    // Index of the last definition of each name
+    definitions: HashMap<&'a str, usize>,
+    // Names defined again differently, which code from before `start` may know by an older
+    // definition, and the index of the function whose code is being evaluated
+    replaced: HashSet<&'a str>,
+    start: usize,
+    context: usize,
+    constants: HashMap<String, f64>,
+    // Consts whose values are being computed, to catch those that depend on themselves
+    evaluating: Vec<String>,
+    cycle: bool,
+    fuel: usize,
+    depth: usize,
+}
+
+impl<'a> Evaluator<'a> {
+    fn new(functions: &'a [Function], start: usize) -> Self {
+        let mut definitions = HashMap::new();
+        let mut replaced = HashSet::new();
+        for (i, f) in functions.iter().enumerate() {
+            if f.name == "_top_level_expr" {
+                continue;
+            }
+            if let Some(previous) = definitions.insert(f.name.as_str(), i)
+                && !resolver::same_definition(&functions[previous], f)
+            {
+                replaced.insert(f.name.as_str());
+            }
+        }
+        Evaluator {
+            functions,
+            definitions,
+            replaced,
+            start,
+            context: start,
+            constants: HashMap::new(),
+            evaluating: Vec::new(),
+            cycle: false,
+            fuel: FUEL,
+            depth: 0,
+        }
+    }
+
+    fn lookup(&self, name: &str) -> Option<usize> {
+        if self.context < self.start && self.replaced.contains(name) {
+            return None;
+        }
+        self.definitions.get(name).copied()
+    }
+
+    // Evaluate the body of function `index`, whose names are looked up as its code knows them
+    fn eval_body(&mut self, index: usize, frame: &mut Frame) -> Option<f64> {
+        let context = std::mem::replace(&mut self.context, index);
+        let value = self.eval(&self.functions[index].body, frame);
+        self.context = context;
+        value
+    }
+
+    fn constant(&mut self, name: &str) -> Option<f64> {
+        let index = self.lookup(name)?;
+        let f = &self.functions[index];
+        if !f.is_const {
+            return None;
+        }
+        if let Some(value) = self.constants.get(name) {
+            return Some(*value);
+        }
+        if self.evaluating.iter().any(|c| c == name) {
+            self.cycle = true;
+            return None;
+        }
+
+        self.evaluating.push(name.to_string());
+        let value = self.eval_body(index, &mut Frame::new(&[]));
+        self.evaluating.pop();
+        if let Some(value) = value {
+            self.constants.insert(name.to_string(), value);
+        }
+        value
+    }
+
+    fn call(&mut self, index: usize, args: &[f64]) -> Option<f64> {
+        let f = &self.functions[index];
+        let callable = !f.is_const && f.test.is_none() && !matches!(f.body, Expr::None);
+        if !callable || f.args.len() != args.len() || self.depth >= MAX_DEPTH {
+            return None;
+        }
+        let mut frame = Frame::new(&[]);
+        for (name, value) in f.args.iter().zip(args) {
+            frame.declare(name, *value);
+        }
+        self.depth += 1;
+        let value = self.eval_body(index, &mut frame);
+        self.depth -= 1;
+        value
+    }
+
+    fn eval(&mut self, e: &Expr, frame: &mut Frame) -> Option<f64> {
+        self.fuel = self.fuel.checked_sub(1)?;
+        match e {
+            Expr::Number(value) => Some(*value),
+            Expr::Located { expr, .. } => self.eval(expr, frame),
+            // Function names used as values are closures, which are not folded
+            Expr::Variable(name) => match frame.get(name) {
+                Some(value) => Some(value),
+                None if frame.outer.contains(name) => None,
+                None => self.constant(name),
+            },
+            Expr::BinOp {
+                left,
+                op: Token::Assign(_),
+                right,
+            } => {
+                let Expr::Variable(name) = left.as_ref() else {
+                    return None;
+                };
+                let value = self.eval(right, frame)?;
+                let slot = *frame.vars.get(name)?;
+                frame.slots[slot] = value;
+                Some(value)
+            }
+            Expr::BinOp { left, op, right } => {
+                let lhs = self.eval(left, frame)?;
+                let rhs = self.eval(right, frame)?;
+                let c = op.operator_char()?;
+                // User-defined operators take precedence over the built-in ones
+                if let Some(index) = self.lookup(&format!("binary{}", c)) {
+                    return self.call(index, &[lhs, rhs]);
+                }
+                match c {
+                    '+' => Some(lhs + rhs),
+                    '-' => Some(lhs - rhs),
+                    '*' => Some(lhs * rhs),
+                    '/' => Some(lhs / rhs),
+                    '<' => Some(if lhs >= rhs { 0.0 } else { 1.0 }),
+                    '>' => Some(if lhs <= rhs { 0.0 } else { 1.0 }),
+                    _ => None,
+                }
+            }
+            Expr::Unary { op, left } => {
+                let operand = self.eval(left, frame)?;
+                let index = self.lookup(&format!("unary{}", op))?;
+                self.call(index, &[operand])
+            }
+            Expr::If {
+                condition,
+                then,
+                els,
+            } => match truthy(self.eval(condition, frame)?) {
+                true => self.eval(then, frame),
+                false => self.eval(els, frame),
+            },
+            Expr::For {
+                ident,
+                start,
+                end,
+                step,
+                body,
+            } => {
+                // As in the interpreter, the end condition is checked before each iteration
+                let start = self.eval(start, frame)?;
+                let old = frame.declare(ident, start);
+                let slot = frame.slots.len() - 1;
+                while truthy(self.eval(end, frame)?) {
+                    self.eval(body, frame)?;
+                    let step = match step {
+                        Some(step) => self.eval(step, frame)?,
+                        None => 1.0,
+                    };
+                    frame.slots[slot] += step;
+                }
+                match old {
+                    Some(old) => frame.vars.insert(ident.clone(), old),
+                    None => frame.vars.remove(ident),
+                };
+                Some(0.0)
+            }
+            Expr::Var { varnames, body } => {
+                let mut old_bindings = Vec::new();
+                for (name, init) in varnames {
+                    let value = match init {
+                        Some(init) => self.eval(init, frame)?,
+                        None => 0.0,
+                    };
+                    old_bindings.push((name, frame.declare(name, value)));
+                }
+                let value = self.eval(body, frame)?;
+                for (name, old) in old_bindings.into_iter().rev() {
+                    match old {
+                        Some(old) => frame.vars.insert(name.clone(), old),
+                        None => frame.vars.remove(name),
+                    };
+                }
+                Some(value)
+            }
+            Expr::Call { identifier, args } => {
+                if frame.vars.contains_key(identifier) || frame.outer.contains(identifier) {
+                    return None;
+                }
+                let index = self.lookup(identifier)?;
+                let mut values = Vec::with_capacity(args.len());
+                for arg in args {
+                    values.push(self.eval(arg, frame)?);
+                }
+                self.call(index, &values)
+            }
+            Expr::Block(exprs) => {
+                let mut last = 0.0;
+                for e in exprs {
+                    last = self.eval(e, frame)?;
+                }
+                Some(last)
+            }
+            Expr::Lambda { .. } | Expr::Assert { .. } | Expr::None => None,
+        }
+    }
+}
+
+// Folds the expressions of a function bottom up, so an expression is only evaluated once its
+// operands have become numbers. Variables are tracked the way the resolver tracks them
+struct ConstantFolder<'e, 'a> {
+    evaluator: &'e mut Evaluator<'a>,
+    bound: Scope,
+}
+
+impl ConstantFolder<'_, '_> {
+    fn evaluate(&mut self, e: &Expr) -> Option<f64> {
+        self.evaluator.fuel = FUEL;
+        self.evaluator.eval(e, &mut Frame::new(self.bound.names()))
+    }
+
+    fn simplify(&mut self, e: Expr) -> Expr {
+        let constant = match &e {
+            Expr::BinOp {
+                op: Token::Assign(_),
+                ..
+            } => false,
+            Expr::BinOp { left, right, .. } => number(left).is_some() && number(right).is_some(),
+            Expr::Unary { left, .. } => number(left).is_some(),
+            Expr::Call { args, .. } => args.iter().all(|a| number(a).is_some()),
+            _ => false,
+        };
+        if constant && let Some(value) = self.evaluate(&e) {
+            return Expr::Number(value);
+        }
+
+        if let Expr::If {
+            condition,
+            then,
+            els,
+        } = &e
+            && let Some(condition) = number(condition)
+        {
+            return if truthy(condition) { then } else { els }.as_ref().clone();
+        }
+        e
+    }
+}
+
+impl Folder for ConstantFolder<'_, '_> {
+    fn fold_function(&mut self, f: Function) -> Function {
+        self.bound.clear();
+        fold_function_children(self, f)
+    }
+
+    fn fold_expr(&mut self, e: Expr) -> Expr {
+        match e {
+            Expr::Variable(name) if !self.bound.contains(&name) => {
+                match self.evaluator.constants.get(&name) {
+                    Some(value) => Expr::Number(*value),
+                    None => Expr::Variable(name),
+                }
+            }
+            e => {
+                let e = fold_expr_children(self, e);
+                self.simplify(e)
+            }
+        }
+    }
+
+    fn bind(&mut self, name: &str) {
+        self.bound.bind(name);
+    }
+
+    fn unbind(&mut self, name: &str) {
+        self.bound.unbind(name);
+    }
+}
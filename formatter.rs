@@ -0,0 +1,725 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use crate::lexer::{LexerContext, Token, Trivia};
+use crate::modules::ModuleLoader;
+use crate::parser::ParserContext;
+use crate::prelude;
+
+// The canonical layout of .kls sources, for the fmt command. Programs are laid out from their
+// tokens rather than their AST, which has no parentheses, imports or comments:
+// - items end with ; and are separated by a line break, or a blank line where the source had one
+// - binary operators are surrounded by spaces. When an expression doesn't fit on a line it is
+//   broken after its loosest operators, and so is one the source already broke there, so
+//   sequences of $ stay one per line
+// - if/then/else chains, for bodies and var bodies always start on a new line, indented by two
+//   spaces except for var, whose body reads on at the same level
+// - arguments that don't fit are wrapped onto lines aligned after the opening parenthesis
+// - comments stay in front of the code they precede, or at the end of the line they ended
+
+const WIDTH: usize = 80;
+const INDENT: usize = 2;
+
+// Format `source`, which must be a program that parses. `path` is where it was read from, for
+// resolving imports, whose operators change how the program parses
+pub fn format_source(source: &str, path: Option<&Path>, search_paths: &[PathBuf]) -> Result<String, String> {
+    let mut lexer = LexerContext::new();
+    lexer.file = path.map(|p| p.display().to_string());
+    lexer.lex(source)?;
+
+    // Parse it the way the compiler does first, both to refuse programs that don't parse and to
+    // know every binary operator
+    let mut parser = ParserContext::new();
+    prelude::load(&mut parser)?;
+    parser.loader = Some(ModuleLoader::new(search_paths.to_vec(), path));
+    parser.parse(&mut lexer)?;
+
+    let tokens: Vec<(Token, String, Vec<Trivia>)> = lexer
+        .tokens_with_trivia()
+        .map(|(tok, text, trivia)| (tok.clone(), text.to_string(), trivia.to_vec()))
+        .collect();
+    let mut builder = Builder {
+        tokens,
+        pos: 0,
+        precedence: parser.binop_precedence,
+    };
+    let doc = builder.program()?;
+
+    let mut printer = Printer::default();
+    printer.print(&doc);
+    printer.newline(0);
+    Ok(printer.out.trim_end().to_string() + "\n")
+}
+
+// A layout to print. Lines are spaces until their group doesn't fit on the line
+enum Doc {
+    Text(String),
+    Line,
+    HardLine,
+    // Own-line comments are printed on a line of their own when they start one, every other
+    // comment is put at the end of the line it ends up on
+    Comment { text: String, own_line: bool },
+    Concat(Vec<Doc>),
+    Nest(usize, Box<Doc>),
+    // Lines inside are indented to the column the Align starts at
+    Align(Box<Doc>),
+    Group(Box<Doc>),
+    // Items separated by Lines that each break only if the next item doesn't fit
+    Fill(Vec<Doc>),
+}
+
+fn text(s: &str) -> Doc {
+    Doc::Text(s.to_string())
+}
+
+fn nest(doc: Doc) -> Doc {
+    Doc::Nest(INDENT, Box::new(doc))
+}
+
+fn align(doc: Doc) -> Doc {
+    Doc::Align(Box::new(doc))
+}
+
+fn group(doc: Doc) -> Doc {
+    Doc::Group(Box::new(doc))
+}
+
+enum Command<'a> {
+    Doc {
+        indent: usize,
+        flat: bool,
+        doc: &'a Doc,
+    },
+    // The rest of a Fill, from item `next` on
+    Fill {
+        indent: usize,
+        items: &'a [Doc],
+        next: usize,
+    },
+}
+
+#[derive(Default)]
+struct Printer {
+    out: String,
+    column: usize,
+    // Whether only indentation is on the current line
+    line_start: bool,
+    // Comments waiting for the end of the line
+    comments: Vec<String>,
+}
+
+impl Printer {
+    fn print(&mut self, doc: &Doc) {
+        self.line_start = true;
+        let mut commands = vec![Command::Doc {
+            indent: 0,
+            flat: false,
+            doc,
+        }];
+        while let Some(command) = commands.pop() {
+            let (indent, flat, doc) = match command {
+                Command::Doc { indent, flat, doc } => (indent, flat, doc),
+                Command::Fill { indent, items, next } => {
+                    if next > 0 {
+                        // What follows the last item is on the line too
+                        let rest: &[Command] = if next + 1 < items.len() { &[] } else { &commands };
+                        let item = &items[next..next + 1];
+                        if fits(item, WIDTH.saturating_sub(self.column + 1), rest, false) {
+                            self.write(" ");
+                        } else {
+                            self.newline(indent);
+                        }
+                    }
+                    if next + 1 < items.len() {
+                        commands.push(Command::Fill {
+                            indent,
+                            items,
+                            next: next + 1,
+                        });
+                    }
+                    let item = &items[next];
+                    let flat = self.fits(std::slice::from_ref(item), &[]);
+                    commands.push(Command::Doc {
+                        indent,
+                        flat,
+                        doc: item,
+                    });
+                    continue;
+                }
+            };
+            match doc {
+                Doc::Text(s) => self.write(s),
+                Doc::Line if flat => self.write(" "),
+                Doc::Line | Doc::HardLine => self.newline(indent),
+                Doc::Comment { text, own_line } => {
+                    if *own_line && self.line_start {
+                        self.write(text);
+                        self.newline(indent);
+                    } else {
+                        self.comments.push(text.clone());
+                    }
+                }
+                Doc::Concat(docs) => {
+                    commands.extend(docs.iter().rev().map(|doc| Command::Doc { indent, flat, doc }));
+                }
+                Doc::Nest(n, doc) => commands.push(Command::Doc {
+                    indent: indent + n,
+                    flat,
+                    doc,
+                }),
+                Doc::Align(doc) => commands.push(Command::Doc {
+                    indent: self.column,
+                    flat,
+                    doc,
+                }),
+                Doc::Group(doc) => {
+                    let flat = flat || self.fits(std::slice::from_ref(doc.as_ref()), &commands);
+                    commands.push(Command::Doc { indent, flat, doc });
+                }
+                Doc::Fill(items) if flat => {
+                    for (i, item) in items.iter().enumerate().rev() {
+                        commands.push(Command::Doc {
+                            indent,
+                            flat,
+                            doc: item,
+                        });
+                        if i > 0 {
+                            commands.push(Command::Doc {
+                                indent,
+                                flat,
+                                doc: &Doc::Line,
+                            });
+                        }
+                    }
+                }
+                Doc::Fill(items) if !items.is_empty() => commands.push(Command::Fill {
+                    indent,
+                    items,
+                    next: 0,
+                }),
+                Doc::Fill(_) => {}
+            }
+        }
+    }
+
+    fn fits(&self, docs: &[Doc], rest: &[Command]) -> bool {
+        fits(docs, WIDTH.saturating_sub(self.column), rest, self.line_start)
+    }
+
+    fn write(&mut self, s: &str) {
+        if !s.is_empty() {
+            self.out.push_str(s);
+            self.column += s.chars().count();
+            self.line_start = false;
+        }
+    }
+
+    fn newline(&mut self, indent: usize) {
+        if !self.comments.is_empty() {
+            let comments = std::mem::take(&mut self.comments).join("  ");
+            if !self.line_start {
+                self.out.push_str("  ");
+            }
+            self.out.push_str(&comments);
+        }
+        let trimmed = self.out.trim_end_matches(' ').len();
+        self.out.truncate(trimmed);
+        self.out.push('\n');
+        self.out.push_str(&" ".repeat(indent));
+        self.column = indent;
+        self.line_start = true;
+    }
+}
+
+// Whether `docs` fit in `width` columns laid out flat, along with what follows them up to the
+// next line break. Hard lines and comments on lines of their own don't fit on one line, unless
+// the comments come first on a line that is still empty
+fn fits(docs: &[Doc], mut width: usize, rest: &[Command], mut line_start: bool) -> bool {
+    let mut stack: Vec<(bool, &Doc)> = docs.iter().rev().map(|d| (true, d)).collect();
+    let mut rest = rest.iter().rev();
+    loop {
+        let (flat, doc) = match stack.pop() {
+            Some(next) => next,
+            None => match rest.next() {
+                Some(Command::Doc { flat, doc, .. }) => (*flat, *doc),
+                Some(Command::Fill { .. }) | None => return true,
+            },
+        };
+        match doc {
+            Doc::Text(s) if s.is_empty() => {}
+            Doc::Text(s) => match width.checked_sub(s.chars().count()) {
+                Some(w) => {
+                    width = w;
+                    line_start = false;
+                }
+                None => return false,
+            },
+            Doc::Comment { own_line: true, .. } if line_start => {}
+            Doc::Line if flat => match width.checked_sub(1) {
+                Some(w) => width = w,
+                None => return false,
+            },
+            // Comments at the end of a line take no room in it
+            Doc::Comment { own_line: false, .. } => {}
+            Doc::Line | Doc::HardLine | Doc::Comment { .. } => return !flat,
+            Doc::Concat(docs) | Doc::Fill(docs) => {
+                let mut first = true;
+                for d in docs.iter().rev() {
+                    // Between the items of a Fill
+                    if matches!(doc, Doc::Fill(_)) && !first {
+                        stack.push((flat, &Doc::Line));
+                    }
+                    stack.push((flat, d));
+                    first = false;
+                }
+            }
+            Doc::Nest(_, doc) | Doc::Align(doc) => stack.push((flat, doc)),
+            Doc::Group(doc) => stack.push((true, doc)),
+        }
+    }
+}
+
+struct Builder {
+    tokens: Vec<(Token, String, Vec<Trivia>)>,
+    pos: usize,
+    precedence: HashMap<char, i8>,
+}
+
+impl Builder {
+    fn peek(&self) -> &Token {
+        &self.tokens[self.pos.min(self.tokens.len() - 1)].0
+    }
+
+    fn peek_at(&self, ahead: usize) -> &Token {
+        &self.tokens[(self.pos + ahead).min(self.tokens.len() - 1)].0
+    }
+
+    // Whether the source had a line break in front of the next token
+    fn newline_before(&self) -> bool {
+        self.tokens[self.pos].2.contains(&Trivia::Newline)
+    }
+
+    // Comments in front of token `i` that are on lines of their own. Those on the line of the
+    // previous token belong to it
+    fn own_line_trivia(&self, i: usize) -> &[Trivia] {
+        let trivia = &self.tokens[i].2;
+        if i == 0 {
+            return trivia;
+        }
+        let first_newline = trivia.iter().position(|t| *t == Trivia::Newline);
+        &trivia[first_newline.unwrap_or(trivia.len())..]
+    }
+
+    // Consume the next token, with its comments
+    fn token(&mut self) -> Doc {
+        let i = self.pos;
+        self.pos += 1;
+        let mut docs: Vec<Doc> = self
+            .own_line_trivia(i)
+            .iter()
+            .filter_map(|t| match t {
+                Trivia::Comment(c) => Some(Doc::Comment {
+                    text: c.clone(),
+                    own_line: true,
+                }),
+                Trivia::Newline => None,
+            })
+            .collect();
+        docs.push(Doc::Text(self.tokens[i].1.clone()));
+        if let Some((_, _, next)) = self.tokens.get(i + 1) {
+            docs.extend(
+                next.iter()
+                    .take_while(|t| **t != Trivia::Newline)
+                    .filter_map(|t| match t {
+                        Trivia::Comment(c) => Some(Doc::Comment {
+                            text: c.clone(),
+                            own_line: false,
+                        }),
+                        Trivia::Newline => None,
+                    }),
+            );
+        }
+        self.tokens[i].2.clear();
+        Doc::Concat(docs)
+    }
+
+    // Consume a token that must be `expected`
+    fn expect(&mut self, expected: Token) -> Result<Doc, String> {
+        if std::mem::discriminant(self.peek()) != std::mem::discriminant(&expected) {
+            return Err(format!("Expected {:?}, got {:?}", expected, self.peek()));
+        }
+        Ok(self.token())
+    }
+
+    // The comments on lines of their own in front of the next statement, which are printed
+    // above it, keeping blank lines between them. `separate` starts with a blank line if the
+    // source had one
+    fn leading(&mut self, separate: bool) -> Doc {
+        let trivia = self.own_line_trivia(self.pos).to_vec();
+        let mut docs = Vec::new();
+        // Line breaks since the last statement or comment, which is one for a line of its own
+        let mut newlines = if self.pos == 0 { 1 } else { 0 };
+        let mut first = true;
+        for t in &trivia {
+            match t {
+                Trivia::Newline => newlines += 1,
+                Trivia::Comment(c) => {
+                    if newlines > 1 && (separate || !first) {
+                        docs.push(Doc::HardLine);
+                    }
+                    docs.push(text(c));
+                    docs.push(Doc::HardLine);
+                    newlines = 0;
+                    first = false;
+                }
+            }
+        }
+        if newlines > 1 && (separate || !first) {
+            docs.push(Doc::HardLine);
+        }
+        // Trailing comments of the previous token stay
+        let keep = self.tokens[self.pos].2.len() - trivia.len();
+        self.tokens[self.pos].2.truncate(keep);
+        Doc::Concat(docs)
+    }
+
+    fn program(&mut self) -> Result<Doc, String> {
+        let mut docs = Vec::new();
+        while !matches!(self.peek(), Token::Eof) {
+            if !docs.is_empty() {
+                docs.push(Doc::HardLine);
+            }
+            let separate = !docs.is_empty();
+            docs.push(self.leading(separate));
+            docs.push(self.item()?);
+        }
+        // Comments after the last item
+        let comments = self
+            .own_line_trivia(self.pos)
+            .iter()
+            .any(|t| matches!(t, Trivia::Comment(_)));
+        if comments && !docs.is_empty() {
+            docs.push(Doc::HardLine);
+        }
+        let separate = !docs.is_empty();
+        docs.push(self.leading(separate));
+        docs.push(self.token());
+        Ok(Doc::Concat(docs))
+    }
+
+    fn item(&mut self) -> Result<Doc, String> {
+        let doc = match self.peek() {
+            Token::Def => self.definition()?,
+            Token::Extern => self.external()?,
+            Token::Pub => {
+                let public = self.token();
+                let item = match self.peek() {
+                    Token::Def => self.definition()?,
+                    Token::Identifier(name) if name == "const" => self.constant()?,
+                    _ => self.external()?,
+                };
+                Doc::Concat(vec![public, text(" "), item])
+            }
+            Token::Import => {
+                let import = self.token();
+                Doc::Concat(vec![import, text(" "), self.token()])
+            }
+            Token::Identifier(name) if name == "test" && matches!(self.peek_at(1), Token::Str(_)) => {
+                return self.test();
+            }
+            Token::Identifier(name) if name == "const" && matches!(self.peek_at(1), Token::Identifier(_)) => {
+                self.constant()?
+            }
+            _ => self.expression()?.0,
+        };
+        Ok(Doc::Concat(vec![doc, text(";")]))
+    }
+
+    // def proto body, with the body on the same line if it fits there and the source didn't
+    // break the line
+    fn definition(&mut self) -> Result<Doc, String> {
+        let def = self.token();
+        let proto = self.prototype()?;
+        let line = if self.newline_before() {
+            Doc::HardLine
+        } else {
+            Doc::Line
+        };
+        let body = self.expression()?.0;
+        Ok(group(Doc::Concat(vec![
+            def,
+            text(" "),
+            proto,
+            nest(Doc::Concat(vec![line, body])),
+        ])))
+    }
+
+    // const name = value, with the value on the next line if it doesn't fit
+    fn constant(&mut self) -> Result<Doc, String> {
+        let keyword = self.token();
+        let name = self.token();
+        let assign = self.expect(Token::Assign('='))?;
+        let value = self.expression()?.0;
+        Ok(group(Doc::Concat(vec![
+            keyword,
+            text(" "),
+            name,
+            text(" "),
+            assign,
+            nest(Doc::Concat(vec![Doc::Line, value])),
+        ])))
+    }
+
+    fn external(&mut self) -> Result<Doc, String> {
+        let mut docs = vec![self.token(), text(" ")];
+        if matches!(self.peek(), Token::Str(_)) {
+            docs.push(self.token());
+            docs.push(text(" "));
+        }
+        docs.push(self.prototype()?);
+        Ok(Doc::Concat(docs))
+    }
+
+    fn prototype(&mut self) -> Result<Doc, String> {
+        let mut docs = vec![self.token()];
+        if matches!(self.peek(), Token::Number(_)) {
+            docs.push(text(" "));
+            docs.push(self.token());
+            docs.push(text(" "));
+        }
+        docs.push(self.expect(Token::LParen('('))?);
+        let mut first = true;
+        while let Token::Identifier(_) = self.peek() {
+            if !first {
+                docs.push(text(" "));
+            }
+            first = false;
+            docs.push(self.token());
+            if matches!(self.peek(), Token::Colon(_)) {
+                docs.push(self.token());
+                docs.push(text(" "));
+                docs.push(self.token());
+            }
+        }
+        docs.push(self.expect(Token::RParen(')'))?);
+        if matches!(self.peek(), Token::Arrow) {
+            docs.push(text(" "));
+            docs.push(self.token());
+            docs.push(text(" "));
+            docs.push(self.token());
+        }
+        Ok(Doc::Concat(docs))
+    }
+
+    fn test(&mut self) -> Result<Doc, String> {
+        let mut header = vec![self.token(), text(" "), self.token(), text(" ")];
+        header.push(self.expect(Token::LBrace('{'))?);
+        let mut body = Vec::new();
+        while !matches!(self.peek(), Token::RBrace(_) | Token::Eof) {
+            body.push(Doc::HardLine);
+            let separate = body.len() > 1;
+            body.push(self.leading(separate));
+            body.push(self.expression()?.0);
+        }
+        header.push(nest(Doc::Concat(body)));
+        header.push(Doc::HardLine);
+        header.push(self.expect(Token::RBrace('}'))?);
+        Ok(Doc::Concat(header))
+    }
+
+    // The precedence of the next token as a binary operator
+    fn binary_precedence(&self) -> Option<i8> {
+        self.peek().operator_char()
+            .and_then(|c| self.precedence.get(&c).copied())
+            .filter(|p| *p >= 0)
+    }
+
+    // An expression, and whether it is a lone if, which `else` chains onto
+    fn expression(&mut self) -> Result<(Doc, bool), String> {
+        let (first, is_if) = self.unary()?;
+        let mut operands = vec![first];
+        let mut operators = Vec::new();
+        while let Some(precedence) = self.binary_precedence() {
+            let broken = self.newline_before();
+            let op = self.token();
+            let broken = broken || self.newline_before();
+            operators.push((op, precedence, broken));
+            operands.push(self.unary()?.0);
+        }
+        if operators.is_empty() {
+            return Ok((operands.pop().unwrap(), is_if));
+        }
+        Ok((chain(operands, operators), false))
+    }
+
+    fn unary(&mut self) -> Result<(Doc, bool), String> {
+        if self.peek().operator_char().is_some() {
+            let op = self.token();
+            let operand = self.unary()?.0;
+            return Ok((Doc::Concat(vec![op, operand]), false));
+        }
+        self.primary()
+    }
+
+    fn primary(&mut self) -> Result<(Doc, bool), String> {
+        let doc = match self.peek() {
+            Token::LParen(_) => {
+                let open = self.token();
+                let inner = self.expression()?.0;
+                let close = self.expect(Token::RParen(')'))?;
+                Doc::Concat(vec![open, align(inner), close])
+            }
+            Token::Number(_) => self.token(),
+            Token::Identifier(_) if matches!(self.peek_at(1), Token::LParen(_)) => {
+                let name = self.token();
+                let open = self.token();
+                let args = self.list(Token::RParen(')'), |b| Ok(b.expression()?.0))?;
+                let close = self.expect(Token::RParen(')'))?;
+                Doc::Concat(vec![name, open, align(args), close])
+            }
+            Token::Identifier(_) => self.token(),
+            Token::Var => {
+                let var = self.token();
+                let bindings = self.list(Token::In, |b| {
+                    let name = b.expect(Token::Identifier(String::new()))?;
+                    if !matches!(b.peek(), Token::Assign(_)) {
+                        return Ok(name);
+                    }
+                    let assign = b.token();
+                    let init = b.expression()?.0;
+                    Ok(Doc::Concat(vec![name, text(" "), assign, text(" "), init]))
+                })?;
+                let keyword = self.expect(Token::In)?;
+                let body = self.expression()?.0;
+                Doc::Concat(vec![
+                    var,
+                    text(" "),
+                    align(bindings),
+                    text(" "),
+                    keyword,
+                    Doc::HardLine,
+                    body,
+                ])
+            }
+            Token::Backslash(_) => {
+                let mut docs = vec![self.token()];
+                let mut first = true;
+                while let Token::Identifier(_) = self.peek() {
+                    if !first {
+                        docs.push(text(" "));
+                    }
+                    first = false;
+                    docs.push(self.token());
+                }
+                docs.push(text(" "));
+                docs.push(self.expect(Token::Arrow)?);
+                docs.push(text(" "));
+                docs.push(self.expression()?.0);
+                Doc::Concat(docs)
+            }
+            Token::If => {
+                let mut docs = vec![self.token(), text(" ")];
+                docs.push(self.expression()?.0);
+                docs.push(text(" "));
+                docs.push(self.expect(Token::Then)?);
+                docs.push(nest(Doc::Concat(vec![Doc::HardLine, self.expression()?.0])));
+                docs.push(Doc::HardLine);
+                docs.push(self.expect(Token::Else)?);
+                let (els, is_if) = self.expression()?;
+                if is_if {
+                    docs.push(text(" "));
+                    docs.push(els);
+                } else {
+                    docs.push(nest(Doc::Concat(vec![Doc::HardLine, els])));
+                }
+                return Ok((Doc::Concat(docs), true));
+            }
+            Token::For => {
+                let mut docs = vec![self.token(), text(" ")];
+                docs.push(self.expect(Token::Identifier(String::new()))?);
+                docs.push(text(" "));
+                docs.push(self.expect(Token::Assign('='))?);
+                docs.push(text(" "));
+                docs.push(self.expression()?.0);
+                docs.push(self.expect(Token::Comma(','))?);
+                docs.push(text(" "));
+                docs.push(self.expression()?.0);
+                if matches!(self.peek(), Token::Comma(_)) {
+                    docs.push(self.token());
+                    docs.push(text(" "));
+                    docs.push(self.expression()?.0);
+                }
+                docs.push(text(" "));
+                docs.push(self.expect(Token::In)?);
+                docs.push(nest(Doc::Concat(vec![Doc::HardLine, self.expression()?.0])));
+                Doc::Concat(docs)
+            }
+            tok => return Err(format!("Unexpected {:?}", tok)),
+        };
+        Ok((doc, false))
+    }
+
+    // Comma separated items up to `end`, wrapped as they fit. A trailing comma is dropped, but
+    // not its comments
+    fn list(
+        &mut self,
+        end: Token,
+        mut item: impl FnMut(&mut Self) -> Result<Doc, String>,
+    ) -> Result<Doc, String> {
+        let mut items = Vec::new();
+        while std::mem::discriminant(self.peek()) != std::mem::discriminant(&end) {
+            let mut doc = item(self)?;
+            if matches!(self.peek(), Token::Comma(_)) {
+                let comma = self.token();
+                doc = if std::mem::discriminant(self.peek()) == std::mem::discriminant(&end) {
+                    match comma {
+                        Doc::Concat(mut docs) => {
+                            docs.retain(|d| !matches!(d, Doc::Text(_)));
+                            Doc::Concat(vec![doc, Doc::Concat(docs)])
+                        }
+                        comma => comma,
+                    }
+                } else {
+                    Doc::Concat(vec![doc, comma])
+                };
+            } else {
+                items.push(doc);
+                break;
+            }
+            items.push(doc);
+        }
+        Ok(Doc::Fill(items))
+    }
+}
+
+// Lay out `operands` joined by `operators`, breaking after the loosest operators first. Each
+// operator comes with its precedence and whether the source broke the line at it
+fn chain(mut operands: Vec<Doc>, operators: Vec<(Doc, i8, bool)>) -> Doc {
+    if operators.is_empty() {
+        return operands.pop().unwrap();
+    }
+    let loosest = operators.iter().map(|(_, p, _)| *p).min().unwrap();
+    let broken = operators.iter().any(|(_, p, b)| *p == loosest && *b);
+
+    let mut operands = operands.into_iter();
+    let mut parts = Vec::new();
+    let mut part = (vec![operands.next().unwrap()], Vec::new());
+    for ((op, precedence, b), operand) in operators.into_iter().zip(operands) {
+        if precedence == loosest {
+            parts.push((std::mem::replace(&mut part, (vec![operand], Vec::new())), op));
+        } else {
+            part.0.push(operand);
+            part.1.push((op, precedence, b));
+        }
+    }
+
+    let mut docs = Vec::new();
+    for ((operands, operators), op) in parts {
+        docs.push(chain(operands, operators));
+        docs.push(text(" "));
+        docs.push(op);
+        docs.push(if broken { Doc::HardLine } else { Doc::Line });
+    }
+    docs.push(chain(part.0, part.1));
+    group(align(Doc::Concat(docs)))
+}
@@ -0,0 +1,242 @@
+use crate::ast::{Expr, Function};
+use crate::lexer::Token;
+
+// Random programs for testing the backends and the passes over the AST, see ProgramGenerator
+
+// xorshift64*, so a seed always generates the same program
+pub struct Rng(u64);
+
+impl Rng {
+    pub fn new(seed: u64) -> Self {
+        // The state must not be zero
+        Rng((seed ^ 0x9e37_79b9_7f4a_7c15).max(1))
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+
+    // A number in 0..n
+    pub fn below(&mut self, n: usize) -> usize {
+        (self.next_u64() % n as u64) as usize
+    }
+
+    // True with a chance of one in n
+    pub fn one_in(&mut self, n: usize) -> bool {
+        self.below(n) == 0
+    }
+}
+
+// Generates random well-formed programs: every name is defined before it is used, calls pass
+// as many arguments as the callee takes, and every program terminates. Functions only call
+// functions defined before them, loops count a variable nothing else assigns up to a small
+// bound, and the number of calls and loops in a function is limited, so running a program
+// stays cheap
+pub struct ProgramGenerator {
+    rng: Rng,
+    functions: Vec<Function>,
+    // Functions the code being generated can call, with their arity
+    callable: Vec<(String, usize)>,
+    // Variables in scope, and the ones of them that may be assigned
+    vars: Vec<String>,
+    assignable: Vec<String>,
+    user_binary: bool,
+    user_unary: bool,
+    calls: usize,
+    loops: usize,
+    names: usize,
+}
+
+const MAX_DEPTH: usize = 4;
+const MAX_CALLS: usize = 2;
+const MAX_LOOPS: usize = 2;
+
+impl ProgramGenerator {
+    pub fn new(seed: u64) -> Self {
+        ProgramGenerator {
+            rng: Rng::new(seed),
+            functions: Vec::new(),
+            callable: Vec::new(),
+            vars: Vec::new(),
+            assignable: Vec::new(),
+            user_binary: false,
+            user_unary: false,
+            calls: 0,
+            loops: 0,
+            names: 0,
+        }
+    }
+
+    // A program of externs, user-defined operators, a few functions and a top-level expression
+    pub fn generate(mut self) -> Vec<Function> {
+        self.functions.push(function("printd", &["x"], Expr::None));
+        self.callable.push((String::from("printd"), 1));
+
+        if self.rng.one_in(2) {
+            let body = self.body(&["a", "b"], false);
+            let mut f = function("binary|", &["a", "b"], body);
+            f.is_operator = true;
+            f.precedence = Some(5.0);
+            self.functions.push(f);
+            self.user_binary = true;
+        }
+        if self.rng.one_in(2) {
+            let body = self.body(&["v"], false);
+            let mut f = function("unary!", &["v"], body);
+            f.is_operator = true;
+            self.functions.push(f);
+            self.user_unary = true;
+        }
+
+        for i in 0..1 + self.rng.below(3) {
+            let name = format!("f{}", i);
+            let params: Vec<String> = (0..self.rng.below(4)).map(|p| format!("p{}", p)).collect();
+            let params: Vec<&str> = params.iter().map(|p| p.as_str()).collect();
+            let body = self.body(&params, true);
+            self.functions.push(function(&name, &params, body));
+            self.callable.push((name, params.len()));
+        }
+
+        let body = self.body(&[], true);
+        self.functions.push(function("_top_level_expr", &[], body));
+        self.functions
+    }
+
+    // The body of a function taking `params`. Operators don't call anything, since they are
+    // defined before the functions and used by them
+    fn body(&mut self, params: &[&str], calls: bool) -> Expr {
+        self.vars = params.iter().map(|p| p.to_string()).collect();
+        self.assignable = self.vars.clone();
+        self.calls = if calls { 0 } else { MAX_CALLS };
+        self.loops = 0;
+        self.expr(MAX_DEPTH)
+    }
+
+    fn fresh(&mut self, prefix: &str) -> String {
+        self.names += 1;
+        format!("{}{}", prefix, self.names)
+    }
+
+    fn expr(&mut self, depth: usize) -> Expr {
+        if depth == 0 {
+            return self.leaf();
+        }
+        match self.rng.below(12) {
+            0 | 1 => self.leaf(),
+            2..=4 => {
+                let op = match self.rng.below(if self.user_binary { 7 } else { 6 }) {
+                    0 => Token::Plus('+'),
+                    1 => Token::Minus('-'),
+                    2 => Token::Star('*'),
+                    3 => Token::Slash('/'),
+                    4 => Token::Less('<'),
+                    5 => Token::Greater('>'),
+                    _ => Token::Pipe('|'),
+                };
+                Expr::BinOp {
+                    left: Box::new(self.expr(depth - 1)),
+                    op,
+                    right: Box::new(self.expr(depth - 1)),
+                }
+            }
+            5 if !self.assignable.is_empty() => {
+                let name = self.assignable[self.rng.below(self.assignable.len())].clone();
+                Expr::BinOp {
+                    left: Box::new(Expr::Variable(name)),
+                    op: Token::Assign('='),
+                    right: Box::new(self.expr(depth - 1)),
+                }
+            }
+            6 if self.calls < MAX_CALLS => {
+                self.calls += 1;
+                let (identifier, arity) = self.callable[self.rng.below(self.callable.len())].clone();
+                Expr::Call {
+                    identifier,
+                    args: (0..arity).map(|_| self.expr(depth - 1)).collect(),
+                }
+            }
+            7 => Expr::If {
+                condition: Box::new(self.expr(depth - 1)),
+                then: Box::new(self.expr(depth - 1)),
+                els: Box::new(self.expr(depth - 1)),
+            },
+            8 if self.loops < MAX_LOOPS => {
+                self.loops += 1;
+                let ident = self.fresh("i");
+                let start = self.rng.below(3) as f64;
+                let end = Expr::BinOp {
+                    left: Box::new(Expr::Variable(ident.clone())),
+                    op: Token::Less('<'),
+                    right: Box::new(Expr::Number(start + 1.0 + self.rng.below(4) as f64)),
+                };
+                let step = self.rng.one_in(2).then(|| Box::new(Expr::Number(1.0)));
+                self.vars.push(ident.clone());
+                let body = self.expr(depth - 1);
+                self.vars.pop();
+                Expr::For {
+                    ident,
+                    start: Box::new(Expr::Number(start)),
+                    end: Box::new(end),
+                    step,
+                    body: Box::new(body),
+                }
+            }
+            9 => {
+                let mut varnames = Vec::new();
+                for _ in 0..1 + self.rng.below(2) {
+                    let init = (!self.rng.one_in(4)).then(|| self.expr(depth - 1));
+                    let name = self.fresh("v");
+                    self.vars.push(name.clone());
+                    self.assignable.push(name.clone());
+                    varnames.push((name, init));
+                }
+                let body = self.expr(depth - 1);
+                self.vars.truncate(self.vars.len() - varnames.len());
+                self.assignable.truncate(self.assignable.len() - varnames.len());
+                Expr::Var {
+                    varnames,
+                    body: Box::new(body),
+                }
+            }
+            10 if self.user_unary => Expr::Unary {
+                op: '!',
+                left: Box::new(self.expr(depth - 1)),
+            },
+            _ => self.leaf(),
+        }
+    }
+
+    fn leaf(&mut self) -> Expr {
+        if !self.vars.is_empty() && self.rng.one_in(2) {
+            return Expr::Variable(self.vars[self.rng.below(self.vars.len())].clone());
+        }
+        let value = match self.rng.below(5) {
+            0 => 0.0,
+            1 => 1.0,
+            2 => -(self.rng.below(10) as f64),
+            3 => self.rng.below(100) as f64 / 8.0,
+            _ => self.rng.below(10) as f64,
+        };
+        Expr::Number(value)
+    }
+}
+
+fn function(name: &str, args: &[&str], body: Expr) -> Function {
+    Function {
+        name: name.to_string(),
+        args: args.iter().map(|a| a.to_string()).collect(),
+        body,
+        is_operator: false,
+        precedence: None,
+        module: None,
+        is_pub: false,
+        library: None,
+        signature: None,
+        test: None,
+        is_const: false,
+        from_prelude: false,
+    }
+}
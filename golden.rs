@@ -0,0 +1,414 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+#[cfg(feature = "llvm")]
+use inkwell::context::Context;
+
+use crate::ast::Function;
+use crate::bytecode::{self, Program};
+#[cfg(feature = "llvm")]
+use crate::engine::Engine;
+use crate::externs::{self, FfiRegistry, OutputSink};
+use crate::fold;
+use crate::interp::Interpreter;
+use crate::lexer::LexerContext;
+use crate::modules::ModuleLoader;
+use crate::parser::ParserContext;
+use crate::prelude;
+use crate::resolver;
+use crate::vm::Vm;
+
+// Golden tests for .kls programs. A program states what running it should produce in comments:
+//
+//     # EXPECT: 55             the value of the last top-level expression
+//     # OUTPUT: 1              a line the program prints; every line must be listed, in order
+//     # ERROR: Unknown extern  compiling fails with a message containing this text
+//     # LINK: libkernels       skip unless this library was loaded with --link-lib
+//
+// Failed assert and assert_eq calls while the program runs also fail it. Each test "name" { }
+// block is run afterwards as a test of its own. Files without annotations or test blocks, such
+// as modules that are only imported, are skipped
+#[derive(Default)]
+pub struct Expectations {
+    pub result: Option<String>,
+    pub output: Vec<String>,
+    pub error: Option<String>,
+    pub link: Vec<String>,
+}
+
+pub enum Outcome {
+    Pass,
+    Skip(String),
+    Fail(String),
+}
+
+// Read the annotations of a program, or None if it has none
+pub fn parse_expectations(source: &str) -> Option<Expectations> {
+    let mut expected = Expectations::default();
+    let mut annotated = false;
+
+    for line in source.lines() {
+        let comment = match line.trim_start().strip_prefix('#') {
+            Some(c) => c.trim_start(),
+            None => continue,
+        };
+        // Strip exactly one space after the colon, so OUTPUT lines keep their indentation
+        let value = |rest: &str| rest.strip_prefix(' ').unwrap_or(rest).to_string();
+
+        if let Some(rest) = comment.strip_prefix("EXPECT:") {
+            expected.result = Some(value(rest).trim().to_string());
+        } else if let Some(rest) = comment.strip_prefix("OUTPUT:") {
+            expected.output.push(value(rest));
+        } else if let Some(rest) = comment.strip_prefix("ERROR:") {
+            expected.error = Some(value(rest).trim().to_string());
+        } else if let Some(rest) = comment.strip_prefix("LINK:") {
+            expected.link.push(value(rest).trim().to_string());
+        } else {
+            continue;
+        }
+        annotated = true;
+    }
+
+    annotated.then_some(expected)
+}
+
+// How programs are run
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Backend {
+    #[cfg(feature = "llvm")]
+    Jit,
+    Interp,
+    Vm,
+}
+
+// The JIT when it is built in
+impl Default for Backend {
+    #[cfg(feature = "llvm")]
+    fn default() -> Self {
+        Backend::Jit
+    }
+
+    #[cfg(not(feature = "llvm"))]
+    fn default() -> Self {
+        Backend::Interp
+    }
+}
+
+// What running a program produced
+struct Run {
+    result: Result<f64, String>,
+    output: String,
+    assert_failures: Vec<String>,
+    // Outcomes of the program's test blocks, by test name
+    tests: Vec<(String, Outcome)>,
+}
+
+// Run one program with a fresh environment and check it against its annotations, along with
+// each of its test blocks. Results are labelled with the test name, or None for the program
+// itself
+pub fn run_file(
+    path: &Path,
+    backend: Backend,
+    libraries: &[PathBuf],
+    search_paths: &[PathBuf],
+) -> Vec<(Option<String>, Outcome)> {
+    let source = match fs::read_to_string(path) {
+        Ok(s) => s,
+        Err(e) => {
+            let error = format!("Failed to read {}: {}", path.display(), e);
+            return vec![(None, Outcome::Fail(error))];
+        }
+    };
+    let expected = parse_expectations(&source);
+
+    let mut registry = FfiRegistry::new();
+    for lib in libraries {
+        if let Err(e) = registry.load_library(lib) {
+            return vec![(None, Outcome::Fail(e))];
+        }
+    }
+    let loaded = registry.library_names();
+    if let Some(missing) = expected
+        .iter()
+        .flat_map(|e| e.link.iter())
+        .find(|l| !loaded.contains(l))
+    {
+        return vec![(None, Outcome::Skip(format!("needs --link-lib for {}", missing)))];
+    }
+
+    externs::take_assert_failures();
+    let run = match backend {
+        #[cfg(feature = "llvm")]
+        Backend::Jit => run_jit(path, registry, search_paths),
+        Backend::Interp => run_interp(path, &source, registry, search_paths),
+        Backend::Vm => run_vm(path, &source, registry, search_paths),
+    };
+
+    let mut results = Vec::new();
+    match &expected {
+        Some(expected) => {
+            let outcome = check(expected, &run.result, &run.output, &run.assert_failures);
+            results.push((None, outcome));
+        }
+        // Without annotations only errors and failed assertions are checked
+        None if run.result.is_err() || !run.assert_failures.is_empty() => {
+            let mut failures = run.assert_failures;
+            if let Err(e) = &run.result {
+                failures.insert(0, format!("unexpected error\n    {}", e));
+            }
+            results.push((None, Outcome::Fail(failures.join("\n"))));
+        }
+        None if run.tests.is_empty() => {
+            results.push((None, Outcome::Skip(String::from("no annotations or tests"))));
+        }
+        None => {}
+    }
+
+    results.extend(
+        run.tests
+            .into_iter()
+            .map(|(name, outcome)| (Some(name), outcome)),
+    );
+    results
+}
+
+// A test passes if calling it succeeds without failing any assertion
+fn test_outcome(result: Result<f64, String>) -> Outcome {
+    match (result, externs::take_assert_failures()) {
+        (Err(e), _) => Outcome::Fail(e),
+        (Ok(_), failures) if failures.is_empty() => Outcome::Pass,
+        (Ok(_), failures) => Outcome::Fail(failures.join("\n")),
+    }
+}
+
+#[cfg(feature = "llvm")]
+fn run_jit(path: &Path, registry: FfiRegistry, search_paths: &[PathBuf]) -> Run {
+    let context = Context::create();
+    let mut engine = match Engine::new(&context) {
+        Ok(e) => e,
+        Err(e) => return Run::failed(e),
+    };
+    engine.registry = registry;
+    engine.search_paths = search_paths.to_vec();
+
+    engine.set_output(OutputSink::Buffer(Vec::new()));
+    let result = engine.eval_file(path);
+    let output = engine.take_output().contents().unwrap_or_default();
+    let assert_failures = externs::take_assert_failures();
+
+    let mut tests = Vec::new();
+    for (name, function) in engine.tests() {
+        engine.set_output(OutputSink::Buffer(Vec::new()));
+        tests.push((name, test_outcome(engine.call(&function, &[]))));
+    }
+    Run {
+        result,
+        output,
+        assert_failures,
+        tests,
+    }
+}
+
+// Parse a program read from `path` after the prelude, the way the CLI does
+pub fn parse_program(
+    path: &Path,
+    source: &str,
+    search_paths: &[PathBuf],
+) -> Result<Vec<Function>, String> {
+    let mut lexer = LexerContext::new();
+    lexer.file = Some(path.display().to_string());
+    lexer.lex(source)?;
+
+    let mut parser = ParserContext::new();
+    prelude::load(&mut parser)?;
+    parser.loader = Some(ModuleLoader::new(search_paths.to_vec(), Some(path)));
+    parser.parse(&mut lexer)?;
+    Ok(parser.functions)
+}
+
+// Parse a program and resolve and fold it, as the CLI does before running it
+fn compile_program(
+    path: &Path,
+    source: &str,
+    search_paths: &[PathBuf],
+) -> Result<Vec<Function>, String> {
+    let mut functions = parse_program(path, source, search_paths)?;
+    resolver::resolve(&functions)?;
+    fold::fold_program(&mut functions)?;
+    Ok(functions)
+}
+
+fn run_interp(path: &Path, source: &str, registry: FfiRegistry, search_paths: &[PathBuf]) -> Run {
+    let functions = match compile_program(path, source, search_paths) {
+        Ok(f) => f,
+        Err(e) => return Run::failed(e),
+    };
+    let mut interp = match Interpreter::new(&functions, &registry) {
+        Ok(i) => i,
+        Err(e) => return Run::failed(e),
+    };
+
+    let previous = externs::set_output(OutputSink::Buffer(Vec::new()));
+    let result = interp.run();
+    let output = externs::set_output(OutputSink::Buffer(Vec::new()))
+        .contents()
+        .unwrap_or_default();
+    let assert_failures = externs::take_assert_failures();
+
+    let mut tests = Vec::new();
+    for f in functions.iter().filter(|f| f.test.is_some()) {
+        let outcome = test_outcome(interp.call(&f.name, &[]));
+        tests.push((f.test.clone().unwrap(), outcome));
+    }
+    externs::set_output(previous);
+    Run {
+        result,
+        output,
+        assert_failures,
+        tests,
+    }
+}
+
+// Programs run on the VM after a round trip through the .kbc format, so the tests check the
+// format as well
+fn run_vm(path: &Path, source: &str, registry: FfiRegistry, search_paths: &[PathBuf]) -> Run {
+    let program = compile_program(path, source, search_paths)
+        .and_then(|functions| bytecode::compile(&functions))
+        .and_then(|program| Program::from_bytes(&program.to_bytes()));
+    let program = match program {
+        Ok(p) => p,
+        Err(e) => return Run::failed(e),
+    };
+    let mut vm = match Vm::new(&program, &registry) {
+        Ok(vm) => vm,
+        Err(e) => return Run::failed(e),
+    };
+
+    let previous = externs::set_output(OutputSink::Buffer(Vec::new()));
+    let result = vm.run();
+    let output = externs::set_output(OutputSink::Buffer(Vec::new()))
+        .contents()
+        .unwrap_or_default();
+    let assert_failures = externs::take_assert_failures();
+
+    let mut tests = Vec::new();
+    for (name, chunk) in program.tests() {
+        tests.push((name, test_outcome(vm.call(chunk, &[]))));
+    }
+    externs::set_output(previous);
+    Run {
+        result,
+        output,
+        assert_failures,
+        tests,
+    }
+}
+
+impl Run {
+    fn failed(error: String) -> Self {
+        Run {
+            result: Err(error),
+            output: String::new(),
+            assert_failures: Vec::new(),
+            tests: Vec::new(),
+        }
+    }
+}
+
+fn check(
+    expected: &Expectations,
+    result: &Result<f64, String>,
+    output: &str,
+    assert_failures: &[String],
+) -> Outcome {
+    let mut failures = Vec::new();
+    match (result, &expected.error) {
+        (Err(e), Some(error)) if e.contains(error.as_str()) => return Outcome::Pass,
+        (Err(e), Some(error)) => failures.push(format!(
+            "expected an error containing\n    {}\nbut got\n    {}",
+            error, e
+        )),
+        (Err(e), None) => failures.push(format!("unexpected error\n    {}", e)),
+        (Ok(value), Some(error)) => failures.push(format!(
+            "expected an error containing\n    {}\nbut the program returned {}",
+            error, value
+        )),
+        (Ok(_), None) => {}
+    }
+
+    if let (Ok(value), Some(want)) = (result, &expected.result) {
+        let got = value.to_string();
+        if got != *want {
+            failures.push(format!("result differs\n    expected {}\n    got      {}", want, got));
+        }
+    }
+    if result.is_ok() {
+        let got: Vec<&str> = output.lines().collect();
+        let want: Vec<&str> = expected.output.iter().map(|s| s.as_str()).collect();
+        if got != want {
+            failures.push(format!("output differs (-expected +actual)\n{}", diff(&want, &got)));
+        }
+    }
+    failures.extend(assert_failures.iter().cloned());
+
+    if failures.is_empty() {
+        Outcome::Pass
+    } else {
+        Outcome::Fail(failures.join("\n"))
+    }
+}
+
+// Every .kls file under `path` (or `path` itself), sorted
+pub fn collect_files(path: &Path) -> Result<Vec<PathBuf>, String> {
+    if path.is_file() {
+        return Ok(vec![path.to_path_buf()]);
+    }
+
+    let mut files = Vec::new();
+    let entries =
+        fs::read_dir(path).map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+    for entry in entries {
+        let entry = entry.map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+        let p = entry.path();
+        if p.is_dir() {
+            files.extend(collect_files(&p)?);
+        } else if p.extension().is_some_and(|ext| ext == "kls") {
+            files.push(p);
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+// Line diff of two outputs, from their longest common subsequence. Lines only in `want` are
+// marked with -, lines only in `got` with +
+pub fn diff(want: &[&str], got: &[&str]) -> String {
+    let (n, m) = (want.len(), got.len());
+    let mut lcs = vec![vec![0usize; m + 1]; n + 1];
+    for i in (0..n).rev() {
+        for j in (0..m).rev() {
+            lcs[i][j] = if want[i] == got[j] {
+                lcs[i + 1][j + 1] + 1
+            } else {
+                lcs[i + 1][j].max(lcs[i][j + 1])
+            };
+        }
+    }
+
+    let mut out = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    while i < n || j < m {
+        if i < n && j < m && want[i] == got[j] {
+            out.push(format!("  {}", want[i]));
+            i += 1;
+            j += 1;
+        } else if i < n && (j == m || lcs[i + 1][j] >= lcs[i][j + 1]) {
+            out.push(format!("- {}", want[i]));
+            i += 1;
+        } else {
+            out.push(format!("+ {}", got[j]));
+            j += 1;
+        }
+    }
+    out.join("\n")
+}
@@ -0,0 +1,453 @@
+use std::collections::HashMap;
+
+use crate::ast::{Expr, Function};
+use crate::externs::{self, FfiRegistry, NativeFunction};
+use crate::lexer::Token;
+use crate::resolver;
+
+// A tree-walking interpreter for parsed programs, with the same semantics as the LLVM backend:
+// every value is a double, only the last top-level expression runs, and a name refers to its
+// definition wherever that is. Externs are called through the FFI registry, so nothing here
+// needs LLVM
+
+// Function values are NaN-boxed: a quiet NaN with this tag in the high bits and the index of
+// the closure in the low bits. Compiled code passes pointers to closure records instead, so a
+// function value only means something to the backend that created it
+const CLOSURE_TAG: u64 = 0x7ffc_0000_0000_0000;
+const CLOSURE_TAG_MASK: u64 = 0xffff_0000_0000_0000;
+
+// Nested calls allowed, since each one recurses on the Rust stack. This stays within the 8MB
+// stack of the main thread, where compiled code and the VM would go much deeper
+const MAX_DEPTH: usize = 1000;
+
+enum Closure<'a> {
+    Function(usize),
+    Lambda {
+        params: &'a [String],
+        body: &'a Expr,
+        captures: Vec<(String, f64)>,
+    },
+}
+
+// Variables of one function activation. Like the allocas of compiled code, each declaration
+// gets a new slot, and a name maps to the slot currently in scope
+struct Frame {
+    slots: Vec<f64>,
+    vars: HashMap<String, usize>,
+}
+
+impl Frame {
+    fn new() -> Self {
+        Frame {
+            slots: Vec::new(),
+            vars: HashMap::new(),
+        }
+    }
+
+    fn declare(&mut self, name: &str, value: f64) -> Option<usize> {
+        self.slots.push(value);
+        self.vars.insert(name.to_string(), self.slots.len() - 1)
+    }
+}
+
+pub struct Interpreter<'a> {
+    functions: &'a [Function],
+    // Index of the last definition of each name, which replaces any before it (see
+    // resolver.rs)
+    last: HashMap<&'a str, usize>,
+    natives: HashMap<&'a str, NativeFunction<'a>>,
+    closures: Vec<Closure<'a>>,
+    function_closures: HashMap<usize, f64>,
+    // Calls being evaluated
+    depth: usize,
+}
+
+impl<'a> Interpreter<'a> {
+    // Resolve every extern of the program against the registry, reporting the same errors
+    // compiling it would
+    pub fn new(functions: &'a [Function], ffi_registry: &FfiRegistry) -> Result<Self, String> {
+        let mut natives = HashMap::new();
+        for f in functions {
+            if matches!(f.body, Expr::None) {
+                natives.insert(f.name.as_str(), ffi_registry.native_function(f)?);
+            }
+        }
+        resolver::resolve(functions)?;
+
+        let mut last = HashMap::new();
+        for (i, f) in functions.iter().enumerate() {
+            if f.name != "_top_level_expr" {
+                last.insert(f.name.as_str(), i);
+            }
+        }
+        Ok(Interpreter {
+            functions,
+            last,
+            natives,
+            closures: Vec::new(),
+            function_closures: HashMap::new(),
+            depth: 0,
+        })
+    }
+
+    // Run the program, returning the value of its last top-level expression (0 if there is none)
+    pub fn run(&mut self) -> Result<f64, String> {
+        let functions = self.functions;
+        match functions.iter().rposition(|f| f.name == "_top_level_expr") {
+            Some(i) => self.eval(&functions[i].body, &mut Frame::new()),
+            None => Ok(0.0),
+        }
+    }
+
+    // Call a function of the program by name
+    pub fn call(&mut self, name: &str, args: &[f64]) -> Result<f64, String> {
+        let index = self
+            .lookup(name)
+            .ok_or_else(|| format!("Unknown function: {}", name))?;
+        self.call_function(index, args)
+    }
+
+    fn lookup(&self, name: &str) -> Option<usize> {
+        self.last.get(name).copied()
+    }
+
+    fn call_function(&mut self, index: usize, args: &[f64]) -> Result<f64, String> {
+        let functions = self.functions;
+        let f = &functions[index];
+        if matches!(f.body, Expr::None) {
+            return match self.natives.get(f.name.as_str()) {
+                Some(native) => native.call(&f.name, args),
+                None => Err(format!("Unknown function: {}", f.name)),
+            };
+        }
+        if f.args.len() != args.len() {
+            return Err(format!(
+                "{} takes {} arguments, but was called with {}",
+                f.name,
+                f.args.len(),
+                args.len()
+            ));
+        }
+
+        let mut frame = Frame::new();
+        for (name, value) in f.args.iter().zip(args) {
+            frame.declare(name, *value);
+        }
+        self.eval_body(&f.body, &mut frame)
+    }
+
+    // Evaluate the body of a function or lambda being called, counting the nested calls
+    fn eval_body(&mut self, body: &'a Expr, frame: &mut Frame) -> Result<f64, String> {
+        if self.depth >= MAX_DEPTH {
+            return Err(format!("Calls are nested more than {} deep", MAX_DEPTH));
+        }
+        self.depth += 1;
+        let result = self.eval(body, frame);
+        self.depth -= 1;
+        result
+    }
+
+    fn function_closure(&mut self, index: usize) -> f64 {
+        if let Some(value) = self.function_closures.get(&index) {
+            return *value;
+        }
+        let value = self.new_closure(Closure::Function(index));
+        self.function_closures.insert(index, value);
+        value
+    }
+
+    fn new_closure(&mut self, closure: Closure<'a>) -> f64 {
+        self.closures.push(closure);
+        f64::from_bits(CLOSURE_TAG | (self.closures.len() - 1) as u64)
+    }
+
+    fn call_closure(&mut self, value: f64, args: &[f64]) -> Result<f64, String> {
+        let bits = value.to_bits();
+        let index = (bits & !CLOSURE_TAG_MASK) as usize;
+        if bits & CLOSURE_TAG_MASK != CLOSURE_TAG || index >= self.closures.len() {
+            return Err(format!("Called value {} is not a function", value));
+        }
+
+        let (params, body, mut frame) = match &self.closures[index] {
+            Closure::Function(i) => return self.call_function(*i, args),
+            Closure::Lambda {
+                params,
+                body,
+                captures,
+            } => {
+                let mut frame = Frame::new();
+                for (name, value) in captures {
+                    frame.declare(name, *value);
+                }
+                (*params, *body, frame)
+            }
+        };
+        if params.len() != args.len() {
+            return Err(format!(
+                "Lambda takes {} arguments, but was called with {}",
+                params.len(),
+                args.len()
+            ));
+        }
+        for (name, value) in params.iter().zip(args) {
+            frame.declare(name, *value);
+        }
+        self.eval_body(body, &mut frame)
+    }
+
+    // Conditions are true when they compare ordered and not equal to 0, so NaN is false
+    fn truthy(value: f64) -> bool {
+        !value.is_nan() && value != 0.0
+    }
+
+    fn eval(&mut self, mut expr: &'a Expr, frame: &mut Frame) -> Result<f64, String> {
+        // Locations only matter to codegen. Skipping them here keeps them from adding to the depth
+        // of the recursion
+        while let Expr::Located { expr: inner, .. } = expr {
+            expr = inner;
+        }
+        match expr {
+            Expr::Number(value) => Ok(*value),
+            Expr::Variable(name) => match frame.vars.get(name) {
+                Some(slot) => Ok(frame.slots[*slot]),
+                None => self.eval_function_value(name),
+            },
+            Expr::BinOp { left, op, right } => self.eval_binop(left, op, right, frame),
+            Expr::Unary { op, left } => self.eval_unary(*op, left, frame),
+            Expr::If {
+                condition,
+                then,
+                els,
+            } => {
+                if Self::truthy(self.eval(condition, frame)?) {
+                    self.eval(then, frame)
+                } else {
+                    self.eval(els, frame)
+                }
+            }
+            Expr::For {
+                ident,
+                start,
+                end,
+                step,
+                body,
+            } => self.eval_for(ident, start, end, step.as_deref(), body, frame),
+            Expr::Var { varnames, body } => self.eval_var(varnames, body, frame),
+            Expr::Call { identifier, args } => self.eval_call(identifier, args, frame),
+            Expr::Lambda { params, body } => Ok(self.eval_lambda(expr, params, body, frame)),
+            Expr::Assert {
+                args,
+                text,
+                location,
+            } => self.eval_assert(args, text, location, frame),
+            Expr::Block(exprs) => {
+                let mut last = 0.0;
+                for expr in exprs {
+                    last = self.eval(expr, frame)?;
+                }
+                Ok(last)
+            }
+            Expr::Located { .. } => unreachable!("Locations are skipped"),
+            Expr::None => Err(String::from("Unhandled expression: None")),
+        }
+    }
+
+    // The arms of eval are kept in methods of their own, so the frames of eval, which recursion
+    // in a program stacks up, stay small
+
+    // Function names used as values evaluate to a closure of that function
+    fn eval_function_value(&mut self, name: &str) -> Result<f64, String> {
+        let index = self
+            .lookup(name)
+            .ok_or_else(|| format!("Unknown variable: {}", name))?;
+        Ok(self.function_closure(index))
+    }
+
+    fn eval_unary(&mut self, op: char, operand: &'a Expr, frame: &mut Frame) -> Result<f64, String> {
+        let operand = self.eval(operand, frame)?;
+        let name = format!("unary{}", op);
+        let index = self
+            .lookup(&name)
+            .ok_or_else(|| format!("Unknown unary operator: {}", name))?;
+        self.call_function(index, &[operand])
+    }
+
+    // Captured variables are copied when the lambda is created
+    fn eval_lambda(
+        &mut self,
+        lambda: &Expr,
+        params: &'a [String],
+        body: &'a Expr,
+        frame: &Frame,
+    ) -> f64 {
+        let captures = lambda
+            .free_variables()
+            .into_iter()
+            .filter_map(|name| {
+                let value = frame.vars.get(&name).map(|slot| frame.slots[*slot])?;
+                Some((name, value))
+            })
+            .collect();
+        self.new_closure(Closure::Lambda {
+            params,
+            body,
+            captures,
+        })
+    }
+
+    fn eval_assert(
+        &mut self,
+        args: &'a [Expr],
+        text: &str,
+        location: &str,
+        frame: &mut Frame,
+    ) -> Result<f64, String> {
+        let mut values = Vec::with_capacity(args.len());
+        for arg in args {
+            values.push(self.eval(arg, frame)?);
+        }
+        Ok(externs::check_assert(
+            &format!("{}: {}", location, text),
+            &values,
+        ))
+    }
+
+    fn eval_binop(
+        &mut self,
+        left: &'a Expr,
+        op: &Token,
+        right: &'a Expr,
+        frame: &mut Frame,
+    ) -> Result<f64, String> {
+        if let (Token::Assign(_), Expr::Variable(name)) = (op, left) {
+            let value = self.eval(right, frame)?;
+            let slot = *frame
+                .vars
+                .get(name)
+                .ok_or_else(|| format!("Unknown variable: {}", name))?;
+            frame.slots[slot] = value;
+            return Ok(value);
+        }
+
+        let lhs = self.eval(left, frame)?;
+        let rhs = self.eval(right, frame)?;
+        let c = match op {
+            Token::Plus(c)
+            | Token::Minus(c)
+            | Token::Star(c)
+            | Token::Slash(c)
+            | Token::Less(c)
+            | Token::Greater(c)
+            | Token::Bang(c)
+            | Token::Pipe(c)
+            | Token::Ampersand(c)
+            | Token::Caret(c)
+            | Token::Percent(c)
+            | Token::Dollar(c)
+            | Token::At(c)
+            | Token::Tilde(c) => *c,
+            _ => return Err(format!("Unknown token type: {:?}", op)),
+        };
+
+        // User-defined operators take precedence over the built-in ones
+        if let Some(index) = self.lookup(&format!("binary{}", c)) {
+            return self.call_function(index, &[lhs, rhs]);
+        }
+        // Comparisons are unordered: true if either side is NaN
+        match op {
+            Token::Plus(_) => Ok(lhs + rhs),
+            Token::Minus(_) => Ok(lhs - rhs),
+            Token::Star(_) => Ok(lhs * rhs),
+            Token::Slash(_) => Ok(lhs / rhs),
+            Token::Less(_) => Ok(if lhs >= rhs { 0.0 } else { 1.0 }),
+            Token::Greater(_) => Ok(if lhs <= rhs { 0.0 } else { 1.0 }),
+            _ => Err(format!("Unknown binary operator: {:?}", op)),
+        }
+    }
+
+    fn eval_for(
+        &mut self,
+        ident: &str,
+        start: &'a Expr,
+        end: &'a Expr,
+        step: Option<&'a Expr>,
+        body: &'a Expr,
+        frame: &mut Frame,
+    ) -> Result<f64, String> {
+        // The end condition is checked before each iteration, so the body may not run at all
+        let start = self.eval(start, frame)?;
+        let old = frame.vars.get(ident).copied();
+        frame.declare(ident, start);
+        let slot = frame.slots.len() - 1;
+
+        while Self::truthy(self.eval(end, frame)?) {
+            self.eval(body, frame)?;
+            let step = match step {
+                Some(s) => self.eval(s, frame)?,
+                None => 1.0,
+            };
+            frame.slots[slot] += step;
+        }
+
+        match old {
+            Some(old) => frame.vars.insert(ident.to_string(), old),
+            None => frame.vars.remove(ident),
+        };
+        Ok(0.0)
+    }
+
+    fn eval_var(
+        &mut self,
+        varnames: &'a [(String, Option<Expr>)],
+        body: &'a Expr,
+        frame: &mut Frame,
+    ) -> Result<f64, String> {
+        // The names are bound in the body only. Shadowed ones are restored in reverse, so a
+        // name declared twice gets back what it had before both
+        let mut old_bindings = Vec::new();
+        for (name, init) in varnames {
+            let value = match init {
+                Some(e) => self.eval(e, frame)?,
+                None => 0.0,
+            };
+            old_bindings.push((name.as_str(), frame.declare(name, value)));
+        }
+
+        let value = self.eval(body, frame)?;
+        for (name, old) in old_bindings.into_iter().rev() {
+            match old {
+                Some(old) => frame.vars.insert(name.to_string(), old),
+                None => frame.vars.remove(name),
+            };
+        }
+        Ok(value)
+    }
+
+    fn eval_call(
+        &mut self,
+        identifier: &str,
+        args: &'a [Expr],
+        frame: &mut Frame,
+    ) -> Result<f64, String> {
+        // A variable in scope shadows any function of the same name
+        let closure = frame.vars.get(identifier).map(|slot| frame.slots[*slot]);
+        let index = match closure {
+            Some(_) => None,
+            None => Some(
+                self.lookup(identifier)
+                    .ok_or_else(|| format!("Unknown function: {}", identifier))?,
+            ),
+        };
+
+        let mut values = Vec::with_capacity(args.len());
+        for arg in args {
+            values.push(self.eval(arg, frame)?);
+        }
+
+        match (index, closure) {
+            (Some(index), _) => self.call_function(index, &values),
+            (None, Some(closure)) => self.call_closure(closure, &values),
+            (None, None) => unreachable!(),
+        }
+    }
+}
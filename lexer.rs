@@ -31,24 +31,129 @@ pub enum Token {
     Tilde(char),
     Binary(char),
     Unary(char),
+    Backslash(char),
+    Arrow,
+    Import,
+    Pub,
+    Str(String),
+    Colon(char),
+    LBrace(char),
+    RBrace(char),
+}
+
+impl Token {
+    // The token a character makes on its own, for operators and punctuation
+    pub fn from_char(c: char) -> Option<Token> {
+        match c {
+            '(' => Some(Token::LParen(c)),
+            ')' => Some(Token::RParen(c)),
+            '+' => Some(Token::Plus(c)),
+            ',' => Some(Token::Comma(c)),
+            '-' => Some(Token::Minus(c)),
+            '/' => Some(Token::Slash(c)),
+            '*' => Some(Token::Star(c)),
+            '>' => Some(Token::Greater(c)),
+            '<' => Some(Token::Less(c)),
+            '=' => Some(Token::Assign(c)),
+            '!' => Some(Token::Bang(c)),
+            '|' => Some(Token::Pipe(c)),
+            '&' => Some(Token::Ampersand(c)),
+            '^' => Some(Token::Caret(c)),
+            '%' => Some(Token::Percent(c)),
+            '$' => Some(Token::Dollar(c)),
+            '@' => Some(Token::At(c)),
+            '~' => Some(Token::Tilde(c)),
+            '\\' => Some(Token::Backslash(c)),
+            ':' => Some(Token::Colon(c)),
+            '{' => Some(Token::LBrace(c)),
+            '}' => Some(Token::RBrace(c)),
+            _ => None,
+        }
+    }
+
+    // The character of a token that can be a unary or binary operator
+    pub fn operator_char(&self) -> Option<char> {
+        match self {
+            Token::Plus(c)
+            | Token::Minus(c)
+            | Token::Star(c)
+            | Token::Slash(c)
+            | Token::Less(c)
+            | Token::Greater(c)
+            | Token::Assign(c)
+            | Token::Bang(c)
+            | Token::Pipe(c)
+            | Token::Ampersand(c)
+            | Token::Caret(c)
+            | Token::Percent(c)
+            | Token::Dollar(c)
+            | Token::At(c)
+            | Token::Tilde(c) => Some(*c),
+            _ => None,
+        }
+    }
+}
+
+// What the parser skips between two tokens. Only the formatter needs it, to keep comments and
+// blank lines
+#[derive(Debug, PartialEq, Clone)]
+pub enum Trivia {
+    Newline,
+    // A # comment, without the line break that ends it
+    Comment(String),
 }
 
 pub struct LexerContext {
     tokens: Vec<Token>,
+    // Byte range of each token in the source
+    spans: Vec<(usize, usize)>,
+    // Trivia in front of each token
+    trivia: Vec<Vec<Trivia>>,
+    source: String,
     position: usize,
+    // Byte range of the text that stopped lexing, when `lex` failed
+    error_span: Option<(usize, usize)>,
+    // File the source was read from, used in locations
+    pub file: Option<String>,
+    // Print every token as it is lexed
+    pub trace: bool,
+}
+
+impl Default for LexerContext {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl LexerContext {
     pub fn new() -> Self {
         LexerContext {
             tokens: Vec::new(),
+            spans: Vec::new(),
+            trivia: Vec::new(),
+            source: String::new(),
             position: 0,
+            error_span: None,
+            file: None,
+            trace: false,
         }
     }
 
-    pub fn lex(&mut self, input: &str) {
+    fn trace_token(&self, tok: &Token) {
+        if self.trace {
+            println!("TOK: {:?}", tok);
+        }
+    }
+
+    // Lex all of `input`. On an error the tokens before it are kept, so that tools can still show
+    // them, and the error is reported with its location
+    pub fn lex(&mut self, input: &str) -> Result<(), String> {
         let mut tokens = Vec::new();
+        let mut spans = Vec::new();
+        let mut trivia = Vec::new();
+        let mut pending = Vec::new();
         let mut cursor = 0;
+        let mut error = None;
 
         while cursor < input.len() {
             let remaining = &input[cursor..];
@@ -60,48 +165,40 @@ impl LexerContext {
 
             // Skip whitespace
             if cchar.is_whitespace() {
+                if cchar == '\n' {
+                    pending.push(Trivia::Newline);
+                }
                 cursor += cchar.len_utf8();
                 continue;
             }
 
             // Skip line comments
             if cchar == '#' {
-                while cursor < input.len() {
-                    let c = input[cursor..].chars().next().unwrap();
-                    cursor += c.len_utf8();
-                    if c == '\n' {
-                        break;
-                    }
-                }
+                let end = remaining.find('\n').map_or(input.len(), |len| cursor + len);
+                pending.push(Trivia::Comment(input[cursor..end].trim_end().to_string()));
+                cursor = end;
+                continue;
+            }
+
+            // Arrow separating lambda parameters from the body, or an extern's parameters from its
+            // return type. The parser splits it back into - and > where an operator goes
+            if remaining.starts_with("->") {
+                self.trace_token(&Token::Arrow);
+                tokens.push(Token::Arrow);
+                spans.push((cursor, cursor + 2));
+                trivia.push(std::mem::take(&mut pending));
+                cursor += 2;
                 continue;
             }
 
             // Single character tokens
-            let token = match cchar {
-                '(' => Some(Token::LParen(cchar)),
-                ')' => Some(Token::RParen(cchar)),
-                '+' => Some(Token::Plus(cchar)),
-                ',' => Some(Token::Comma(cchar)),
-                '-' => Some(Token::Minus(cchar)),
-                '/' => Some(Token::Slash(cchar)),
-                '*' => Some(Token::Star(cchar)),
-                '>' => Some(Token::Greater(cchar)),
-                '<' => Some(Token::Less(cchar)),
-                '=' => Some(Token::Assign(cchar)),
-                '!' => Some(Token::Bang(cchar)),
-                '|' => Some(Token::Pipe(cchar)),
-                '&' => Some(Token::Ampersand(cchar)),
-                '^' => Some(Token::Caret(cchar)),
-                '%' => Some(Token::Percent(cchar)),
-                '$' => Some(Token::Dollar(cchar)),
-                '@' => Some(Token::At(cchar)),
-                '~' => Some(Token::Tilde(cchar)),
-                _ => None,
-            };
+            let token = Token::from_char(cchar);
 
             if let Some(tok) = token {
-                println!("TOK: {:?}", tok);
+                self.trace_token(&tok);
                 tokens.push(tok);
+                spans.push((cursor, cursor + cchar.len_utf8()));
+                trivia.push(std::mem::take(&mut pending));
                 cursor += cchar.len_utf8();
                 continue;
             }
@@ -125,8 +222,29 @@ impl LexerContext {
                 }
 
                 let nval = input[start..cursor].parse::<f64>().unwrap();
-                println!("TOK: {:?}", Token::Number(nval));
+                self.trace_token(&Token::Number(nval));
                 tokens.push(Token::Number(nval));
+                spans.push((start, cursor));
+                trivia.push(std::mem::take(&mut pending));
+                continue;
+            }
+
+            // String literals, used for import paths
+            if cchar == '"' {
+                let start = cursor + 1;
+                let end = match input[start..].find('"') {
+                    Some(len) => start + len,
+                    None => {
+                        error = Some(("Unterminated string literal".to_string(), (cursor, input.len())));
+                        break;
+                    }
+                };
+                let tok = Token::Str(input[start..end].to_string());
+                self.trace_token(&tok);
+                tokens.push(tok);
+                spans.push((cursor, end + 1));
+                trivia.push(std::mem::take(&mut pending));
+                cursor = end + 1;
                 continue;
             }
 
@@ -136,8 +254,11 @@ impl LexerContext {
                 cursor += cchar.len_utf8();
 
                 while cursor < input.len() {
-                    let c = input[cursor..].chars().next().unwrap();
-                    if c.is_alphanumeric() {
+                    let mut rest = input[cursor..].chars();
+                    let c = rest.next().unwrap();
+                    // A dot followed by a letter continues a module-qualified name like math.sqr
+                    let qualified = c == '.' && rest.next().is_some_and(|n| n.is_alphabetic());
+                    if c.is_alphanumeric() || c == '_' || qualified {
                         cursor += c.len_utf8();
                     } else {
                         break;
@@ -154,27 +275,31 @@ impl LexerContext {
                     "then" => Token::Then,
                     "for" => Token::For,
                     "in" => Token::In,
-                    "binary" => {
-                        if cursor >= input.len() {
-                            panic!("Expected a char after unary identifier")
-                        };
-                        cursor += 1;
-                        Token::Binary(input.chars().nth(cursor - 1).unwrap())
-                    }
-                    "unary" => {
-                        if cursor >= input.len() {
-                            panic!("Expected a char after unary identifier")
+                    "import" => Token::Import,
+                    "pub" => Token::Pub,
+                    "binary" | "unary" => {
+                        let Some(op) = input[cursor..].chars().next() else {
+                            let message = format!("Expected an operator character after {}", ident);
+                            error = Some((message, (start, cursor)));
+                            break;
                         };
-                        cursor += 1;
-                        Token::Unary(input.chars().nth(cursor - 1).unwrap())
+                        cursor += op.len_utf8();
+                        match ident {
+                            "binary" => Token::Binary(op),
+                            _ => Token::Unary(op),
+                        }
                     }
                     _ => {
-                        println!("{:?}", ident);
+                        if self.trace {
+                            println!("{:?}", ident);
+                        }
                         Token::Identifier(ident.to_string())
                     }
                 };
-                println!("TOK: {:?}", tok);
+                self.trace_token(&tok);
                 tokens.push(tok);
+                spans.push((start, cursor));
+                trivia.push(std::mem::take(&mut pending));
                 continue;
             }
 
@@ -182,9 +307,82 @@ impl LexerContext {
             cursor += cchar.len_utf8();
         }
 
-        println!("TOK: {:?}", Token::Eof);
+        self.trace_token(&Token::Eof);
         tokens.push(Token::Eof);
+        spans.push((input.len(), input.len()));
+        trivia.push(pending);
         self.tokens = tokens;
+        self.spans = spans;
+        self.trivia = trivia;
+        self.source = input.to_string();
+        self.error_span = error.as_ref().map(|(_, span)| *span);
+        match error {
+            Some((message, (start, _))) => Err(format!("{}: {}", self.location(start), message)),
+            None => Ok(()),
+        }
+    }
+
+    // Byte range of the text that made `lex` fail
+    pub fn error_span(&self) -> Option<(usize, usize)> {
+        self.error_span
+    }
+
+    // Byte offset where the next token starts
+    pub fn offset(&self) -> usize {
+        self.spans
+            .get(self.position)
+            .map_or(self.source.len(), |(start, _)| *start)
+    }
+
+    // Every token with its byte range in the source, ending with Eof
+    pub fn spanned_tokens(&self) -> impl Iterator<Item = (&Token, (usize, usize))> {
+        self.tokens.iter().zip(self.spans.iter().copied())
+    }
+
+    // Every token with its source text and the trivia in front of it, ending with Eof, whose
+    // trivia is what follows the last token
+    pub fn tokens_with_trivia(&self) -> impl Iterator<Item = (&Token, &str, &[Trivia])> {
+        self.tokens
+            .iter()
+            .zip(&self.spans)
+            .zip(&self.trivia)
+            .map(|((tok, (start, end)), trivia)| (tok, &self.source[*start..*end], trivia.as_slice()))
+    }
+
+    // Byte range of the last consumed token, which is where a parse error was found
+    pub fn last_span(&self) -> Option<(usize, usize)> {
+        self.position
+            .checked_sub(1)
+            .and_then(|p| self.spans.get(p).copied())
+    }
+
+    // Byte offset just past the last consumed token
+    pub fn last_end(&self) -> usize {
+        match self.position {
+            0 => 0,
+            p => self.spans.get(p - 1).map_or(self.source.len(), |(_, end)| *end),
+        }
+    }
+
+    pub fn slice(&self, start: usize, end: usize) -> &str {
+        &self.source[start..end]
+    }
+
+    // 1-based line and column of a byte offset
+    pub fn line_col(&self, offset: usize) -> (usize, usize) {
+        let before = &self.source[..offset];
+        let line = before.matches('\n').count() + 1;
+        let col = before.chars().rev().take_while(|c| *c != '\n').count() + 1;
+        (line, col)
+    }
+
+    // file:line:col of a byte offset, or line:col when the source has no file
+    pub fn location(&self, offset: usize) -> String {
+        let (line, col) = self.line_col(offset);
+        match &self.file {
+            Some(file) => format!("{}:{}:{}", file, line, col),
+            None => format!("{}:{}", line, col),
+        }
     }
 
     pub fn next_token(&mut self) -> Token {
@@ -197,12 +395,30 @@ impl LexerContext {
         }
     }
 
-    pub fn peek_token(&self) -> Token {
-        if self.position < self.tokens.len() {
-            self.tokens[self.position].clone()
-        } else {
-            Token::Eof
+    // Turn an Arrow at the next token back into the - and > it was lexed from, for expressions
+    // like a->b, which subtract the unary > of b
+    pub fn split_arrow(&mut self) {
+        if self.tokens.get(self.position) != Some(&Token::Arrow) {
+            return;
         }
+        let (start, end) = self.spans[self.position];
+        self.tokens[self.position] = Token::Minus('-');
+        self.spans[self.position] = (start, start + 1);
+        self.tokens.insert(self.position + 1, Token::Greater('>'));
+        self.spans.insert(self.position + 1, (start + 1, end));
+        self.trivia.insert(self.position + 1, Vec::new());
+    }
+
+    pub fn peek_token(&self) -> Token {
+        self.peek_token_at(0)
+    }
+
+    // Look `ahead` tokens past the next one without consuming anything
+    pub fn peek_token_at(&self, ahead: usize) -> Token {
+        self.tokens
+            .get(self.position + ahead)
+            .cloned()
+            .unwrap_or(Token::Eof)
     }
 
     pub fn consume_assert_next_token(&mut self, expected: Token) -> Result<Token, String> {
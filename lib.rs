@@ -0,0 +1,32 @@
+pub mod ast;
+pub mod bytecode;
+#[cfg(feature = "llvm")]
+pub mod codegen;
+#[cfg(feature = "llvm")]
+pub mod debuginfo;
+#[cfg(feature = "llvm")]
+pub mod differential;
+#[cfg(feature = "llvm")]
+pub mod dot;
+#[cfg(feature = "llvm")]
+pub mod engine;
+pub mod externs;
+pub mod fold;
+pub mod formatter;
+pub mod generator;
+pub mod golden;
+pub mod interp;
+pub mod json;
+pub mod lexer;
+pub mod lsp;
+pub mod modules;
+pub mod parser;
+pub mod prelude;
+pub mod printer;
+pub mod resolver;
+pub mod serialize;
+pub mod visitor;
+pub mod vm;
+
+#[cfg(feature = "llvm")]
+pub use engine::Engine;
@@ -0,0 +1,544 @@
+use std::collections::HashMap;
+use std::io::{BufRead, Write};
+use std::path::PathBuf;
+
+use crate::ast::{Expr, Function};
+use crate::json::Json;
+use crate::lexer::{LexerContext, Token};
+use crate::fold;
+use crate::modules::ModuleLoader;
+use crate::parser::ParserContext;
+use crate::prelude;
+use crate::resolver;
+
+// A language server for .kls files, speaking the Language Server Protocol over stdio. Open
+// documents are parsed on every change the way the compiler parses a file, with the prelude and
+// their imports, and checked for names that don't resolve. Since both stop at the first error, a
+// document has at most one diagnostic. Definitions are found in the tokens of the document, so
+// go-to-definition and document symbols only cover what the document itself defines
+
+// LSP error code for requests the server doesn't implement
+const METHOD_NOT_FOUND: f64 = -32601.0;
+
+// Serve requests from `input` until the client sends exit or closes the stream
+pub fn serve(
+    input: &mut impl BufRead,
+    output: &mut impl Write,
+    search_paths: &[PathBuf],
+) -> Result<(), String> {
+    let mut server = Server {
+        documents: HashMap::new(),
+        search_paths: search_paths.to_vec(),
+        exit: false,
+    };
+    while let Some(message) = read_message(input)? {
+        for reply in server.handle(&message) {
+            write_message(output, &reply)?;
+        }
+        if server.exit {
+            break;
+        }
+    }
+    Ok(())
+}
+
+// Messages are JSON preceded by a Content-Length header. Returns None at the end of the input
+fn read_message(input: &mut impl BufRead) -> Result<Option<Json>, String> {
+    let mut length = None;
+    loop {
+        let mut line = String::new();
+        if input.read_line(&mut line).map_err(|e| e.to_string())? == 0 {
+            return Ok(None);
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':')
+            && name.eq_ignore_ascii_case("Content-Length")
+        {
+            length = Some(
+                value
+                    .trim()
+                    .parse::<usize>()
+                    .map_err(|e| format!("Invalid Content-Length: {}", e))?,
+            );
+        }
+    }
+
+    let length = length.ok_or("Message without a Content-Length header")?;
+    let mut body = vec![0; length];
+    input.read_exact(&mut body).map_err(|e| e.to_string())?;
+    let text = String::from_utf8(body).map_err(|e| e.to_string())?;
+    Json::parse(&text).map(Some)
+}
+
+fn write_message(output: &mut impl Write, message: &Json) -> Result<(), String> {
+    let text = message.to_string();
+    write!(output, "Content-Length: {}\r\n\r\n{}", text.len(), text)
+        .and_then(|_| output.flush())
+        .map_err(|e| e.to_string())
+}
+
+struct Server {
+    documents: HashMap<String, Document>,
+    search_paths: Vec<PathBuf>,
+    exit: bool,
+}
+
+struct Document {
+    text: String,
+    // Functions parsed before any error, including the prelude's and those of imports
+    functions: Vec<Function>,
+    tokens: Vec<(Token, (usize, usize))>,
+    // The name of each def and extern of the document, with the byte range of the name
+    definitions: Vec<(String, (usize, usize))>,
+    // The error and the byte range it is about
+    error: Option<(String, (usize, usize))>,
+}
+
+impl Server {
+    // The responses and notifications a message calls for
+    fn handle(&mut self, message: &Json) -> Vec<Json> {
+        let method = match message.get("method").and_then(|m| m.as_str()) {
+            Some(m) => m,
+            // A response to a request of ours, and we make none
+            None => return Vec::new(),
+        };
+        let id = message.get("id").cloned();
+        let params = message.get("params").cloned().unwrap_or(Json::Null);
+        let uri = params
+            .get("textDocument")
+            .and_then(|d| d.get("uri"))
+            .and_then(|u| u.as_str())
+            .unwrap_or_default()
+            .to_string();
+
+        let result = match method {
+            "initialize" => Json::object(vec![
+                ("capabilities", capabilities()),
+                (
+                    "serverInfo",
+                    Json::object(vec![("name", Json::string("rust_kaleidoscope"))]),
+                ),
+            ]),
+            "shutdown" => Json::Null,
+            "exit" => {
+                self.exit = true;
+                return Vec::new();
+            }
+            "textDocument/didOpen" => {
+                let text = params
+                    .get("textDocument")
+                    .and_then(|d| d.get("text"))
+                    .and_then(|t| t.as_str())
+                    .unwrap_or_default();
+                return vec![self.update(&uri, text)];
+            }
+            "textDocument/didChange" => {
+                // Documents are synchronized in full, so the last change has the whole text
+                let text = params
+                    .get("contentChanges")
+                    .and_then(|c| c.as_array())
+                    .and_then(|c| c.last())
+                    .and_then(|c| c.get("text"))
+                    .and_then(|t| t.as_str())
+                    .unwrap_or_default();
+                return vec![self.update(&uri, text)];
+            }
+            "textDocument/didClose" => {
+                self.documents.remove(&uri);
+                return vec![diagnostics(&uri, Vec::new())];
+            }
+            "textDocument/definition"
+            | "textDocument/hover"
+            | "textDocument/completion"
+            | "textDocument/documentSymbol" => match self.documents.get(&uri) {
+                Some(doc) => {
+                    let offset = params
+                        .get("position")
+                        .map_or(0, |p| doc.offset(p));
+                    match method {
+                        "textDocument/definition" => doc.definition(&uri, offset),
+                        "textDocument/hover" => doc.hover(offset),
+                        "textDocument/completion" => doc.completion(),
+                        _ => doc.symbols(&uri),
+                    }
+                }
+                None => Json::Null,
+            },
+            _ => {
+                // Notifications we don't handle are ignored, requests get an error
+                return match id {
+                    Some(id) => vec![Json::object(vec![
+                        ("jsonrpc", Json::string("2.0")),
+                        ("id", id),
+                        (
+                            "error",
+                            Json::object(vec![
+                                ("code", Json::Number(METHOD_NOT_FOUND)),
+                                ("message", Json::String(format!("Unknown method {}", method))),
+                            ]),
+                        ),
+                    ])],
+                    None => Vec::new(),
+                };
+            }
+        };
+
+        match id {
+            Some(id) => vec![Json::object(vec![
+                ("jsonrpc", Json::string("2.0")),
+                ("id", id),
+                ("result", result),
+            ])],
+            None => Vec::new(),
+        }
+    }
+
+    // Reparse a document, returning its diagnostics
+    fn update(&mut self, uri: &str, text: &str) -> Json {
+        let doc = Document::parse(uri, text, &self.search_paths);
+        let found = doc
+            .error
+            .iter()
+            .map(|(message, span)| {
+                Json::object(vec![
+                    ("range", doc.range(*span)),
+                    ("severity", Json::Number(1.0)),
+                    ("source", Json::string("kaleidoscope")),
+                    ("message", Json::string(message)),
+                ])
+            })
+            .collect();
+        self.documents.insert(uri.to_string(), doc);
+        diagnostics(uri, found)
+    }
+}
+
+fn capabilities() -> Json {
+    Json::object(vec![
+        // Full text on every change
+        ("textDocumentSync", Json::Number(1.0)),
+        ("definitionProvider", Json::Bool(true)),
+        ("hoverProvider", Json::Bool(true)),
+        ("completionProvider", Json::object(Vec::new())),
+        ("documentSymbolProvider", Json::Bool(true)),
+    ])
+}
+
+fn diagnostics(uri: &str, found: Vec<Json>) -> Json {
+    Json::object(vec![
+        ("jsonrpc", Json::string("2.0")),
+        ("method", Json::string("textDocument/publishDiagnostics")),
+        (
+            "params",
+            Json::object(vec![("uri", Json::string(uri)), ("diagnostics", Json::Array(found))]),
+        ),
+    ])
+}
+
+// The file a file:// URI names
+fn uri_to_path(uri: &str) -> Option<PathBuf> {
+    let path = uri.strip_prefix("file://")?;
+    let mut bytes = Vec::new();
+    let mut rest = path.bytes();
+    while let Some(b) = rest.next() {
+        match b {
+            b'%' => {
+                let hex: Vec<u8> = rest.by_ref().take(2).collect();
+                let code = std::str::from_utf8(&hex)
+                    .ok()
+                    .and_then(|h| u8::from_str_radix(h, 16).ok())?;
+                bytes.push(code);
+            }
+            b => bytes.push(b),
+        }
+    }
+    String::from_utf8(bytes).ok().map(PathBuf::from)
+}
+
+impl Document {
+    fn parse(uri: &str, text: &str, search_paths: &[PathBuf]) -> Document {
+        let path = uri_to_path(uri);
+        let mut lexer = LexerContext::new();
+        lexer.file = Some(match &path {
+            Some(p) => p.display().to_string(),
+            None => uri.to_string(),
+        });
+        let mut parser = ParserContext::new();
+
+        let lexed = lexer.lex(text);
+        let parsed = lexed.clone().and_then(|()| {
+            prelude::load(&mut parser)?;
+            parser.loader = Some(ModuleLoader::new(search_paths.to_vec(), path.as_deref()));
+            parser.debug_info = true;
+            parser.parse(&mut lexer)
+        });
+
+        let tokens: Vec<(Token, (usize, usize))> = lexer
+            .spanned_tokens()
+            .map(|(tok, span)| (tok.clone(), span))
+            .collect();
+        let mut doc = Document {
+            text: text.to_string(),
+            functions: parser.functions,
+            definitions: definitions(&tokens),
+            tokens,
+            error: None,
+        };
+        doc.error = match parsed {
+            // A lexer error points at what it couldn't lex, a parser error at the last token read
+            Err(e) => {
+                let span = match lexed {
+                    Err(_) => lexer.error_span(),
+                    Ok(()) => lexer.last_span(),
+                };
+                Some((e, span.unwrap_or((0, 0))))
+            }
+            Ok(()) => resolver::resolve(&doc.functions)
+                .and_then(|_| fold::fold_program(&mut doc.functions))
+                .err()
+                .map(|e| doc.locate_check_error(e)),
+        };
+        doc
+    }
+
+    // Errors of the checks start with the line:col of the expression they are about
+    fn locate_check_error(&self, error: String) -> (String, (usize, usize)) {
+        let location = error.split_once(": ").and_then(|(location, message)| {
+            let (line, col) = location.split_once(':')?;
+            Some((line.parse::<usize>().ok()?, col.parse::<usize>().ok()?, message))
+        });
+        let (line, col, message) = match location {
+            Some(l) => l,
+            None => return (error, (0, 0)),
+        };
+        let offset = self
+            .text
+            .split_inclusive('\n')
+            .take(line - 1)
+            .map(|l| l.len())
+            .sum::<usize>();
+        let offset = self.text[offset..]
+            .char_indices()
+            .nth(col - 1)
+            .map_or(self.text.len(), |(i, _)| offset + i);
+        let span = self
+            .tokens
+            .iter()
+            .find(|(_, (start, _))| *start == offset)
+            .map_or((offset, offset), |(_, span)| *span);
+        (message.to_string(), span)
+    }
+
+    // Byte offset of an LSP position, whose character counts UTF-16 code units
+    fn offset(&self, position: &Json) -> usize {
+        let line = position.get("line").and_then(|l| l.as_f64()).unwrap_or(0.0) as usize;
+        let character = position.get("character").and_then(|c| c.as_f64()).unwrap_or(0.0) as usize;
+        let start: usize = self.text.split_inclusive('\n').take(line).map(|l| l.len()).sum();
+        let mut units = 0;
+        for (i, c) in self.text[start..].char_indices() {
+            if units >= character || c == '\n' {
+                return start + i;
+            }
+            units += c.len_utf16();
+        }
+        self.text.len()
+    }
+
+    fn position(&self, offset: usize) -> Json {
+        let before = &self.text[..offset.min(self.text.len())];
+        let line_start = before.rfind('\n').map_or(0, |i| i + 1);
+        let character: usize = before[line_start..].chars().map(|c| c.len_utf16()).sum();
+        Json::object(vec![
+            ("line", Json::Number(before.matches('\n').count() as f64)),
+            ("character", Json::Number(character as f64)),
+        ])
+    }
+
+    fn range(&self, (start, end): (usize, usize)) -> Json {
+        Json::object(vec![("start", self.position(start)), ("end", self.position(end))])
+    }
+
+    // The token under the cursor, or just before it, as when the cursor is at the end of a name
+    fn token_at(&self, offset: usize) -> Option<&(Token, (usize, usize))> {
+        self.tokens
+            .iter()
+            .find(|(_, (start, end))| *start <= offset && offset < *end)
+            .or_else(|| self.tokens.iter().find(|(_, (start, end))| start < end && *end == offset))
+    }
+
+    // The function the token under the cursor refers to. Operator characters refer to the
+    // user-defined operator, binary if there is one
+    fn function_at(&self, offset: usize) -> Option<(&Function, (usize, usize))> {
+        let (tok, span) = self.token_at(offset)?;
+        let names = match tok {
+            Token::Identifier(name) => vec![name.clone()],
+            Token::Binary(c) => vec![format!("binary{}", c)],
+            Token::Unary(c) => vec![format!("unary{}", c)],
+            _ => match tok.operator_char() {
+                Some(c) => vec![format!("binary{}", c), format!("unary{}", c)],
+                None => Vec::new(),
+            },
+        };
+        names
+            .iter()
+            .find_map(|name| self.lookup(name))
+            .map(|f| (f, *span))
+    }
+
+    // The definition a name refers to. The last one wins, as when compiling
+    fn lookup(&self, name: &str) -> Option<&Function> {
+        self.functions
+            .iter()
+            .rfind(|f| f.name == name && f.test.is_none() && f.name != "_top_level_expr")
+    }
+
+    fn definition(&self, uri: &str, offset: usize) -> Json {
+        let name = match self.function_at(offset) {
+            Some((f, _)) => f.name.as_str(),
+            None => return Json::Null,
+        };
+        match self.definitions.iter().rfind(|(n, _)| n == name) {
+            Some((_, span)) => Json::object(vec![
+                ("uri", Json::string(uri)),
+                ("range", self.range(*span)),
+            ]),
+            None => Json::Null,
+        }
+    }
+
+    fn hover(&self, offset: usize) -> Json {
+        let (f, span) = match self.function_at(offset) {
+            Some(found) => found,
+            None => return Json::Null,
+        };
+        let mut text = format!("```kaleidoscope\n{}\n```", describe(f));
+        if let Some(module) = &f.module {
+            text.push_str(&format!("\n\nFrom module `{}`", module));
+        }
+        Json::object(vec![
+            (
+                "contents",
+                Json::object(vec![
+                    ("kind", Json::string("markdown")),
+                    ("value", Json::String(text)),
+                ]),
+            ),
+            ("range", self.range(span)),
+        ])
+    }
+
+    // Every function a program can call by name: its own, the prelude's, and the public ones
+    // of its imports
+    fn completion(&self) -> Json {
+        let mut seen = Vec::new();
+        let mut items = Vec::new();
+        for f in &self.functions {
+            if f.is_operator
+                || f.test.is_some()
+                || f.name == "_top_level_expr"
+                || (f.module.is_some() && !f.is_pub)
+                || seen.contains(&f.name)
+            {
+                continue;
+            }
+            seen.push(f.name.clone());
+            items.push(Json::object(vec![
+                ("label", Json::string(&f.name)),
+                // CompletionItemKind.Function
+                ("kind", Json::Number(3.0)),
+                ("detail", Json::String(describe(f))),
+            ]));
+        }
+        Json::Array(items)
+    }
+
+    fn symbols(&self, uri: &str) -> Json {
+        Json::Array(
+            self.definitions
+                .iter()
+                .map(|(name, span)| {
+                    // SymbolKind.Operator, SymbolKind.Constant or SymbolKind.Function
+                    let kind = if name.starts_with("binary") || name.starts_with("unary") {
+                        25.0
+                    } else if self.lookup(name).is_some_and(|f| f.is_const) {
+                        14.0
+                    } else {
+                        12.0
+                    };
+                    Json::object(vec![
+                        ("name", Json::string(name)),
+                        ("kind", Json::Number(kind)),
+                        (
+                            "location",
+                            Json::object(vec![
+                                ("uri", Json::string(uri)),
+                                ("range", self.range(*span)),
+                            ]),
+                        ),
+                    ])
+                })
+                .collect(),
+        )
+    }
+}
+
+// The names defined by def, extern and const, found from the tokens so the definitions before
+// and after a parse error are all there
+fn definitions(tokens: &[(Token, (usize, usize))]) -> Vec<(String, (usize, usize))> {
+    let mut found = Vec::new();
+    for (i, (tok, _)) in tokens.iter().enumerate() {
+        let constant = matches!(tok, Token::Identifier(name) if name == "const");
+        if !matches!(tok, Token::Def | Token::Extern) && !constant {
+            continue;
+        }
+        // Externs can name the library first
+        let mut rest = tokens[i + 1..]
+            .iter()
+            .skip_while(|(t, _)| matches!(tok, Token::Extern) && matches!(t, Token::Str(_)));
+        let name = match rest.next() {
+            Some((Token::Identifier(name), span)) => (name.clone(), *span),
+            _ if constant => continue,
+            Some((Token::Binary(c), span)) => (format!("binary{}", c), *span),
+            Some((Token::Unary(c), span)) => (format!("unary{}", c), *span),
+            _ => continue,
+        };
+        found.push(name);
+    }
+    found
+}
+
+// A function's prototype as it would be written in source. Binary operators show the
+// precedence they have, even when it is the default
+fn describe(f: &Function) -> String {
+    if f.is_const {
+        return match f.body {
+            Expr::Number(value) => format!("const {} = {}", f.name, value),
+            _ => format!("const {}", f.name),
+        };
+    }
+    let mut text = String::from(if matches!(f.body, Expr::None) { "extern " } else { "def " });
+    if let Some(library) = &f.library {
+        text.push_str(&format!("\"{}\" ", library));
+    }
+    text.push_str(&f.name);
+    if f.is_operator && f.name.starts_with("binary") {
+        text.push_str(&format!(" {} ", f.precedence.unwrap_or(30.0)));
+    }
+    let params: Vec<String> = match &f.signature {
+        Some(sig) => f
+            .args
+            .iter()
+            .zip(&sig.params)
+            .map(|(name, ty)| format!("{}: {}", name, ty.name()))
+            .collect(),
+        None => f.args.clone(),
+    };
+    text.push_str(&format!("({})", params.join(" ")));
+    if let Some(sig) = &f.signature {
+        text.push_str(&format!(" -> {}", sig.ret.name()));
+    }
+    text
+}
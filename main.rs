@@ -1,23 +1,170 @@
-pub mod ast;
-pub mod codegen;
-pub mod externs;
-pub mod lexer;
-pub mod parser;
-use codegen::CodegenContext;
-use externs::FfiRegistry;
+#[cfg(feature = "llvm")]
 use inkwell::{context::Context, OptimizationLevel};
-use lexer::LexerContext;
-use parser::ParserContext;
+use rust_kaleidoscope::ast::Function;
+use rust_kaleidoscope::bytecode::{self, Program};
+#[cfg(feature = "llvm")]
+use rust_kaleidoscope::codegen::CodegenContext;
+#[cfg(feature = "llvm")]
+use rust_kaleidoscope::differential;
+#[cfg(feature = "llvm")]
+use rust_kaleidoscope::dot;
+use rust_kaleidoscope::externs::{self, FfiRegistry, OutputSink};
+use rust_kaleidoscope::fold;
+use rust_kaleidoscope::formatter;
+#[cfg(feature = "llvm")]
+use rust_kaleidoscope::generator::ProgramGenerator;
+use rust_kaleidoscope::golden::{self, Backend, Outcome};
+use rust_kaleidoscope::interp::Interpreter;
+use rust_kaleidoscope::lexer::LexerContext;
+use rust_kaleidoscope::lsp;
+use rust_kaleidoscope::modules::ModuleLoader;
+use rust_kaleidoscope::parser::ParserContext;
+use rust_kaleidoscope::prelude;
+use rust_kaleidoscope::printer;
+use rust_kaleidoscope::resolver;
+use rust_kaleidoscope::serialize;
+use rust_kaleidoscope::vm::Vm;
 use std::env;
 use std::fs::File;
-use std::io::{self, ErrorKind, Read};
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
 
 fn main() -> io::Result<()> {
-    let args: Vec<String> = env::args().collect();
-    let mut input = String::new();
+    let mut filename: Option<String> = None;
+    // `test [paths...]` runs golden tests instead of a single program
+    let mut test_mode = false;
+    let mut test_paths: Vec<String> = Vec::new();
+    let mut libraries: Vec<PathBuf> = Vec::new();
+    let mut search_paths: Vec<PathBuf> = Vec::new();
+    let mut backend = Backend::default();
+    #[cfg(feature = "llvm")]
+    let mut differential = false;
+    let mut use_prelude = true;
+    let mut emit_bytecode: Option<PathBuf> = None;
+    #[cfg(feature = "llvm")]
+    let mut emit_object: Option<PathBuf> = None;
+    // `--emit=cfg-dot` writes the control flow graph of each function, and `--emit=cfg-dot-opt`
+    // those of the optimized code as well
+    #[cfg(feature = "llvm")]
+    let mut emit_cfg: Option<bool> = None;
+    #[cfg(feature = "llvm")]
+    let mut debug_info = false;
+    let mut list_externs = false;
+    // `--print-ast sexpr|json|source` prints the parsed program instead of running it
+    let mut print_ast: Option<String> = None;
+    let mut ffi_registry = FfiRegistry::new();
+
+    let mut args = env::args().skip(1).peekable();
+    if args.peek().map(|a| a.as_str()) == Some("test") {
+        args.next();
+        test_mode = true;
+    }
+    #[cfg(feature = "llvm")]
+    if args.peek().map(|a| a.as_str()) == Some("soak") {
+        args.next();
+        return soak(args);
+    }
+    if args.peek().map(|a| a.as_str()) == Some("fmt") {
+        args.next();
+        return format_files(args);
+    }
+    if args.peek().map(|a| a.as_str()) == Some("lsp") {
+        args.next();
+        return serve_lsp(args);
+    }
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-I" => {
+                let dir = args
+                    .next()
+                    .ok_or_else(|| io::Error::other("-I expects a directory"))?;
+                search_paths.push(PathBuf::from(dir));
+            }
+            "--no-prelude" => use_prelude = false,
+            "--list-externs" => list_externs = true,
+            "--interp" => backend = Backend::Interp,
+            "--vm" => backend = Backend::Vm,
+            "--emit-bytecode" => {
+                let path = args
+                    .next()
+                    .ok_or_else(|| io::Error::other("--emit-bytecode expects a file path"))?;
+                emit_bytecode = Some(PathBuf::from(path));
+            }
+            "--print-ast" => {
+                let format = args
+                    .next()
+                    .ok_or_else(|| io::Error::other("--print-ast expects sexpr, json or source"))?;
+                print_ast = Some(format);
+            }
+            #[cfg(feature = "llvm")]
+            "--emit-object" => {
+                let path = args
+                    .next()
+                    .ok_or_else(|| io::Error::other("--emit-object expects a file path"))?;
+                emit_object = Some(PathBuf::from(path));
+            }
+            #[cfg(feature = "llvm")]
+            "--emit=cfg-dot" => emit_cfg = Some(false),
+            #[cfg(feature = "llvm")]
+            "--emit=cfg-dot-opt" => emit_cfg = Some(true),
+            #[cfg(feature = "llvm")]
+            _ if arg.starts_with("--emit=") => {
+                return Err(io::Error::other(format!(
+                    "Unknown output {}, expected --emit=cfg-dot or --emit=cfg-dot-opt",
+                    arg
+                )));
+            }
+            #[cfg(not(feature = "llvm"))]
+            _ if arg.starts_with("--emit=") => {
+                return Err(io::Error::other(format!(
+                    "{} needs the llvm feature, which this build was compiled without",
+                    arg
+                )));
+            }
+            #[cfg(feature = "llvm")]
+            "-g" => debug_info = true,
+            #[cfg(feature = "llvm")]
+            "--differential" => differential = true,
+            "--output" => {
+                let path = args
+                    .next()
+                    .ok_or_else(|| io::Error::other("--output expects a file path"))?;
+                externs::set_output(OutputSink::File(File::create(path)?));
+            }
+            "--link-lib" => {
+                let lib = args
+                    .next()
+                    .ok_or_else(|| io::Error::other("--link-lib expects a library path"))?;
+                ffi_registry
+                    .load_library(Path::new(&lib))
+                    .map_err(io::Error::other)?;
+                libraries.push(PathBuf::from(lib));
+            }
+            _ if arg.starts_with("-I") => search_paths.push(PathBuf::from(&arg[2..])),
+            _ if test_mode => test_paths.push(arg),
+            _ => filename = Some(arg),
+        }
+    }
+
+    if test_mode {
+        return run_tests(&test_paths, backend, &libraries, &search_paths);
+    }
 
-    if args.len() > 1 {
-        let filename = &args[1];
+    if list_externs {
+        print_externs(&ffi_registry);
+        return Ok(());
+    }
+
+    // Compiled bytecode runs on the VM without parsing anything
+    if let Some(filename) = filename.as_deref().filter(|f| f.ends_with(".kbc")) {
+        let program = Program::load(Path::new(filename)).map_err(io::Error::other)?;
+        run_bytecode(&program, &ffi_registry)?;
+        report_assert_failures(&externs::take_assert_failures());
+        return Ok(());
+    }
+
+    let mut input = String::new();
+    if let Some(filename) = &filename {
         let mut file = File::open(filename)?;
         file.read_to_string(&mut input)?;
     } else {
@@ -26,24 +173,107 @@ fn main() -> io::Result<()> {
 
     // Lex the entire input into tokens
     let mut lexer = LexerContext::new();
-    lexer.lex(&input);
+    lexer.trace = print_ast.is_none();
+    lexer.file = filename.clone();
+    lexer.lex(&input).map_err(io::Error::other)?;
 
     let mut parser = ParserContext::new();
+    parser.trace = print_ast.is_none();
+    if use_prelude {
+        prelude::load(&mut parser).map_err(io::Error::other)?;
+    }
+    let prelude_len = parser.functions.len();
+    parser.loader = Some(ModuleLoader::new(
+        search_paths,
+        filename.as_deref().map(Path::new),
+    ));
+    // After the prelude, whose lines are not in the source file
+    #[cfg(feature = "llvm")]
+    {
+        parser.debug_info = debug_info;
+    }
     parser
         .parse(&mut lexer)
-        .map_err(|e: String| io::Error::new(ErrorKind::Other, e))?;
+        .map_err(|e: String| io::Error::other(e))?;
 
+    if let Some(format) = print_ast {
+        return print_program(&parser.functions[prelude_len..], &format);
+    }
+    resolver::resolve(&parser.functions).map_err(io::Error::other)?;
+    fold::fold_program(&mut parser.functions).map_err(io::Error::other)?;
 
+    if let Some(path) = emit_bytecode {
+        let program = bytecode::compile(&parser.functions).map_err(io::Error::other)?;
+        program.save(&path).map_err(io::Error::other)?;
+        println!("Wrote {}", path.display());
+        return Ok(());
+    }
+
+    // Debug information is described relative to the source file
+    #[cfg(feature = "llvm")]
+    let source_path = debug_info.then(|| PathBuf::from(filename.as_deref().unwrap_or("<stdin>")));
+
+    #[cfg(feature = "llvm")]
+    if let Some(path) = emit_object {
+        return write_object(&parser, &path, source_path.as_deref());
+    }
+
+    #[cfg(feature = "llvm")]
+    if let Some(optimized) = emit_cfg {
+        let stem = filename
+            .as_deref()
+            .and_then(|f| Path::new(f).file_stem())
+            .map(|s| s.to_string_lossy().into_owned())
+            .unwrap_or_else(|| String::from("stdin"));
+        return write_cfg(&parser, Path::new(&format!("{}-cfg", stem)), optimized);
+    }
+
+    match backend {
+        #[cfg(feature = "llvm")]
+        Backend::Jit if differential => run_differential(&parser, &ffi_registry)?,
+        #[cfg(feature = "llvm")]
+        Backend::Jit => run_jit(&parser, &ffi_registry, source_path.as_deref())?,
+        Backend::Interp => run_interp(&parser, &ffi_registry)?,
+        Backend::Vm => {
+            let program = bytecode::compile(&parser.functions).map_err(io::Error::other)?;
+            run_bytecode(&program, &ffi_registry)?;
+        }
+    }
+
+    report_assert_failures(&externs::take_assert_failures());
+    Ok(())
+}
+
+// Failed assertions make the run fail once the program has finished
+fn report_assert_failures(failures: &[String]) {
+    if !failures.is_empty() {
+        for failure in failures {
+            eprintln!("{}", failure);
+        }
+        std::process::exit(1);
+    }
+}
+
+// Compile the program to IR, print it, and run it on the JIT. With a source path, the IR carries
+// debug information for it
+#[cfg(feature = "llvm")]
+fn run_jit(
+    parser: &ParserContext,
+    ffi_registry: &FfiRegistry,
+    source_path: Option<&Path>,
+) -> io::Result<()> {
     let context = Context::create();
     let mut cg = CodegenContext::new(&context, "main");
+    if let Some(path) = source_path {
+        cg.enable_debug_info(path).map_err(io::Error::other)?;
+    }
     let execution_engine = cg
         .module
         .create_jit_execution_engine(OptimizationLevel::None)
-        .map_err(|e| io::Error::new(ErrorKind::Other, format!("Failed to create JIT: {}", e)))?;
+        .map_err(|e| io::Error::other(format!("Failed to create JIT: {}", e)))?;
 
-    let ffi_registry = FfiRegistry::new();
-    cg.codegen(&parser, &ffi_registry, &execution_engine)
-        .map_err(|e: String| io::Error::new(ErrorKind::Other, e))?;
+    cg.codegen(parser, ffi_registry, &execution_engine)
+        .map_err(|e: String| io::Error::other(e))?;
 
     println!("{}", cg.module.print_to_string().to_string());
 
@@ -51,10 +281,261 @@ fn main() -> io::Result<()> {
     unsafe {
         let main_fn = execution_engine
             .get_function::<unsafe extern "C" fn() -> f64>("main")
-            .map_err(|e| io::Error::new(ErrorKind::Other, format!("Failed to get main: {}", e)))?;
+            .map_err(|e| io::Error::other(format!("Failed to get main: {}", e)))?;
         let result = main_fn.call();
         println!("\nResult: {}", result);
     }
+    Ok(())
+}
+
+// Compile the program to an object file. Top-level code goes into kls_main(), for a C main() to
+// call, see examples/aot/runtime.c
+#[cfg(feature = "llvm")]
+fn write_object(parser: &ParserContext, path: &Path, source_path: Option<&Path>) -> io::Result<()> {
+    let context = Context::create();
+    let mut cg = CodegenContext::with_entry(&context, "main", "kls_main");
+    if let Some(source_path) = source_path {
+        cg.enable_debug_info(source_path).map_err(io::Error::other)?;
+    }
+    cg.codegen_object(&parser.functions)
+        .map_err(io::Error::other)?;
+    cg.write_object(path).map_err(io::Error::other)?;
+    println!("Wrote {}", path.display());
+    Ok(())
+}
+
+// Write the control flow graph of each generated function to `dir` as NAME.dot, and with
+// `optimized`, the graph after LLVM's -O2 pipeline to `dir/opt`. Names may have dots, so a
+// suffix could clash with another function's graph
+#[cfg(feature = "llvm")]
+fn write_cfg(parser: &ParserContext, dir: &Path, optimized: bool) -> io::Result<()> {
+    let context = Context::create();
+    let mut cg = CodegenContext::new(&context, "main");
+    cg.codegen_object(&parser.functions)
+        .map_err(io::Error::other)?;
+
+    let write_graphs = |cg: &CodegenContext, dir: &Path| -> io::Result<()> {
+        std::fs::create_dir_all(dir)?;
+        for (name, graph) in dot::module_graphs(&cg.module) {
+            let path = dir.join(dot::file_name(&name));
+            std::fs::write(&path, graph)?;
+            println!("Wrote {}", path.display());
+        }
+        Ok(())
+    };
+    write_graphs(&cg, dir)?;
+    if optimized {
+        cg.optimize().map_err(io::Error::other)?;
+        write_graphs(&cg, &dir.join("opt"))?;
+    }
+    Ok(())
+}
+
+// Run the program on the interpreter, which needs no code generation
+fn run_interp(parser: &ParserContext, ffi_registry: &FfiRegistry) -> io::Result<()> {
+    let mut interp = Interpreter::new(&parser.functions, ffi_registry).map_err(io::Error::other)?;
+    let result = interp.run().map_err(io::Error::other)?;
+    println!("\nResult: {}", result);
+    Ok(())
+}
+
+fn run_bytecode(program: &Program, ffi_registry: &FfiRegistry) -> io::Result<()> {
+    let mut vm = Vm::new(program, ffi_registry).map_err(io::Error::other)?;
+    let result = vm.run().map_err(io::Error::other)?;
+    println!("\nResult: {}", result);
+    Ok(())
+}
+
+// Run the program on every backend, failing if they disagree
+#[cfg(feature = "llvm")]
+fn run_differential(parser: &ParserContext, ffi_registry: &FfiRegistry) -> io::Result<()> {
+    let execution =
+        differential::compare(&parser.functions, ffi_registry).map_err(io::Error::other)?;
+    externs::write_output(&execution.output);
+    let result = execution.result.map_err(io::Error::other)?;
+    println!("\nResult: {}", result);
+    report_assert_failures(&execution.assert_failures);
+    Ok(())
+}
 
+// `fmt [--check] [-I dir]... [files...]` rewrites each file in the canonical layout, or formats
+// stdin to stdout without files. --check only lists the files that would change, and fails if
+// there are any
+fn format_files(mut args: impl Iterator<Item = String>) -> io::Result<()> {
+    let mut check = false;
+    let mut search_paths = Vec::new();
+    let mut files = Vec::new();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--check" => check = true,
+            "-I" => {
+                let dir = args
+                    .next()
+                    .ok_or_else(|| io::Error::other("-I expects a directory"))?;
+                search_paths.push(PathBuf::from(dir));
+            }
+            _ if arg.starts_with("-I") => search_paths.push(PathBuf::from(&arg[2..])),
+            _ => files.push(PathBuf::from(arg)),
+        }
+    }
+
+    if files.is_empty() {
+        let mut input = String::new();
+        io::stdin().read_to_string(&mut input)?;
+        let formatted =
+            formatter::format_source(&input, None, &search_paths).map_err(io::Error::other)?;
+        print!("{}", formatted);
+        return Ok(());
+    }
+
+    let mut unformatted = 0;
+    for file in &files {
+        let source = std::fs::read_to_string(file)?;
+        let formatted = formatter::format_source(&source, Some(file), &search_paths)
+            .map_err(|e| io::Error::other(format!("{}: {}", file.display(), e)))?;
+        if formatted == source {
+            continue;
+        }
+        if check {
+            println!("{}", file.display());
+            unformatted += 1;
+        } else {
+            std::fs::write(file, formatted)?;
+        }
+    }
+    if unformatted > 0 {
+        std::process::exit(1);
+    }
+    Ok(())
+}
+
+// `lsp [-I dir]...` runs the language server on stdin and stdout, until the client exits
+fn serve_lsp(mut args: impl Iterator<Item = String>) -> io::Result<()> {
+    let mut search_paths = Vec::new();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-I" => {
+                let dir = args
+                    .next()
+                    .ok_or_else(|| io::Error::other("-I expects a directory"))?;
+                search_paths.push(PathBuf::from(dir));
+            }
+            _ if arg.starts_with("-I") => search_paths.push(PathBuf::from(&arg[2..])),
+            _ => return Err(io::Error::other(format!("Unknown lsp argument: {}", arg))),
+        }
+    }
+    lsp::serve(&mut io::stdin().lock(), &mut io::stdout().lock(), &search_paths)
+        .map_err(io::Error::other)
+}
+
+// `soak [--seed N] [--iterations N]` compares the backends on random programs, stopping at the
+// first that they disagree on
+#[cfg(feature = "llvm")]
+fn soak(mut args: impl Iterator<Item = String>) -> io::Result<()> {
+    let mut seed = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    let mut iterations = 1000;
+    while let Some(arg) = args.next() {
+        let mut number = |flag: &str| -> io::Result<u64> {
+            args.next()
+                .and_then(|n| n.parse().ok())
+                .ok_or_else(|| io::Error::other(format!("{} expects a number", flag)))
+        };
+        match arg.as_str() {
+            "--seed" => seed = number("--seed")?,
+            "--iterations" => iterations = number("--iterations")?,
+            _ => return Err(io::Error::other(format!("Unknown soak argument: {}", arg))),
+        }
+    }
+
+    let ffi_registry = FfiRegistry::new();
+    println!("Comparing {} programs from seed {}", iterations, seed);
+    for n in seed..seed.saturating_add(iterations) {
+        let functions = ProgramGenerator::new(n).generate();
+        if let Err(e) = differential::compare(&functions, &ffi_registry) {
+            eprintln!("Seed {}: {}\n{:#?}", n, e, functions);
+            std::process::exit(1);
+        }
+    }
+    println!("No differences");
+    Ok(())
+}
+
+// Print the functions of the program, without the prelude's, as S-expressions, JSON or source
+fn print_program(functions: &[Function], format: &str) -> io::Result<()> {
+    match format {
+        "sexpr" => print!("{}", serialize::to_sexpr(functions)),
+        "json" => println!("{}", serialize::to_json(functions)),
+        "source" => print!("{}", printer::print_program(functions)),
+        _ => {
+            return Err(io::Error::other(format!(
+                "Unknown AST format {}, expected sexpr, json or source",
+                format
+            )))
+        }
+    }
+    Ok(())
+}
+
+// Print the registered externs as declarations that can be pasted into a program
+fn print_externs(ffi_registry: &FfiRegistry) {
+    for (name, arity) in ffi_registry.list() {
+        let params: Vec<String> = (0..arity).map(|i| format!("x{}", i)).collect();
+        println!("extern {}({});", name, params.join(" "));
+    }
+    for lib in ffi_registry.library_names() {
+        println!("# and any symbol exported by {} (extern \"{}\" name(...))", lib, lib);
+    }
+    println!("# and any function of the C library, declared with C types");
+}
+
+// Run the golden tests in `paths` (default: examples/) and exit with status 1 if any fail
+fn run_tests(
+    paths: &[String],
+    backend: Backend,
+    libraries: &[PathBuf],
+    search_paths: &[PathBuf],
+) -> io::Result<()> {
+    let roots: Vec<PathBuf> = if paths.is_empty() {
+        vec![PathBuf::from("examples")]
+    } else {
+        paths.iter().map(PathBuf::from).collect()
+    };
+
+    let (mut passed, mut failed, mut skipped) = (0, 0, 0);
+    for root in roots {
+        for file in golden::collect_files(&root).map_err(io::Error::other)? {
+            for (test, outcome) in golden::run_file(&file, backend, libraries, search_paths) {
+                let label = match test {
+                    Some(name) => format!("{} \"{}\"", file.display(), name),
+                    None => file.display().to_string(),
+                };
+                match outcome {
+                    Outcome::Pass => {
+                        passed += 1;
+                        println!("PASS {}", label);
+                    }
+                    Outcome::Skip(reason) => {
+                        skipped += 1;
+                        println!("SKIP {} ({})", label, reason);
+                    }
+                    Outcome::Fail(report) => {
+                        failed += 1;
+                        println!("FAIL {}", label);
+                        for line in report.lines() {
+                            println!("    {}", line);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    println!("\n{} passed, {} failed, {} skipped", passed, failed, skipped);
+    if failed > 0 {
+        std::process::exit(1);
+    }
     Ok(())
 }
@@ -0,0 +1,197 @@
+use std::collections::{HashMap, HashSet};
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::ast::{Expr, Function};
+use crate::visitor::{walk_expr_mut, MutVisitor};
+
+pub enum ImportSpec {
+    // import "lib/math.kls";
+    Path(String),
+    // import math;  (dots become directory separators, so lib.math is lib/math.kls)
+    Name(String),
+}
+
+pub struct Module {
+    pub name: String,
+    pub path: PathBuf,
+    pub source: String,
+}
+
+// Resolves and reads imported files. Each file is loaded at most once, and the stack of files
+// currently being loaded is kept so import cycles can be reported
+pub struct ModuleLoader {
+    pub search_paths: Vec<PathBuf>,
+    loaded: HashSet<PathBuf>,
+    loading: Vec<PathBuf>,
+    names: HashMap<String, PathBuf>,
+}
+
+impl ModuleLoader {
+    // `root` is the file the program was read from, if any. Relative imports are resolved
+    // against the directory of the importing file, then against each search path in order
+    pub fn new(search_paths: Vec<PathBuf>, root: Option<&Path>) -> Self {
+        let mut loader = ModuleLoader {
+            search_paths,
+            loaded: HashSet::new(),
+            loading: Vec::new(),
+            names: HashMap::new(),
+        };
+        if let Some(root) = root.and_then(|p| p.canonicalize().ok()) {
+            loader.loaded.insert(root.clone());
+            loader.loading.push(root);
+        }
+        loader
+    }
+
+    // Start loading a module. Returns None if the module was already loaded, in which case
+    // there is nothing to do. Every Some must be paired with a call to leave()
+    pub fn enter(&mut self, spec: &ImportSpec) -> Result<Option<Module>, String> {
+        let path = self.resolve(spec)?;
+
+        if let Some(pos) = self.loading.iter().position(|p| *p == path) {
+            let chain: Vec<String> = self.loading[pos..]
+                .iter()
+                .chain(std::iter::once(&path))
+                .map(|p| p.display().to_string())
+                .collect();
+            return Err(format!("Import cycle detected: {}", chain.join(" -> ")));
+        }
+        if !self.loaded.insert(path.clone()) {
+            return Ok(None);
+        }
+
+        let name = path
+            .file_stem()
+            .map(|s| s.to_string_lossy().to_string())
+            .ok_or_else(|| format!("Invalid module path: {}", path.display()))?;
+        if let Some(other) = self.names.insert(name.clone(), path.clone()) {
+            return Err(format!(
+                "Module name `{}` is used by both {} and {}",
+                name,
+                other.display(),
+                path.display()
+            ));
+        }
+
+        let source = fs::read_to_string(&path)
+            .map_err(|e| format!("Failed to read module {}: {}", path.display(), e))?;
+        self.loading.push(path.clone());
+        Ok(Some(Module { name, path, source }))
+    }
+
+    pub fn leave(&mut self) {
+        self.loading.pop();
+    }
+
+    fn resolve(&self, spec: &ImportSpec) -> Result<PathBuf, String> {
+        let relative = match spec {
+            ImportSpec::Path(p) => PathBuf::from(p),
+            ImportSpec::Name(n) => PathBuf::from(format!("{}.kls", n.replace('.', "/"))),
+        };
+
+        let base = match self.loading.last().and_then(|p| p.parent()) {
+            Some(dir) => dir.to_path_buf(),
+            None => env::current_dir().map_err(|e| e.to_string())?,
+        };
+        let candidates: Vec<PathBuf> = std::iter::once(&base)
+            .chain(self.search_paths.iter())
+            .map(|dir| dir.join(&relative))
+            .collect();
+
+        candidates
+            .iter()
+            .find(|p| p.is_file())
+            .and_then(|p| p.canonicalize().ok())
+            .ok_or_else(|| {
+                let searched: Vec<String> =
+                    candidates.iter().map(|p| p.display().to_string()).collect();
+                format!(
+                    "Cannot find module {} (searched {})",
+                    relative.display(),
+                    searched.join(", ")
+                )
+            })
+    }
+}
+
+// Prefix every function defined in `module` with the module name, and rewrite references to
+// them from inside the module. Externs and operators stay global: externs name native symbols,
+// and operators are looked up by their character
+pub fn qualify(functions: &mut [Function], module: &str) {
+    let owned = |f: &Function| f.module.as_deref() == Some(module);
+    let locals: HashSet<String> = functions
+        .iter()
+        .filter(|f| owned(f) && !f.is_operator && !matches!(f.body, Expr::None))
+        .map(|f| f.name.clone())
+        .collect();
+
+    for f in functions.iter_mut().filter(|f| owned(f)) {
+        if locals.contains(&f.name) {
+            f.name = format!("{}.{}", module, f.name);
+        }
+        let mut qualifier = Qualifier {
+            module,
+            locals: &locals,
+            bound: f.args.clone(),
+        };
+        qualifier.visit_expr_mut(&mut f.body);
+    }
+}
+
+// Rewrites the names of a function body that refer to the module's own definitions, unless
+// a local binding shadows them
+struct Qualifier<'a> {
+    module: &'a str,
+    locals: &'a HashSet<String>,
+    bound: Vec<String>,
+}
+
+impl MutVisitor for Qualifier<'_> {
+    fn visit_expr_mut(&mut self, e: &mut Expr) {
+        if let Expr::Variable(name) | Expr::Call { identifier: name, .. } = e
+            && self.locals.contains(name)
+            && !self.bound.contains(name)
+        {
+            *name = format!("{}.{}", self.module, name);
+        }
+        walk_expr_mut(self, e);
+    }
+
+    fn bind(&mut self, name: &str) {
+        self.bound.push(name.to_string());
+    }
+
+    fn unbind(&mut self, _name: &str) {
+        self.bound.pop();
+    }
+}
+
+// Reject references to private definitions from outside the module that defines them
+pub fn check_visibility(functions: &[Function]) -> Result<(), String> {
+    let private: HashMap<&str, &str> = functions
+        .iter()
+        .filter(|f| !f.is_pub && !f.is_operator && !matches!(f.body, Expr::None))
+        .filter_map(|f| f.module.as_deref().map(|m| (f.name.as_str(), m)))
+        .collect();
+
+    for f in functions {
+        for name in f.body.free_variables() {
+            if let Some(owner) = private.get(name.as_str())
+                && f.module.as_deref() != Some(*owner)
+            {
+                return Err(format!(
+                    "`{}` is private to module `{}`; mark it `pub` to use it from {}",
+                    name,
+                    owner,
+                    match &f.module {
+                        Some(m) => format!("module `{}`", m),
+                        None => String::from("the main program"),
+                    }
+                ));
+            }
+        }
+    }
+    Ok(())
+}
@@ -1,10 +1,26 @@
-use crate::ast::{Expr, Function};
+use crate::ast::{CType, Expr, Function, Signature};
 use crate::lexer::{LexerContext, Token};
+use crate::modules::{self, ImportSpec, ModuleLoader};
 use std::collections::HashMap;
 
 pub struct ParserContext {
     pub functions: Vec<Function>,
     pub binop_precedence: HashMap<char, i8>,
+    // Imports are only available when a loader is set
+    pub loader: Option<ModuleLoader>,
+    // Module whose source is currently being parsed, None for the main program
+    pub module: Option<String>,
+    // Print every parsed function, and the tokens of imported modules
+    pub trace: bool,
+    // Wrap expressions in Expr::Located, for the line information of -g. Only expressions of
+    // the source being parsed get locations, not those of imported modules
+    pub debug_info: bool,
+}
+
+impl Default for ParserContext {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl ParserContext {
@@ -23,6 +39,10 @@ impl ParserContext {
         ParserContext {
             functions: Vec::new(),
             binop_precedence,
+            loader: None,
+            module: None,
+            trace: false,
+            debug_info: false,
         }
     }
 
@@ -38,6 +58,30 @@ impl ParserContext {
                     let f = self.parse_extern(lexer)?;
                     self.functions.push(f);
                 }
+                Token::Pub => {
+                    lexer.consume_assert_next_token(Token::Pub)?;
+                    let mut f = match lexer.peek_token() {
+                        Token::Def => self.parse_function_definition(lexer)?,
+                        Token::Extern => self.parse_extern(lexer)?,
+                        Token::Identifier(ref name) if name == "const" => self.parse_const(lexer)?,
+                        tok => return Err(format!("Expected def, extern or const after pub, got {:?}", tok)),
+                    };
+                    f.is_pub = true;
+                    self.functions.push(f);
+                }
+                Token::Import => self.parse_import(lexer)?,
+                Token::Identifier(ref name)
+                    if name == "test" && matches!(lexer.peek_token_at(1), Token::Str(_)) =>
+                {
+                    let f = self.parse_test(lexer)?;
+                    self.functions.push(f);
+                }
+                Token::Identifier(ref name)
+                    if name == "const" && matches!(lexer.peek_token_at(1), Token::Identifier(_)) =>
+                {
+                    let f = self.parse_const(lexer)?;
+                    self.functions.push(f);
+                }
                 Token::Eof => break,
 
                 // Top level expression
@@ -47,28 +91,58 @@ impl ParserContext {
                 }
             }
         }
+
+        // Once the whole program is parsed, all imports are resolved
+        if self.module.is_none() {
+            modules::check_visibility(&self.functions)?;
+        }
+        Ok(())
+    }
+
+    // Parse an imported module into this context, so its operators become usable immediately
+    // and its functions end up alongside ours, prefixed with the module name
+    fn parse_import(&mut self, lexer: &mut LexerContext) -> Result<(), String> {
+        lexer.consume_assert_next_token(Token::Import)?;
+        let spec = match lexer.next_token() {
+            Token::Str(path) => ImportSpec::Path(path),
+            Token::Identifier(name) => ImportSpec::Name(name),
+            tok => return Err(format!("Expected module name or path after import, got {:?}", tok)),
+        };
+
+        let loader = self
+            .loader
+            .as_mut()
+            .ok_or("Imports are not available without a module loader")?;
+        let module = match loader.enter(&spec)? {
+            Some(m) => m,
+            None => return Ok(()), // Already loaded
+        };
+
+        let mut module_lexer = LexerContext::new();
+        module_lexer.trace = self.trace;
+        module_lexer.file = Some(module.path.display().to_string());
+        let lexed = module_lexer.lex(&module.source);
+
+        let outer = self.module.replace(module.name.clone());
+        let debug_info = std::mem::replace(&mut self.debug_info, false);
+        let start = self.functions.len();
+        let result = lexed.and_then(|()| self.parse(&mut module_lexer));
+        self.module = outer;
+        self.debug_info = debug_info;
+        if let Some(loader) = self.loader.as_mut() {
+            loader.leave();
+        }
+        result.map_err(|e| format!("In module `{}`: {}", module.name, e))?;
+
+        modules::qualify(&mut self.functions[start..], &module.name);
         Ok(())
     }
 
     fn get_precedence(&self, tok: &Token) -> i8 {
         // Extract the character from the token
-        let op_char = match tok {
-            Token::Less(c)
-            | Token::Greater(c)
-            | Token::Plus(c)
-            | Token::Minus(c)
-            | Token::Star(c)
-            | Token::Slash(c)
-            | Token::Assign(c)
-            | Token::Bang(c)
-            | Token::Pipe(c)
-            | Token::Ampersand(c)
-            | Token::Caret(c)
-            | Token::Percent(c)
-            | Token::Dollar(c)
-            | Token::At(c)
-            | Token::Tilde(c) => *c,
-            _ => return -1,
+        let op_char = match tok.operator_char() {
+            Some(c) => c,
+            None => return -1,
         };
 
         // Look up the precedence in the table (handles both built-in and user-defined)
@@ -84,6 +158,7 @@ impl ParserContext {
     ) -> Result<Box<Expr>, String> {
         loop {
             // Peek the next token to see if it's a binary operator
+            lexer.split_arrow();
             let peeked = lexer.peek_token();
             let tok_prec = self.get_precedence(&peeked);
 
@@ -95,14 +170,18 @@ impl ParserContext {
             let op = lexer.next_token();
 
             // Parse the primary expression after the binary operator
+            let start = lexer.offset();
             let mut rhs = Box::new(self.parse_unary(lexer)?);
 
             // Check the next operator's precedence for right-associativity
+            lexer.split_arrow();
             let next_prec = self.get_precedence(&lexer.peek_token());
 
             if tok_prec < next_prec {
                 rhs = self.parse_binop_rhs(tok_prec + 1, rhs, lexer)?;
             }
+            // Operands of sequencing operators like $ are often statements on lines of their own
+            let rhs = Box::new(self.locate(lexer, start, *rhs));
 
             // Merge LHS and RHS
             lhs = Box::new(Expr::BinOp {
@@ -121,9 +200,11 @@ impl ParserContext {
             // Parens Expression - parse full expression inside
             Token::LParen(_) => {
                 lexer.consume_assert_next_token(Token::LParen('('))?;
-                let expr = self.parse_expression(lexer)?;
+                let expr = self.parse_unary(lexer)?;
+                let expr = self.parse_binop_rhs(0, Box::new(expr), lexer)?;
                 lexer.consume_assert_next_token(Token::RParen(')'))?;
-                Ok(expr)
+                // Not located, so (x) = 1 still assigns to a variable
+                Ok(*expr)
             }
 
             // Local Var Decls
@@ -174,6 +255,8 @@ impl ParserContext {
 
             // Either Expr::Variable or Expr::Call
             Token::Identifier(_) => {
+                let start = lexer.offset();
+
                 // Consume the identifier to get its name
                 let name = if let Token::Identifier(n) = lexer.next_token() {
                     n
@@ -201,6 +284,9 @@ impl ParserContext {
                     }
 
                     lexer.consume_assert_next_token(Token::RParen(')'))?;
+                    if name == "assert" || name == "assert_eq" {
+                        return Self::make_assert(lexer, start, name, args);
+                    }
                     Ok(Expr::Call {
                         args,
                         identifier: name,
@@ -212,6 +298,19 @@ impl ParserContext {
                 }
             }
 
+            // Lambda - \x y -> body
+            Token::Backslash(_) => {
+                lexer.consume_assert_next_token(Token::Backslash('\\'))?;
+                let mut params = Vec::new();
+                while let Token::Identifier(s) = lexer.peek_token() {
+                    lexer.next_token();
+                    params.push(s);
+                }
+                lexer.consume_assert_next_token(Token::Arrow)?;
+                let body = Box::new(self.parse_expression(lexer)?);
+                Ok(Expr::Lambda { params, body })
+            }
+
             // if-then-else
             Token::If => {
                 lexer.consume_assert_next_token(Token::If)?;
@@ -262,8 +361,35 @@ impl ParserContext {
         }
     }
 
+    // assert(cond) and assert_eq(a, b) are builtins rather than functions, since they need the
+    // text and location of the call to report failures
+    fn make_assert(
+        lexer: &LexerContext,
+        start: usize,
+        name: String,
+        args: Vec<Expr>,
+    ) -> Result<Expr, String> {
+        let arity = if name == "assert" { 1 } else { 2 };
+        if args.len() != arity {
+            return Err(format!(
+                "{}: {} takes {} argument{}, got {}",
+                lexer.location(start),
+                name,
+                arity,
+                if arity == 1 { "" } else { "s" },
+                args.len()
+            ));
+        }
+        Ok(Expr::Assert {
+            args,
+            text: lexer.slice(start, lexer.last_end()).to_string(),
+            location: lexer.location(start),
+        })
+    }
+
     fn parse_unary(&self, lexer: &mut LexerContext) -> Result<Expr, String> {
         // (  )  ,  are all reserved
+        lexer.split_arrow();
         match lexer.peek_token() {
             Token::Plus(c)
             | Token::Minus(c)
@@ -292,8 +418,24 @@ impl ParserContext {
 
     // Parse full expressions with binary operators
     fn parse_expression(&self, lexer: &mut LexerContext) -> Result<Expr, String> {
+        let start = lexer.offset();
         let expr = self.parse_unary(lexer)?;
-        self.parse_binop_rhs(0, Box::new(expr), lexer).map(|b| *b)
+        let expr = self.parse_binop_rhs(0, Box::new(expr), lexer)?;
+        Ok(self.locate(lexer, start, *expr))
+    }
+
+    // Wrap an expression starting at byte offset `start` in its location, if debug information
+    // is wanted
+    fn locate(&self, lexer: &LexerContext, start: usize, expr: Expr) -> Expr {
+        if !self.debug_info {
+            return expr;
+        }
+        let (line, col) = lexer.line_col(start);
+        Expr::Located {
+            line: line as u32,
+            col: col as u32,
+            expr: Box::new(expr),
+        }
     }
 
     fn parse_top_level_expression(&self, lexer: &mut LexerContext) -> Result<Function, String> {
@@ -301,34 +443,141 @@ impl ParserContext {
         // Kaleidescope https://llvm.org/docs/tutorial/MyFirstLanguageFrontend/LangImpl02.html at least
         // semi-truthfully, that's how we're going to do it as well.
 
+        if let Some(module) = &self.module {
+            return Err(format!(
+                "Top-level expressions are not allowed in imported module `{}`",
+                module
+            ));
+        }
+
         let f = Function {
             name: String::from("_top_level_expr"),
             args: Vec::new(),
             body: self.parse_expression(lexer)?,
             is_operator: false,
             precedence: None,
+            module: None,
+            is_pub: false,
+            library: None,
+            signature: None,
+            test: None,
+            is_const: false,
+            from_prelude: false,
+        };
+
+        if self.trace {
+            println!("Parsed top level expr {:?}", f);
+        }
+        Ok(f)
+    }
+
+    // test "name" { expr ... } becomes a function with no arguments that the test runner calls.
+    // `test` is only a keyword in front of a string, so it can still be used as a name
+    fn parse_test(&mut self, lexer: &mut LexerContext) -> Result<Function, String> {
+        lexer.next_token();
+        let name = match lexer.next_token() {
+            Token::Str(name) => name,
+            _ => unreachable!("Peeked a string after test"),
+        };
+        lexer.consume_assert_next_token(Token::LBrace('{'))?;
+
+        let mut body = Vec::new();
+        loop {
+            match lexer.peek_token() {
+                Token::RBrace(_) => break,
+                Token::Eof => return Err(format!("Missing }} at the end of test \"{}\"", name)),
+                _ => body.push(self.parse_expression(lexer)?),
+            }
+        }
+        lexer.next_token();
+
+        let index = self.functions.iter().filter(|f| f.test.is_some()).count();
+        let f = Function {
+            name: format!("__test.{}", index),
+            args: Vec::new(),
+            body: Expr::Block(body),
+            is_operator: false,
+            precedence: None,
+            module: self.module.clone(),
+            is_pub: false,
+            library: None,
+            signature: None,
+            test: Some(name),
+            is_const: false,
+            from_prelude: false,
         };
+        if self.trace {
+            println!("Parsed test {:?}", f);
+        }
+        Ok(f)
+    }
 
-        println!("Parsed top level expr {:?}", f);
+    // const NAME = expr names a value computed when the program is compiled. It is kept as a
+    // function without arguments until folding replaces its uses. Like `test`, `const` is only a
+    // keyword in front of a name
+    fn parse_const(&mut self, lexer: &mut LexerContext) -> Result<Function, String> {
+        lexer.next_token();
+        let name = match lexer.next_token() {
+            Token::Identifier(name) => name,
+            tok => return Err(format!("Expected a name after const, got {:?}", tok)),
+        };
+        lexer
+            .consume_assert_next_token(Token::Assign('='))
+            .map_err(|_| format!("Expected = after const {}", name))?;
+
+        let f = Function {
+            name,
+            args: Vec::new(),
+            body: self.parse_expression(lexer)?,
+            is_operator: false,
+            precedence: None,
+            module: self.module.clone(),
+            is_pub: false,
+            library: None,
+            signature: None,
+            test: None,
+            is_const: true,
+            from_prelude: false,
+        };
+        if self.trace {
+            println!("Parsed const {:?}", f);
+        }
         Ok(f)
     }
 
     fn parse_function_definition(&mut self, lexer: &mut LexerContext) -> Result<Function, String> {
         lexer.consume_opt_next_token(Token::Def)?;
         let mut v = self.parse_proto(lexer)?;
+        if v.signature.is_some() {
+            return Err(format!(
+                "C types are only allowed on extern declarations, not in def {}",
+                v.name
+            ));
+        }
         v.body = self.parse_expression(lexer)?;
         Ok(v)
     }
 
     fn parse_extern(&mut self, lexer: &mut LexerContext) -> Result<Function, String> {
         lexer.consume_opt_next_token(Token::Extern)?;
-        self.parse_proto(lexer)
+
+        // Optional library to resolve the symbol from
+        let library = match lexer.consume_opt_next_token(Token::Str(String::new()))? {
+            Some(Token::Str(lib)) => Some(lib),
+            _ => None,
+        };
+
+        let mut f = self.parse_proto(lexer)?;
+        f.library = library;
+        Ok(f)
     }
 
     fn parse_proto(&mut self, lexer: &mut LexerContext) -> Result<Function, String> {
         let mut precedence: Option<f64> = None;
         let mut operator_kind: Option<Token> = None;
-        let name = match lexer.next_token() {
+        let tok = lexer.next_token();
+        let name_start = lexer.last_span().map_or(0, |(start, _)| start);
+        let name = match tok {
             // If it's a binary or unary, it means it is a user-defined overload
             tok @ Token::Binary(c) | tok @ Token::Unary(c) => {
                 // this next token maybe be the precedence level (if they specified one)
@@ -356,9 +605,21 @@ impl ParserContext {
         let _ = lexer.consume_assert_next_token(Token::LParen('('))?; // Skip Starting parens
 
         let mut args = Vec::new();
+        let mut param_types = Vec::new();
         loop {
             match lexer.next_token() {
-                Token::Identifier(s) => args.push(s),
+                Token::Identifier(s) => {
+                    args.push(s);
+                    // Optional C type for externs, as in (n: i32)
+                    let ty = match lexer.consume_opt_next_token(Token::Colon(':'))? {
+                        Some(_) => Some(self.parse_ctype(lexer)?),
+                        None => None,
+                    };
+                    if ty == Some(CType::Void) {
+                        return Err(String::from("Parameters can not have type void"));
+                    }
+                    param_types.push(ty);
+                }
                 Token::RParen(_) => break,
                 tok => {
                     return Err(format!(
@@ -369,22 +630,47 @@ impl ParserContext {
             }
         }
 
+        // Optional C return type, as in -> i32. Giving any type makes this a typed signature,
+        // where the untyped parts default to f64
+        let ret = match lexer.consume_opt_next_token(Token::Arrow)? {
+            Some(_) => Some(self.parse_ctype(lexer)?),
+            None => None,
+        };
+        let signature = if ret.is_some() || param_types.iter().any(|t| t.is_some()) {
+            Some(Signature {
+                params: param_types
+                    .iter()
+                    .map(|t| t.unwrap_or(CType::F64))
+                    .collect(),
+                ret: ret.unwrap_or(CType::F64),
+            })
+        } else {
+            None
+        };
+
         // Argument size validation for user-defined operators
-        match &operator_kind {
-            Some(Token::Binary(c)) => {
-                assert_eq!(
-                    args.len(),
-                    2,
-                    "Binary operators require exactly 2 arguments"
-                );
-                // Register binary operator in precedence table
-                let prec = precedence.unwrap_or(30.0) as i8; // Default precedence is 30
-                self.binop_precedence.insert(*c, prec);
-            }
-            Some(Token::Unary(_)) => {
-                assert_eq!(args.len(), 1, "Unary operators require exactly 1 argument")
-            }
-            _ => {}
+        let arity = match &operator_kind {
+            Some(Token::Binary(_)) => Some(("Binary", 2)),
+            Some(Token::Unary(_)) => Some(("Unary", 1)),
+            _ => None,
+        };
+        if let Some((kind, arity)) = arity
+            && args.len() != arity
+        {
+            return Err(format!(
+                "{}: {} operator {} requires exactly {} argument{}, got {}",
+                lexer.location(name_start),
+                kind,
+                name,
+                arity,
+                if arity == 1 { "" } else { "s" },
+                args.len()
+            ));
+        }
+        if let Some(Token::Binary(c)) = &operator_kind {
+            // Register binary operator in precedence table
+            let prec = precedence.unwrap_or(30.0) as i8; // Default precedence is 30
+            self.binop_precedence.insert(*c, prec);
         }
 
         let f = Function {
@@ -393,8 +679,26 @@ impl ParserContext {
             body: Expr::None,
             is_operator: operator_kind.is_some(),
             precedence,
+            module: self.module.clone(),
+            is_pub: false,
+            library: None,
+            signature,
+            test: None,
+            is_const: false,
+            from_prelude: false,
         };
-        println!("Parsed function proto {:?}", f);
+        if self.trace {
+            println!("Parsed function proto {:?}", f);
+        }
         Ok(f)
     }
+
+    fn parse_ctype(&self, lexer: &mut LexerContext) -> Result<CType, String> {
+        match lexer.next_token() {
+            Token::Identifier(name) => {
+                CType::from_name(&name).ok_or_else(|| format!("Unknown C type: {}", name))
+            }
+            tok => Err(format!("Expected a C type, got {:?}", tok)),
+        }
+    }
 }
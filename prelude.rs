@@ -0,0 +1,23 @@
+use crate::lexer::LexerContext;
+use crate::parser::ParserContext;
+
+// Kaleidoscope source parsed ahead of every program, compiled into the binary
+pub const SOURCE: &str = include_str!("prelude.kls");
+
+// Parse the prelude into `parser`, registering its operators in the precedence table so the
+// program that follows can use them. Its functions are marked as the prelude's, so the program
+// may replace them
+pub fn load(parser: &mut ParserContext) -> Result<(), String> {
+    let start = parser.functions.len();
+    let mut lexer = LexerContext::new();
+    lexer.trace = parser.trace;
+    lexer
+        .lex(SOURCE)
+        .and_then(|()| parser.parse(&mut lexer))
+        .map_err(|e| format!("In prelude: {}", e))?;
+    for f in &mut parser.functions[start..] {
+        f.from_prelude = true;
+    }
+    Ok(())
+}
+
@@ -0,0 +1,264 @@
+use std::collections::HashMap;
+
+use crate::ast::{Expr, Function};
+use crate::lexer::Token;
+use crate::parser::ParserContext;
+use crate::prelude;
+
+// Kaleidoscope source for a list of functions, which parses back to the same functions after
+// the prelude. Parentheses are only added where precedence needs them, so precedence is tracked
+// the way the parser does: binary operators take effect from their definition on. Functions of
+// imported modules are printed as ordinary definitions under their qualified names, so the
+// source stands on its own. Numbers must be finite, and negative ones come back as a negation
+
+const INDENT: usize = 2;
+
+pub fn print_program(functions: &[Function]) -> String {
+    let mut printer = Printer::new();
+    let mut items: Vec<(String, Option<String>, &str)> = Vec::new();
+    for f in functions {
+        let (head, body) = printer.function(f);
+        // Statements following each other must not read as one expression, see separate()
+        let is_expr = f.name == "_top_level_expr";
+        if let (Some((_, Some(previous), _)), Some(body)) = (items.last_mut(), body.as_ref())
+            && is_expr
+        {
+            let mut pair = [std::mem::take(previous), body.clone()];
+            separate(&mut pair);
+            let [previous_body, body] = pair;
+            *previous = previous_body;
+            items.push((head, Some(body), ";"));
+            continue;
+        }
+        let end = if f.test.is_some() { "" } else { ";" };
+        items.push((head, body, end));
+    }
+    items
+        .into_iter()
+        .map(|(head, body, end)| format!("{}{}{}\n", head, body.unwrap_or_default(), end))
+        .collect()
+}
+
+// Wrap expressions that follow each other in parentheses where the second would otherwise
+// continue the first: as a binary operation when it starts with an operator, or as a call when
+// it starts with a parenthesis
+fn separate(exprs: &mut [String]) {
+    for i in 1..exprs.len() {
+        let starts_operator = exprs[i]
+            .chars()
+            .next()
+            .and_then(Token::from_char)
+            .is_some_and(|t| t.operator_char().is_some());
+        if starts_operator {
+            exprs[i] = format!("({})", exprs[i]);
+        }
+        if exprs[i].starts_with('(') {
+            exprs[i - 1] = format!("({})", exprs[i - 1]);
+        }
+    }
+}
+
+struct Printer {
+    precedence: HashMap<char, i8>,
+}
+
+impl Printer {
+    // Programs are parsed after the prelude, so its operators are known
+    fn new() -> Self {
+        let mut parser = ParserContext::new();
+        // The prelude is part of the binary, so it always parses
+        let _ = prelude::load(&mut parser);
+        Printer {
+            precedence: parser.binop_precedence,
+        }
+    }
+
+    // The parts of a definition before and after its body, and the body. Tests are all head
+    fn function(&mut self, f: &Function) -> (String, Option<String>) {
+        if let Some(name) = &f.test {
+            let exprs = match &f.body {
+                Expr::Block(exprs) => exprs.as_slice(),
+                body => std::slice::from_ref(body),
+            };
+            let mut exprs: Vec<String> = exprs.iter().map(|e| self.expr(e, INDENT)).collect();
+            separate(&mut exprs);
+            let mut out = format!("test {} {{\n", quote(name));
+            for e in exprs {
+                out.push_str(&format!("{}{}\n", spaces(INDENT), e));
+            }
+            return (out + "}", None);
+        }
+        if f.name == "_top_level_expr" {
+            return (String::new(), Some(self.expr(&f.body, 0)));
+        }
+
+        if f.is_operator && f.name.starts_with("binary") {
+            let c = f.name["binary".len()..].chars().next().unwrap_or('?');
+            self.precedence
+                .insert(c, f.precedence.unwrap_or(30.0) as i8);
+        }
+        let mut out = String::new();
+        if f.is_pub {
+            out.push_str("pub ");
+        }
+        if f.is_const {
+            out.push_str(&format!("const {} = ", f.name));
+            return (out, Some(self.expr(&f.body, INDENT)));
+        }
+        let is_extern = matches!(f.body, Expr::None);
+        out.push_str(if is_extern { "extern " } else { "def " });
+        if let Some(library) = &f.library {
+            out.push_str(&format!("{} ", quote(library)));
+        }
+        out.push_str(&f.name);
+        if let Some(precedence) = f.precedence {
+            out.push_str(&format!(" {} ", precedence));
+        }
+        let params: Vec<String> = match &f.signature {
+            Some(sig) => f
+                .args
+                .iter()
+                .zip(&sig.params)
+                .map(|(name, ty)| format!("{}: {}", name, ty.name()))
+                .collect(),
+            None => f.args.clone(),
+        };
+        out.push_str(&format!("({})", params.join(" ")));
+        if let Some(sig) = &f.signature {
+            out.push_str(&format!(" -> {}", sig.ret.name()));
+        }
+        if is_extern {
+            return (out, None);
+        }
+        out.push_str(&format!("\n{}", spaces(INDENT)));
+        (out, Some(self.expr(&f.body, INDENT)))
+    }
+
+    fn binary_precedence(&self, op: char) -> i8 {
+        self.precedence.get(&op).copied().unwrap_or(30)
+    }
+
+    // `e` printed at `indent`, which is where lines it breaks into start
+    fn expr(&self, e: &Expr, indent: usize) -> String {
+        let inner = spaces(indent + INDENT);
+        match e {
+            Expr::Number(n) => format!("{}", n),
+            Expr::Variable(name) => name.clone(),
+            Expr::BinOp { left, op, right } => {
+                let c = op.operator_char().unwrap_or('?');
+                let precedence = self.binary_precedence(c);
+                // Operators are left associative, so an operand of the same precedence only
+                // needs parentheses on the right
+                let left = self.operand(left, indent, |p| p < precedence);
+                let right = self.operand(right, indent, |p| p <= precedence);
+                format!("{} {} {}", left, c, right)
+            }
+            Expr::Unary { op, left } => {
+                format!("{}{}", op, self.operand(left, indent, |_| true))
+            }
+            Expr::Call { identifier, args } => {
+                let args: Vec<String> = args.iter().map(|a| self.expr(a, indent)).collect();
+                format!("{}({})", identifier, args.join(", "))
+            }
+            Expr::Assert { args, .. } => {
+                let name = if args.len() == 1 { "assert" } else { "assert_eq" };
+                let args: Vec<String> = args.iter().map(|a| self.expr(a, indent)).collect();
+                format!("{}({})", name, args.join(", "))
+            }
+            Expr::If {
+                condition,
+                then,
+                els,
+            } => {
+                let mut out = format!(
+                    "if {} then\n{}{}\n{}else",
+                    self.expr(condition, indent),
+                    inner,
+                    self.expr(then, indent + INDENT),
+                    spaces(indent)
+                );
+                match unlocated(els) {
+                    els @ Expr::If { .. } => out.push_str(&format!(" {}", self.expr(els, indent))),
+                    els => out.push_str(&format!("\n{}{}", inner, self.expr(els, indent + INDENT))),
+                }
+                out
+            }
+            Expr::For {
+                ident,
+                start,
+                end,
+                step,
+                body,
+            } => {
+                let mut out = format!(
+                    "for {} = {}, {}",
+                    ident,
+                    self.expr(start, indent),
+                    self.expr(end, indent)
+                );
+                if let Some(step) = step {
+                    out.push_str(&format!(", {}", self.expr(step, indent)));
+                }
+                out + &format!(" in\n{}{}", inner, self.expr(body, indent + INDENT))
+            }
+            Expr::Var { varnames, body } => {
+                let bindings: Vec<String> = varnames
+                    .iter()
+                    .map(|(name, init)| match init {
+                        Some(init) => format!("{} = {}", name, self.expr(init, indent)),
+                        None => name.clone(),
+                    })
+                    .collect();
+                format!(
+                    "var {} in\n{}{}",
+                    bindings.join(", "),
+                    spaces(indent),
+                    self.expr(body, indent)
+                )
+            }
+            Expr::Lambda { params, body } => {
+                format!("\\{} -> {}", params.join(" "), self.expr(body, indent))
+            }
+            // Only test bodies are blocks, and those are printed by function()
+            Expr::Block(exprs) => {
+                let exprs: Vec<String> = exprs.iter().map(|e| self.expr(e, indent)).collect();
+                format!("({})", exprs.join(" $ "))
+            }
+            Expr::Located { expr, .. } => self.expr(expr, indent),
+            Expr::None => String::from("0"),
+        }
+    }
+
+    // An operand of an operator, in parentheses if it is a binary operation that `needs` them
+    // for its precedence. Expressions ending in another expression, like if and var, would take
+    // in whatever follows them, so they always get parentheses
+    fn operand(&self, e: &Expr, indent: usize, needs: impl Fn(i8) -> bool) -> String {
+        let parens = match unlocated(e) {
+            Expr::BinOp { op, .. } => needs(self.binary_precedence(op.operator_char().unwrap_or('?'))),
+            Expr::If { .. } | Expr::For { .. } | Expr::Var { .. } | Expr::Lambda { .. } => true,
+            _ => false,
+        };
+        let printed = self.expr(e, indent + 1);
+        if parens {
+            format!("({})", printed)
+        } else {
+            printed
+        }
+    }
+}
+
+fn unlocated(e: &Expr) -> &Expr {
+    match e {
+        Expr::Located { expr, .. } => unlocated(expr),
+        e => e,
+    }
+}
+
+fn spaces(n: usize) -> String {
+    " ".repeat(n)
+}
+
+// Strings have no escapes, so a name can't contain a quote
+fn quote(s: &str) -> String {
+    format!("\"{}\"", s)
+}
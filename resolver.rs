@@ -0,0 +1,196 @@
+use std::collections::HashMap;
+
+use crate::ast::{Expr, Function};
+use crate::lexer::Token;
+use crate::visitor::{fold_expr_children, walk_expr, Folder, Scope, Visitor};
+
+// Name resolution for a parsed program, run before any code is generated so every backend
+// reports the same errors, even for code that never runs. A function may be used anywhere in
+// the program, before or after its definition, and a variable in scope shadows any function.
+// Operators change how code parses, so they must be defined before the code using them, and
+// one defined over a builtin operator must come before any use of the builtin. Variables
+// declared with var are bound in its body only.
+//
+// Errors in code parsed with locations start with the line:col of the innermost expression
+// around the problem
+
+// Operators that work without a definition. Defining one of them replaces the builtin
+const BUILTIN_BINARY: [char; 6] = ['+', '-', '*', '/', '<', '>'];
+
+// What defining a name that is already defined does
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Redefinition {
+    // A program defines each name once. Repeating a definition exactly is allowed, since
+    // programs often declare what the prelude already has. The prelude is not part of the
+    // program, so a different definition replaces the prelude's
+    Error,
+    // Each input to a REPL may replace what earlier inputs defined. Code compiled before keeps
+    // the definition it was compiled with
+    Replace,
+}
+
+pub fn resolve(functions: &[Function]) -> Result<(), String> {
+    resolve_from(functions, 0, Redefinition::Error)
+}
+
+// Check the functions from index `start` on, which may use any of the ones before them. This is
+// for programs that grow, like those of an Engine. A name refers to its last definition
+pub fn resolve_from(functions: &[Function], start: usize, redefinition: Redefinition) -> Result<(), String> {
+    let mut definitions: HashMap<&str, Vec<usize>> = HashMap::new();
+    for (i, f) in functions.iter().enumerate() {
+        if f.name != "_top_level_expr" {
+            definitions.entry(f.name.as_str()).or_default().push(i);
+        }
+    }
+    for (i, f) in functions.iter().enumerate().skip(start) {
+        let defs = definitions.get(f.name.as_str()).map(|d| d.as_slice()).unwrap_or_default();
+        if let Some(&j) = defs.iter().rev().find(|j| **j < i) {
+            let replaces = (redefinition == Redefinition::Replace && j < start)
+                || functions[j].from_prelude;
+            if !replaces && !same_definition(&functions[j], f) {
+                return Err(format!("Redefinition of function: {}", f.name));
+            }
+        }
+
+        let mut resolver = Resolver {
+            functions,
+            definitions: &definitions,
+            scope: i,
+            bound: Scope::default(),
+            location: Vec::new(),
+            error: None,
+        };
+        resolver.visit_function(f);
+        if let Some(error) = resolver.error {
+            return Err(error);
+        }
+    }
+    Ok(())
+}
+
+// Whether two definitions are the same apart from where they are in the source, and which
+// module declared them, as modules repeat externs and operators of the main program
+pub fn same_definition(a: &Function, b: &Function) -> bool {
+    let unlocated = |f: &Function| Function {
+        module: None,
+        is_pub: false,
+        from_prelude: false,
+        ..Unlocated.fold_function(f.clone())
+    };
+    a == b || unlocated(a) == unlocated(b)
+}
+
+struct Unlocated;
+
+impl Folder for Unlocated {
+    fn fold_expr(&mut self, e: Expr) -> Expr {
+        match fold_expr_children(self, e) {
+            Expr::Located { expr, .. } => *expr,
+            e => e,
+        }
+    }
+}
+
+struct Resolver<'a> {
+    functions: &'a [Function],
+    definitions: &'a HashMap<&'a str, Vec<usize>>,
+    // Index of the function being resolved, which operators must be defined by
+    scope: usize,
+    bound: Scope,
+    // Locations of the expressions being visited, innermost last
+    location: Vec<(u32, u32)>,
+    error: Option<String>,
+}
+
+impl Resolver<'_> {
+    fn lookup(&self, name: &str) -> Option<&Function> {
+        let i = self.definitions.get(name)?.last()?;
+        Some(&self.functions[*i])
+    }
+
+    // Check that operator `name` is defined before the code being resolved, if `builtin` is
+    // false or it is defined at all
+    fn check_operator(&mut self, name: &str, kind: &str, builtin: bool) {
+        let definitions = self.definitions.get(name).map(|d| d.as_slice()).unwrap_or_default();
+        if definitions.iter().any(|i| *i <= self.scope) || (builtin && definitions.is_empty()) {
+            return;
+        }
+        if definitions.is_empty() {
+            self.fail(format!("Unknown {} operator: {}", kind, name));
+        } else {
+            self.fail(format!("{} is used before its definition", name));
+        }
+    }
+
+    // Keep the first error, which is where compiling would have stopped
+    fn fail(&mut self, message: String) {
+        if self.error.is_none() {
+            self.error = Some(match self.location.last() {
+                Some((line, col)) => format!("{}:{}: {}", line, col, message),
+                None => message,
+            });
+        }
+    }
+
+    fn check(&mut self, e: &Expr) {
+        match e {
+            Expr::Variable(name) if !self.bound.contains(name) && self.lookup(name).is_none() => {
+                self.fail(format!("Unknown variable: {}", name));
+            }
+            Expr::Call { identifier, args } if !self.bound.contains(identifier) => {
+                match self.lookup(identifier).map(|f| (f.args.len(), f.is_const)) {
+                    None => self.fail(format!("Unknown function: {}", identifier)),
+                    Some((_, true)) => self.fail(format!("{} is a constant, not a function", identifier)),
+                    Some((arity, _)) if arity != args.len() => self.fail(format!(
+                        "{} takes {} arguments, but was called with {}",
+                        identifier,
+                        arity,
+                        args.len()
+                    )),
+                    Some(_) => {}
+                }
+            }
+            Expr::BinOp { left, op: Token::Assign(_), .. } => match left.as_ref() {
+                Expr::Variable(name) if !self.bound.contains(name) => {
+                    self.fail(format!("Unknown variable: {}", name));
+                }
+                Expr::Variable(_) => {}
+                _ => self.fail(String::from("The left side of = must be a variable")),
+            },
+            Expr::BinOp { op, .. } => {
+                let c = op.operator_char().unwrap_or('?');
+                self.check_operator(&format!("binary{}", c), "binary", BUILTIN_BINARY.contains(&c));
+            }
+            Expr::Unary { op, .. } => self.check_operator(&format!("unary{}", op), "unary", false),
+            _ => {}
+        }
+    }
+}
+
+impl Visitor for Resolver<'_> {
+    fn visit_expr(&mut self, e: &Expr) {
+        self.check(e);
+        match e {
+            // The assigned variable is not read, and was checked above
+            Expr::BinOp {
+                left,
+                op: Token::Assign(_),
+                right,
+            } if matches!(left.as_ref(), Expr::Variable(_)) => self.visit_expr(right),
+            Expr::Located { line, col, expr } => {
+                self.location.push((*line, *col));
+                self.visit_expr(expr);
+                self.location.pop();
+            }
+            _ => walk_expr(self, e),
+        }
+    }
+
+    fn bind(&mut self, name: &str) {
+        self.bound.bind(name);
+    }
+
+    fn unbind(&mut self, name: &str) {
+        self.bound.unbind(name);
+    }
+}
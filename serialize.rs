@@ -0,0 +1,766 @@
+use std::fmt::Write;
+
+use crate::ast::{CType, Expr, Function, Signature};
+use crate::json::Json;
+use crate::lexer::Token;
+
+// Serialized forms of a parsed program, for tools that work on the AST. Both hold every field
+// of every function, so reading one back gives the same functions.
+//
+// JSON is an array of functions. Functions are objects with the fields of ast::Function, where
+// absent options are null, and expressions are objects tagged by "kind":
+//   {"kind": "binop", "op": "+", "left": ..., "right": ...}
+//
+// S-expressions have one function per line:
+//   (function fib (x) (if (binary < x 3) 1 (call fib (binary - x 1))) :pub)
+// Options follow the body as :operator, :precedence N, :module NAME, :pub, :library "lib",
+// :signature ((PARAM TYPES) RETURN TYPE), :test "name", :const and :prelude, only when they are set. Expressions
+// are numbers, variables, or lists headed by the kind of expression. Names that could be read
+// as something else are written as strings
+
+pub fn to_json(functions: &[Function]) -> Json {
+    Json::Array(functions.iter().map(function_to_json).collect())
+}
+
+pub fn from_json(json: &Json) -> Result<Vec<Function>, String> {
+    json.as_array()
+        .ok_or("Expected an array of functions")?
+        .iter()
+        .map(function_from_json)
+        .collect()
+}
+
+fn optional(value: Option<Json>) -> Json {
+    value.unwrap_or(Json::Null)
+}
+
+fn strings(items: &[String]) -> Json {
+    Json::Array(items.iter().map(|s| Json::string(s)).collect())
+}
+
+fn function_to_json(f: &Function) -> Json {
+    Json::object(vec![
+        ("name", Json::string(&f.name)),
+        ("args", strings(&f.args)),
+        ("body", expr_to_json(&f.body)),
+        ("is_operator", Json::Bool(f.is_operator)),
+        ("precedence", optional(f.precedence.map(Json::Number))),
+        ("module", optional(f.module.as_deref().map(Json::string))),
+        ("is_pub", Json::Bool(f.is_pub)),
+        ("library", optional(f.library.as_deref().map(Json::string))),
+        (
+            "signature",
+            optional(f.signature.as_ref().map(|sig| {
+                Json::object(vec![
+                    (
+                        "params",
+                        Json::Array(sig.params.iter().map(|t| Json::string(t.name())).collect()),
+                    ),
+                    ("ret", Json::string(sig.ret.name())),
+                ])
+            })),
+        ),
+        ("test", optional(f.test.as_deref().map(Json::string))),
+        ("is_const", Json::Bool(f.is_const)),
+        ("from_prelude", Json::Bool(f.from_prelude)),
+    ])
+}
+
+fn expr_to_json(e: &Expr) -> Json {
+    let boxed = |e: &Expr| expr_to_json(e);
+    let list = |exprs: &[Expr]| Json::Array(exprs.iter().map(expr_to_json).collect());
+    let (kind, mut fields) = match e {
+        Expr::Number(n) => ("number", vec![("value", Json::Number(*n))]),
+        Expr::Variable(name) => ("variable", vec![("name", Json::string(name))]),
+        Expr::BinOp { left, op, right } => (
+            "binop",
+            vec![
+                ("op", Json::String(op.operator_char().unwrap_or('?').to_string())),
+                ("left", boxed(left)),
+                ("right", boxed(right)),
+            ],
+        ),
+        Expr::Call { identifier, args } => (
+            "call",
+            vec![("callee", Json::string(identifier)), ("args", list(args))],
+        ),
+        Expr::If {
+            condition,
+            then,
+            els,
+        } => (
+            "if",
+            vec![
+                ("condition", boxed(condition)),
+                ("then", boxed(then)),
+                ("else", boxed(els)),
+            ],
+        ),
+        Expr::For {
+            ident,
+            start,
+            end,
+            step,
+            body,
+        } => (
+            "for",
+            vec![
+                ("var", Json::string(ident)),
+                ("start", boxed(start)),
+                ("end", boxed(end)),
+                ("step", optional(step.as_deref().map(expr_to_json))),
+                ("body", boxed(body)),
+            ],
+        ),
+        Expr::Unary { op, left } => (
+            "unary",
+            vec![("op", Json::String(op.to_string())), ("operand", boxed(left))],
+        ),
+        Expr::Var { varnames, body } => (
+            "var",
+            vec![
+                (
+                    "bindings",
+                    Json::Array(
+                        varnames
+                            .iter()
+                            .map(|(name, init)| {
+                                Json::object(vec![
+                                    ("name", Json::string(name)),
+                                    ("init", optional(init.as_ref().map(expr_to_json))),
+                                ])
+                            })
+                            .collect(),
+                    ),
+                ),
+                ("body", boxed(body)),
+            ],
+        ),
+        Expr::Lambda { params, body } => (
+            "lambda",
+            vec![("params", strings(params)), ("body", boxed(body))],
+        ),
+        Expr::Assert {
+            args,
+            text,
+            location,
+        } => (
+            "assert",
+            vec![
+                ("args", list(args)),
+                ("text", Json::string(text)),
+                ("location", Json::string(location)),
+            ],
+        ),
+        Expr::Block(exprs) => ("block", vec![("exprs", list(exprs))]),
+        Expr::Located { line, col, expr } => (
+            "located",
+            vec![
+                ("line", Json::Number(*line as f64)),
+                ("col", Json::Number(*col as f64)),
+                ("expr", boxed(expr)),
+            ],
+        ),
+        Expr::None => ("none", Vec::new()),
+    };
+    fields.insert(0, ("kind", Json::string(kind)));
+    Json::object(fields)
+}
+
+fn field<'a>(json: &'a Json, key: &str) -> Result<&'a Json, String> {
+    json.get(key).ok_or_else(|| format!("Missing field {} in {}", key, json))
+}
+
+fn string_field(json: &Json, key: &str) -> Result<String, String> {
+    field(json, key)?
+        .as_str()
+        .map(|s| s.to_string())
+        .ok_or_else(|| format!("Field {} of {} is not a string", key, json))
+}
+
+fn number_field(json: &Json, key: &str) -> Result<f64, String> {
+    field(json, key)?
+        .as_f64()
+        .ok_or_else(|| format!("Field {} of {} is not a number", key, json))
+}
+
+fn bool_field(json: &Json, key: &str) -> Result<bool, String> {
+    match field(json, key)? {
+        Json::Bool(b) => Ok(*b),
+        _ => Err(format!("Field {} of {} is not a boolean", key, json)),
+    }
+}
+
+// A field that may be null
+fn optional_field<T>(
+    json: &Json,
+    key: &str,
+    read: impl FnOnce(&Json) -> Result<T, String>,
+) -> Result<Option<T>, String> {
+    match field(json, key)? {
+        Json::Null => Ok(None),
+        value => read(value).map(Some),
+    }
+}
+
+fn array_field<T>(json: &Json, key: &str, read: impl Fn(&Json) -> Result<T, String>) -> Result<Vec<T>, String> {
+    field(json, key)?
+        .as_array()
+        .ok_or_else(|| format!("Field {} of {} is not an array", key, json))?
+        .iter()
+        .map(read)
+        .collect()
+}
+
+fn as_string(json: &Json) -> Result<String, String> {
+    json.as_str()
+        .map(|s| s.to_string())
+        .ok_or_else(|| format!("Expected a string, got {}", json))
+}
+
+fn ctype(name: &str) -> Result<CType, String> {
+    CType::from_name(name).ok_or_else(|| format!("Unknown C type: {}", name))
+}
+
+fn function_from_json(json: &Json) -> Result<Function, String> {
+    Ok(Function {
+        name: string_field(json, "name")?,
+        args: array_field(json, "args", as_string)?,
+        body: expr_from_json(field(json, "body")?)?,
+        is_operator: bool_field(json, "is_operator")?,
+        precedence: optional_field(json, "precedence", |p| {
+            p.as_f64().ok_or_else(|| format!("Expected a precedence, got {}", p))
+        })?,
+        module: optional_field(json, "module", as_string)?,
+        is_pub: bool_field(json, "is_pub")?,
+        library: optional_field(json, "library", as_string)?,
+        signature: optional_field(json, "signature", |sig| {
+            Ok(Signature {
+                params: array_field(sig, "params", |t| ctype(&as_string(t)?))?,
+                ret: ctype(&string_field(sig, "ret")?)?,
+            })
+        })?,
+        test: optional_field(json, "test", as_string)?,
+        is_const: bool_field(json, "is_const")?,
+        from_prelude: bool_field(json, "from_prelude")?,
+    })
+}
+
+fn operator_token(op: &str) -> Result<Token, String> {
+    let mut chars = op.chars();
+    match (chars.next().and_then(Token::from_char), chars.next()) {
+        (Some(tok), None) if tok.operator_char().is_some() => Ok(tok),
+        _ => Err(format!("Invalid operator {:?}", op)),
+    }
+}
+
+fn operator_char(op: &str) -> Result<char, String> {
+    operator_token(op).map(|tok| tok.operator_char().unwrap_or_default())
+}
+
+fn expr_from_json(json: &Json) -> Result<Expr, String> {
+    let boxed = |key: &str| expr_from_json(field(json, key)?).map(Box::new);
+    let list = |key: &str| array_field(json, key, expr_from_json);
+    Ok(match string_field(json, "kind")?.as_str() {
+        "number" => Expr::Number(number_field(json, "value")?),
+        "variable" => Expr::Variable(string_field(json, "name")?),
+        "binop" => Expr::BinOp {
+            left: boxed("left")?,
+            op: operator_token(&string_field(json, "op")?)?,
+            right: boxed("right")?,
+        },
+        "call" => Expr::Call {
+            identifier: string_field(json, "callee")?,
+            args: list("args")?,
+        },
+        "if" => Expr::If {
+            condition: boxed("condition")?,
+            then: boxed("then")?,
+            els: boxed("else")?,
+        },
+        "for" => Expr::For {
+            ident: string_field(json, "var")?,
+            start: boxed("start")?,
+            end: boxed("end")?,
+            step: optional_field(json, "step", |s| expr_from_json(s).map(Box::new))?,
+            body: boxed("body")?,
+        },
+        "unary" => Expr::Unary {
+            op: operator_char(&string_field(json, "op")?)?,
+            left: boxed("operand")?,
+        },
+        "var" => Expr::Var {
+            varnames: array_field(json, "bindings", |b| {
+                Ok((
+                    string_field(b, "name")?,
+                    optional_field(b, "init", expr_from_json)?,
+                ))
+            })?,
+            body: boxed("body")?,
+        },
+        "lambda" => Expr::Lambda {
+            params: array_field(json, "params", as_string)?,
+            body: boxed("body")?,
+        },
+        "assert" => Expr::Assert {
+            args: list("args")?,
+            text: string_field(json, "text")?,
+            location: string_field(json, "location")?,
+        },
+        "block" => Expr::Block(list("exprs")?),
+        "located" => Expr::Located {
+            line: number_field(json, "line")? as u32,
+            col: number_field(json, "col")? as u32,
+            expr: boxed("expr")?,
+        },
+        "none" => Expr::None,
+        kind => return Err(format!("Unknown expression kind {}", kind)),
+    })
+}
+
+pub fn to_sexpr(functions: &[Function]) -> String {
+    let mut out = String::new();
+    for f in functions {
+        write_function(&mut out, f);
+        out.push('\n');
+    }
+    out
+}
+
+pub fn from_sexpr(text: &str) -> Result<Vec<Function>, String> {
+    let mut reader = Reader { text, pos: 0 };
+    let mut functions = Vec::new();
+    while let Some(form) = reader.next()? {
+        functions.push(function_from_sexpr(&form)?);
+    }
+    Ok(functions)
+}
+
+// A name as an atom, or as a string when it would read back as a number, keyword or list
+fn write_name(out: &mut String, name: &str) {
+    let plain = !name.is_empty()
+        && !name.starts_with(':')
+        && name.parse::<f64>().is_err()
+        && !name.contains(|c: char| c.is_whitespace() || matches!(c, '(' | ')' | '"'));
+    if plain {
+        out.push_str(name);
+    } else {
+        write_string(out, name);
+    }
+}
+
+fn write_string(out: &mut String, s: &str) {
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+fn write_names(out: &mut String, names: &[String]) {
+    out.push('(');
+    for (i, name) in names.iter().enumerate() {
+        if i > 0 {
+            out.push(' ');
+        }
+        write_name(out, name);
+    }
+    out.push(')');
+}
+
+fn write_function(out: &mut String, f: &Function) {
+    out.push_str("(function ");
+    write_name(out, &f.name);
+    out.push(' ');
+    write_names(out, &f.args);
+    out.push(' ');
+    write_expr(out, &f.body);
+    if f.is_operator {
+        out.push_str(" :operator");
+    }
+    if let Some(precedence) = f.precedence {
+        let _ = write!(out, " :precedence {}", precedence);
+    }
+    if let Some(module) = &f.module {
+        out.push_str(" :module ");
+        write_name(out, module);
+    }
+    if f.is_pub {
+        out.push_str(" :pub");
+    }
+    if let Some(library) = &f.library {
+        out.push_str(" :library ");
+        write_string(out, library);
+    }
+    if let Some(sig) = &f.signature {
+        let params: Vec<&str> = sig.params.iter().map(|t| t.name()).collect();
+        let _ = write!(out, " :signature (({}) {})", params.join(" "), sig.ret.name());
+    }
+    if let Some(test) = &f.test {
+        out.push_str(" :test ");
+        write_string(out, test);
+    }
+    if f.is_const {
+        out.push_str(" :const");
+    }
+    if f.from_prelude {
+        out.push_str(" :prelude");
+    }
+    out.push(')');
+}
+
+fn write_expr(out: &mut String, e: &Expr) {
+    let list = |out: &mut String, head: &str, exprs: &[&Expr]| {
+        let _ = write!(out, "({}", head);
+        for e in exprs {
+            out.push(' ');
+            write_expr(out, e);
+        }
+        out.push(')');
+    };
+    match e {
+        Expr::Number(n) => {
+            let _ = write!(out, "{}", n);
+        }
+        Expr::Variable(name) => write_name(out, name),
+        Expr::BinOp { left, op, right } => {
+            let head = format!("binary {}", op.operator_char().unwrap_or('?'));
+            list(out, &head, &[left, right]);
+        }
+        Expr::Unary { op, left } => list(out, &format!("unary {}", op), &[left]),
+        Expr::Call { identifier, args } => {
+            let mut head = String::from("call ");
+            write_name(&mut head, identifier);
+            list(out, &head, &args.iter().collect::<Vec<_>>());
+        }
+        Expr::If {
+            condition,
+            then,
+            els,
+        } => list(out, "if", &[condition, then, els]),
+        Expr::For {
+            ident,
+            start,
+            end,
+            step,
+            body,
+        } => {
+            let mut head = String::from("for ");
+            write_name(&mut head, ident);
+            let mut exprs: Vec<&Expr> = vec![start, end];
+            exprs.extend(step.as_deref());
+            exprs.push(body);
+            list(out, &head, &exprs);
+        }
+        Expr::Var { varnames, body } => {
+            out.push_str("(var (");
+            for (i, (name, init)) in varnames.iter().enumerate() {
+                if i > 0 {
+                    out.push(' ');
+                }
+                out.push('(');
+                write_name(out, name);
+                if let Some(init) = init {
+                    out.push(' ');
+                    write_expr(out, init);
+                }
+                out.push(')');
+            }
+            out.push_str(") ");
+            write_expr(out, body);
+            out.push(')');
+        }
+        Expr::Lambda { params, body } => {
+            out.push_str("(lambda ");
+            write_names(out, params);
+            out.push(' ');
+            write_expr(out, body);
+            out.push(')');
+        }
+        Expr::Assert {
+            args,
+            text,
+            location,
+        } => {
+            let mut head = String::from("assert ");
+            write_string(&mut head, text);
+            head.push(' ');
+            write_string(&mut head, location);
+            list(out, &head, &args.iter().collect::<Vec<_>>());
+        }
+        Expr::Block(exprs) => list(out, "block", &exprs.iter().collect::<Vec<_>>()),
+        Expr::Located { line, col, expr } => list(out, &format!("located {} {}", line, col), &[expr]),
+        Expr::None => out.push_str("(none)"),
+    }
+}
+
+enum Sexpr {
+    // A bare atom, and whether it was written as a string
+    Atom(String, bool),
+    List(Vec<Sexpr>),
+}
+
+impl Sexpr {
+    fn describe(&self) -> String {
+        match self {
+            Sexpr::Atom(a, _) => a.clone(),
+            Sexpr::List(_) => String::from("a list"),
+        }
+    }
+}
+
+struct Reader<'a> {
+    text: &'a str,
+    pos: usize,
+}
+
+impl Reader<'_> {
+    fn skip_whitespace(&mut self) {
+        let rest = &self.text[self.pos..];
+        self.pos += rest.len() - rest.trim_start().len();
+    }
+
+    // The next form, or None at the end of the text
+    fn next(&mut self) -> Result<Option<Sexpr>, String> {
+        self.skip_whitespace();
+        let c = match self.text[self.pos..].chars().next() {
+            Some(c) => c,
+            None => return Ok(None),
+        };
+        match c {
+            '(' => {
+                self.pos += 1;
+                let mut items = Vec::new();
+                loop {
+                    self.skip_whitespace();
+                    if self.text[self.pos..].starts_with(')') {
+                        self.pos += 1;
+                        return Ok(Some(Sexpr::List(items)));
+                    }
+                    match self.next()? {
+                        Some(item) => items.push(item),
+                        None => return Err(String::from("Unclosed list")),
+                    }
+                }
+            }
+            ')' => Err(format!("Unexpected ) at byte {}", self.pos)),
+            '"' => {
+                self.pos += 1;
+                let mut s = String::new();
+                let mut chars = self.text[self.pos..].chars();
+                loop {
+                    let c = chars.next().ok_or("Unterminated string")?;
+                    self.pos += c.len_utf8();
+                    match c {
+                        '"' => return Ok(Some(Sexpr::Atom(s, true))),
+                        '\\' => {
+                            let escape = chars.next().ok_or("Unterminated string")?;
+                            self.pos += escape.len_utf8();
+                            s.push(if escape == 'n' { '\n' } else { escape });
+                        }
+                        c => s.push(c),
+                    }
+                }
+            }
+            _ => {
+                let rest = &self.text[self.pos..];
+                let len = rest
+                    .find(|c: char| c.is_whitespace() || matches!(c, '(' | ')' | '"'))
+                    .unwrap_or(rest.len());
+                self.pos += len;
+                Ok(Some(Sexpr::Atom(rest[..len].to_string(), false)))
+            }
+        }
+    }
+}
+
+fn atom(form: &Sexpr) -> Result<&str, String> {
+    match form {
+        Sexpr::Atom(a, _) => Ok(a),
+        Sexpr::List(_) => Err(String::from("Expected an atom, got a list")),
+    }
+}
+
+fn list(form: &Sexpr) -> Result<&[Sexpr], String> {
+    match form {
+        Sexpr::List(items) => Ok(items),
+        Sexpr::Atom(a, _) => Err(format!("Expected a list, got {}", a)),
+    }
+}
+
+fn names(form: &Sexpr) -> Result<Vec<String>, String> {
+    list(form)?
+        .iter()
+        .map(|n| atom(n).map(|s| s.to_string()))
+        .collect()
+}
+
+fn number(form: &Sexpr) -> Result<f64, String> {
+    atom(form)?
+        .parse()
+        .map_err(|_| format!("Expected a number, got {}", form.describe()))
+}
+
+fn function_from_sexpr(form: &Sexpr) -> Result<Function, String> {
+    let items = list(form)?;
+    if items.len() < 4 || atom(&items[0])? != "function" {
+        return Err(String::from("Expected (function NAME (ARGS) BODY ...)"));
+    }
+    let mut f = Function {
+        name: atom(&items[1])?.to_string(),
+        args: names(&items[2])?,
+        body: expr_from_sexpr(&items[3])?,
+        is_operator: false,
+        precedence: None,
+        module: None,
+        is_pub: false,
+        library: None,
+        signature: None,
+        test: None,
+        is_const: false,
+        from_prelude: false,
+    };
+    let mut options = items[4..].iter();
+    while let Some(option) = options.next() {
+        let mut value = || options.next().ok_or_else(|| format!("Missing value for {}", option.describe()));
+        match atom(option)? {
+            ":operator" => f.is_operator = true,
+            ":pub" => f.is_pub = true,
+            ":const" => f.is_const = true,
+            ":prelude" => f.from_prelude = true,
+            ":precedence" => f.precedence = Some(number(value()?)?),
+            ":module" => f.module = Some(atom(value()?)?.to_string()),
+            ":library" => f.library = Some(atom(value()?)?.to_string()),
+            ":test" => f.test = Some(atom(value()?)?.to_string()),
+            ":signature" => {
+                let parts = list(value()?)?;
+                if parts.len() != 2 {
+                    return Err(String::from("Expected :signature ((PARAMS) RET)"));
+                }
+                let params = list(&parts[0])?
+                    .iter()
+                    .map(|t| ctype(atom(t)?))
+                    .collect::<Result<_, _>>()?;
+                f.signature = Some(Signature {
+                    params,
+                    ret: ctype(atom(&parts[1])?)?,
+                });
+            }
+            other => return Err(format!("Unknown function option {}", other)),
+        }
+    }
+    Ok(f)
+}
+
+fn expr_from_sexpr(form: &Sexpr) -> Result<Expr, String> {
+    let items = match form {
+        Sexpr::Atom(a, false) if a.parse::<f64>().is_ok() => return number(form).map(Expr::Number),
+        Sexpr::Atom(a, _) => return Ok(Expr::Variable(a.clone())),
+        Sexpr::List(items) => items,
+    };
+    let head = items.first().ok_or("Empty expression")?;
+    let args = &items[1..];
+    let arity = |n: usize| {
+        if args.len() == n {
+            Ok(())
+        } else {
+            Err(format!("{} takes {} parts, got {}", head.describe(), n, args.len()))
+        }
+    };
+    let boxed = |i: usize| expr_from_sexpr(&args[i]).map(Box::new);
+    let exprs = |from: usize| args[from..].iter().map(expr_from_sexpr).collect::<Result<Vec<_>, _>>();
+    Ok(match atom(head)? {
+        "binary" => {
+            arity(3)?;
+            Expr::BinOp {
+                op: operator_token(atom(&args[0])?)?,
+                left: boxed(1)?,
+                right: boxed(2)?,
+            }
+        }
+        "unary" => {
+            arity(2)?;
+            Expr::Unary {
+                op: operator_char(atom(&args[0])?)?,
+                left: boxed(1)?,
+            }
+        }
+        "call" => Expr::Call {
+            identifier: atom(args.first().ok_or("call needs a callee")?)?.to_string(),
+            args: exprs(1)?,
+        },
+        "if" => {
+            arity(3)?;
+            Expr::If {
+                condition: boxed(0)?,
+                then: boxed(1)?,
+                els: boxed(2)?,
+            }
+        }
+        "for" => {
+            if !matches!(args.len(), 4 | 5) {
+                return Err(String::from("for takes 4 or 5 parts"));
+            }
+            let step = (args.len() == 5).then(|| boxed(3)).transpose()?;
+            Expr::For {
+                ident: atom(&args[0])?.to_string(),
+                start: boxed(1)?,
+                end: boxed(2)?,
+                step,
+                body: boxed(args.len() - 1)?,
+            }
+        }
+        "var" => {
+            arity(2)?;
+            let varnames = list(&args[0])?
+                .iter()
+                .map(|binding| {
+                    let parts = list(binding)?;
+                    let name = atom(parts.first().ok_or("Empty var binding")?)?.to_string();
+                    let init = parts.get(1).map(expr_from_sexpr).transpose()?;
+                    Ok((name, init))
+                })
+                .collect::<Result<_, String>>()?;
+            Expr::Var {
+                varnames,
+                body: boxed(1)?,
+            }
+        }
+        "lambda" => {
+            arity(2)?;
+            Expr::Lambda {
+                params: names(&args[0])?,
+                body: boxed(1)?,
+            }
+        }
+        "assert" => {
+            if args.len() < 2 {
+                return Err(String::from("assert needs its text and location"));
+            }
+            Expr::Assert {
+                text: atom(&args[0])?.to_string(),
+                location: atom(&args[1])?.to_string(),
+                args: exprs(2)?,
+            }
+        }
+        "block" => Expr::Block(exprs(0)?),
+        "located" => {
+            arity(3)?;
+            Expr::Located {
+                line: number(&args[0])? as u32,
+                col: number(&args[1])? as u32,
+                expr: boxed(2)?,
+            }
+        }
+        "none" => {
+            arity(0)?;
+            Expr::None
+        }
+        other => return Err(format!("Unknown expression kind {}", other)),
+    })
+}
@@ -0,0 +1,93 @@
+// Checks that compiled programs survive a round trip through the .kbc format, and that damaged
+// files are rejected instead of run
+use std::fs;
+use std::path::Path;
+
+use rust_kaleidoscope::bytecode::{self, Op, Program};
+use rust_kaleidoscope::golden;
+
+const ROOT: &str = env!("CARGO_MANIFEST_DIR");
+
+fn compile_example(name: &str) -> Program {
+    let path = Path::new(ROOT).join("examples").join(name);
+    let source = fs::read_to_string(&path).unwrap();
+    let functions = golden::parse_program(&path, &source, &[]).unwrap();
+    bytecode::compile(&functions).unwrap()
+}
+
+#[test]
+fn examples_round_trip() {
+    for file in golden::collect_files(&Path::new(ROOT).join("examples")).unwrap() {
+        let name = file.strip_prefix(Path::new(ROOT).join("examples")).unwrap();
+        let program = compile_example(name.to_str().unwrap());
+        let loaded = Program::from_bytes(&program.to_bytes()).unwrap();
+        assert_eq!(program, loaded, "{}", file.display());
+    }
+}
+
+#[test]
+fn save_and_load() {
+    let program = compile_example("closures.kls");
+    let path = Path::new(env!("CARGO_TARGET_TMPDIR")).join("closures.kbc");
+    program.save(&path).unwrap();
+    assert_eq!(Program::load(&path).unwrap(), program);
+}
+
+#[test]
+fn damaged_files_are_rejected() {
+    let bytes = compile_example("testing.kls").to_bytes();
+    for len in 0..bytes.len() {
+        assert!(Program::from_bytes(&bytes[..len]).is_err(), "truncated to {}", len);
+    }
+
+    let mut wrong_version = bytes.clone();
+    wrong_version[3] += 1;
+    let error = Program::from_bytes(&wrong_version).unwrap_err();
+    assert!(error.contains("Unsupported bytecode version"), "{}", error);
+
+    // Flipping a byte may still give a file that loads, but loading never panics
+    for i in 4..bytes.len() {
+        let mut damaged = bytes.clone();
+        damaged[i] ^= 0xff;
+        let _ = Program::from_bytes(&damaged);
+    }
+}
+
+// Damaged code that still only refers to chunks, slots and strings that exist
+#[test]
+fn damaged_code_is_rejected() {
+    let program = compile_example("closures.kls");
+    let reload = |program: &Program| Program::from_bytes(&program.to_bytes());
+    let user = |program: &Program| {
+        let chunks = &program.chunks;
+        (0..chunks.len()).find(|i| chunks[*i].name == "integrate").unwrap()
+    };
+
+    // Cut off before its Return, so running it would go past the end
+    let mut truncated = program.clone();
+    let i = user(&truncated);
+    truncated.chunks[i].code.pop();
+    let error = reload(&truncated).unwrap_err();
+    assert!(error.contains("runs past the end"), "{}", error);
+
+    // Popping what was never pushed
+    let mut underflow = program.clone();
+    underflow.chunks[i].code.insert(0, Op::Pop);
+    let error = reload(&underflow).unwrap_err();
+    assert!(error.contains("pops more values"), "{}", error);
+
+    // A closure that provides fewer values than its lambda captures
+    let mut captures = program.clone();
+    let closure = captures
+        .chunks
+        .iter_mut()
+        .flat_map(|chunk| chunk.code.iter_mut())
+        .find(|op| matches!(op, Op::Closure(_, n) if *n > 0))
+        .unwrap();
+    if let Op::Closure(_, n) = closure {
+        *n -= 1;
+    }
+    assert!(reload(&captures).is_err());
+
+    assert_eq!(reload(&program).unwrap(), program);
+}
@@ -0,0 +1,23 @@
+// Helpers shared by the integration tests
+use rust_kaleidoscope::ast::{Expr, Function};
+use rust_kaleidoscope::visitor::{walk_expr_mut, MutVisitor};
+
+// Clear the source text and location of assertions, which printing or formatting a program
+// changes, so that programs can be compared by what they do
+pub fn strip_assert_text(functions: &mut [Function]) {
+    for f in functions {
+        WithoutAssertText.visit_function_mut(f);
+    }
+}
+
+struct WithoutAssertText;
+
+impl MutVisitor for WithoutAssertText {
+    fn visit_expr_mut(&mut self, e: &mut Expr) {
+        if let Expr::Assert { text, location, .. } = e {
+            text.clear();
+            location.clear();
+        }
+        walk_expr_mut(self, e);
+    }
+}
@@ -0,0 +1,121 @@
+#![cfg(feature = "llvm")]
+// Checks the DWARF that -g attaches: the module still verifies and runs the same, and functions,
+// variables and lines are described
+use std::fs;
+use std::path::Path;
+
+use inkwell::context::Context;
+use inkwell::OptimizationLevel;
+use rust_kaleidoscope::ast::Function;
+use rust_kaleidoscope::codegen::CodegenContext;
+use rust_kaleidoscope::differential;
+use rust_kaleidoscope::externs::{self, FfiRegistry, OutputSink};
+use rust_kaleidoscope::golden;
+use rust_kaleidoscope::lexer::LexerContext;
+use rust_kaleidoscope::modules::ModuleLoader;
+use rust_kaleidoscope::parser::ParserContext;
+use rust_kaleidoscope::prelude;
+
+const ROOT: &str = env!("CARGO_MANIFEST_DIR");
+
+// Parse like `rust_kaleidoscope -g` does
+fn parse(path: &Path, source: &str) -> Vec<Function> {
+    let mut lexer = LexerContext::new();
+    lexer.file = Some(path.display().to_string());
+    lexer.lex(source).unwrap();
+
+    let mut parser = ParserContext::new();
+    prelude::load(&mut parser).unwrap();
+    parser.loader = Some(ModuleLoader::new(Vec::new(), Some(path)));
+    parser.debug_info = true;
+    parser.parse(&mut lexer).unwrap();
+    parser.functions
+}
+
+fn compile<'ctx>(context: &'ctx Context, path: &Path, functions: &[Function]) -> CodegenContext<'ctx> {
+    let mut cg = CodegenContext::new(context, "main");
+    cg.enable_debug_info(path).unwrap();
+    cg.codegen_object(functions).unwrap();
+    if let Err(e) = cg.module.verify() {
+        panic!("{}: {}", path.display(), e.to_string());
+    }
+    cg
+}
+
+#[test]
+fn examples_run_with_debug_info() {
+    let registry = FfiRegistry::new();
+    for file in golden::collect_files(&Path::new(ROOT).join("examples")).unwrap() {
+        let source = fs::read_to_string(&file).unwrap();
+        if golden::parse_expectations(&source).is_none_or(|e| !e.link.is_empty()) {
+            continue;
+        }
+        let functions = parse(&file, &source);
+        // The locations change nothing for any backend
+        let expected = differential::compare(&functions, &registry)
+            .unwrap_or_else(|e| panic!("{}: {}", file.display(), e));
+
+        let context = Context::create();
+        let mut cg = CodegenContext::new(&context, "main");
+        cg.enable_debug_info(&file).unwrap();
+        let execution_engine = cg
+            .module
+            .create_jit_execution_engine(OptimizationLevel::None)
+            .unwrap();
+        cg.codegen_functions(&functions, &registry, &execution_engine)
+            .unwrap();
+        assert!(cg.module.verify().is_ok(), "{}", file.display());
+
+        let previous = externs::set_output(OutputSink::Buffer(Vec::new()));
+        let result = unsafe {
+            execution_engine
+                .get_function::<unsafe extern "C" fn() -> f64>("main")
+                .unwrap()
+                .call()
+        };
+        let output = externs::set_output(previous).contents().unwrap_or_default();
+        externs::take_assert_failures();
+        assert_eq!(Ok(result), expected.result, "{}", file.display());
+        assert_eq!(output, expected.output, "{}", file.display());
+    }
+}
+
+#[test]
+fn functions_and_variables_are_described() {
+    let path = Path::new("described.kls");
+    let source = "def twice(x)\n  var y = x * 2 in\n    y\n\ndef sum(n)\n  var total = 0 in\n    (for i = 0, i < n in\n      total = total + i) $\n    total\n\ntwice(sum(4))\n";
+    let context = Context::create();
+    let cg = compile(&context, path, &parse(path, source));
+    let ir = cg.module.print_to_string().to_string();
+
+    for expected in [
+        "!DICompileUnit(",
+        "!DIFile(filename: \"described.kls\"",
+        "!DISubprogram(name: \"twice\"",
+        "!DISubprogram(name: \"sum\"",
+        "!DILocalVariable(name: \"x\", arg: 1,",
+        "!DILocalVariable(name: \"y\", scope: !",
+        "!DILocalVariable(name: \"total\", scope: !",
+        "!DILocalVariable(name: \"i\", scope: !",
+        "!DILocation(line: 8, column: 15,",
+        "!DILocation(line: 11, column: 1,",
+    ] {
+        assert!(ir.contains(expected), "{} is missing from\n{}", expected, ir);
+    }
+    // The prelude is not in the source file
+    assert!(!ir.contains("!DISubprogram(name: \"binary$\""), "{}", ir);
+}
+
+#[test]
+fn write_object_file() {
+    let path = Path::new(ROOT).join("examples").join("itefib.kls");
+    let functions = parse(&path, &fs::read_to_string(&path).unwrap());
+    let context = Context::create();
+    let cg = compile(&context, &path, &functions);
+
+    let object = std::env::temp_dir().join(format!("kls-debuginfo-{}.o", std::process::id()));
+    cg.write_object(&object).unwrap();
+    let size = fs::metadata(&object).unwrap().len();
+    fs::remove_file(&object).unwrap();
+    assert!(size > 0);
+}
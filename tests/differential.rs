@@ -0,0 +1,46 @@
+#![cfg(feature = "llvm")]
+// Runs programs on the JIT, the VM and the interpreter and checks that they agree, the same as
+// `rust_kaleidoscope soak` and `rust_kaleidoscope --differential`
+use std::fs;
+use std::path::Path;
+
+use rust_kaleidoscope::differential;
+use rust_kaleidoscope::externs::FfiRegistry;
+use rust_kaleidoscope::generator::ProgramGenerator;
+use rust_kaleidoscope::golden;
+
+const ROOT: &str = env!("CARGO_MANIFEST_DIR");
+
+#[test]
+fn generated_programs_agree() {
+    let registry = FfiRegistry::new();
+    for seed in 0..500 {
+        let functions = ProgramGenerator::new(seed).generate();
+        if let Err(e) = differential::compare(&functions, &registry) {
+            panic!("seed {}: {}\n{:#?}", seed, e, functions);
+        }
+    }
+}
+
+#[test]
+fn generator_is_deterministic() {
+    let first = format!("{:?}", ProgramGenerator::new(42).generate());
+    assert_eq!(first, format!("{:?}", ProgramGenerator::new(42).generate()));
+    assert_ne!(first, format!("{:?}", ProgramGenerator::new(43).generate()));
+}
+
+// Examples that need a native library are left to the golden tests
+#[test]
+fn examples_agree() {
+    let registry = FfiRegistry::new();
+    for file in golden::collect_files(&Path::new(ROOT).join("examples")).unwrap() {
+        let source = fs::read_to_string(&file).unwrap();
+        if golden::parse_expectations(&source).is_none_or(|e| !e.link.is_empty()) {
+            continue;
+        }
+        let functions = golden::parse_program(&file, &source, &[]).unwrap();
+        if let Err(e) = differential::compare(&functions, &registry) {
+            panic!("{}: {}", file.display(), e);
+        }
+    }
+}
@@ -0,0 +1,73 @@
+#![cfg(feature = "llvm")]
+// Checks the control flow graphs written by --emit=cfg-dot: every block of a function is a node
+// with its instructions, and branches are edges between them
+use std::path::Path;
+
+use inkwell::context::Context;
+use rust_kaleidoscope::codegen::CodegenContext;
+use rust_kaleidoscope::dot;
+use rust_kaleidoscope::golden;
+
+const PROGRAM: &str = "def clamp(x) if x < 0 then 0 else x\n\
+                       def count(n) var total in (for i = 0, i < n in total = total + clamp(i)) $ total\n\
+                       count(4)\n";
+
+fn graphs(optimize: bool) -> Vec<(String, String)> {
+    let functions = golden::parse_program(Path::new("dot.kls"), PROGRAM, &[]).unwrap();
+    let context = Context::create();
+    let mut cg = CodegenContext::new(&context, "main");
+    cg.codegen_object(&functions).unwrap();
+    if optimize {
+        cg.optimize().unwrap();
+    }
+    dot::module_graphs(&cg.module)
+}
+
+fn graph<'a>(graphs: &'a [(String, String)], name: &str) -> &'a str {
+    &graphs.iter().find(|(n, _)| n == name).unwrap().1
+}
+
+#[test]
+fn blocks_of_if_and_for_are_nodes() {
+    let graphs = graphs(false);
+    let clamp = graph(&graphs, "clamp");
+    assert!(clamp.starts_with("digraph \"clamp\" {\n"), "{}", clamp);
+    for block in ["entry", "then", "else", "ifcont"] {
+        assert!(clamp.contains(&format!("[label=\"{}:\\l", block)), "{}\n{}", block, clamp);
+    }
+    assert!(clamp.contains("  b0 -> b1 [label=\"true\"];\n"), "{}", clamp);
+    assert!(clamp.contains("  b0 -> b2 [label=\"false\"];\n"), "{}", clamp);
+    assert!(clamp.contains("fcmp ult double"), "{}", clamp);
+
+    let count = graph(&graphs, "count");
+    for block in ["loop", "loopbody", "afterloop"] {
+        assert!(count.contains(&format!("[label=\"{}:\\l", block)), "{}\n{}", block, count);
+    }
+    // The end condition is checked before the body, which branches back to it
+    assert!(count.contains("  b1 -> b2 [label=\"true\"];\n"), "{}", count);
+    assert!(count.contains("  b1 -> b3 [label=\"false\"];\n"), "{}", count);
+    assert!(count.contains("  b2 -> b1;\n"), "{}", count);
+}
+
+#[test]
+fn only_defined_functions_have_graphs() {
+    let names: Vec<String> = graphs(false).into_iter().map(|(name, _)| name).collect();
+    assert!(names.contains(&String::from("main")));
+    assert!(!names.contains(&String::from("printd")), "{:?}", names);
+}
+
+#[test]
+fn optimized_graphs_have_fewer_blocks() {
+    let blocks = |graph: &str| graph.lines().filter(|l| l.contains("[label=") && !l.contains("->")).count();
+    let before = graphs(false);
+    let after = graphs(true);
+    assert!(blocks(graph(&after, "clamp")) < blocks(graph(&before, "clamp")));
+    assert!(!graph(&after, "count").contains("alloca"));
+}
+
+#[test]
+fn operator_names_make_file_names() {
+    assert_eq!(dot::file_name("binary|"), "binary_7c.dot");
+    assert_eq!(dot::file_name("unary-"), "unary-.dot");
+    assert_eq!(dot::file_name("__test.0"), "__test.0.dot");
+}
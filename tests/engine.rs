@@ -0,0 +1,161 @@
+#![cfg(feature = "llvm")]
+// The embedding examples from the README
+use std::cell::Cell;
+
+use inkwell::context::Context;
+use rust_kaleidoscope::externs::{self, OutputSink};
+use rust_kaleidoscope::Engine;
+
+#[test]
+fn compile_and_call() -> Result<(), String> {
+    let context = Context::create();
+    let mut engine = Engine::new(&context)?;
+    engine.compile_str("def area(w h) w * h;")?;
+
+    let area = engine.get_function::<unsafe extern "C" fn(f64, f64) -> f64>("area")?;
+    assert_eq!(unsafe { area.call(3.0, 4.0) }, 12.0);
+    assert_eq!(engine.eval("area(2, 5) + 1")?, 11.0);
+    assert_eq!(engine.call("area", &[6.0, 7.0])?, 42.0);
+    Ok(())
+}
+
+#[test]
+fn get_function_checks_arity() -> Result<(), String> {
+    let context = Context::create();
+    let mut engine = Engine::new(&context)?;
+    engine.compile_str("def area(w h) w * h;")?;
+    assert!(engine
+        .get_function::<unsafe extern "C" fn(f64) -> f64>("area")
+        .is_err());
+    assert!(engine.call("missing", &[]).is_err());
+    Ok(())
+}
+
+#[test]
+fn failed_compile_leaves_engine_usable() -> Result<(), String> {
+    let context = Context::create();
+    let mut engine = Engine::new(&context)?;
+    assert!(engine.eval("def broken(x) y;").is_err());
+    engine.compile_str("def broken(x) x + 1;")?;
+    assert_eq!(engine.eval("broken(1)")?, 2.0);
+
+    // Names are resolved against what earlier sources defined, before compiling
+    assert!(engine.eval("def assign(x) z = x;").is_err());
+    assert_eq!(
+        engine.eval("broken(1, 2)"),
+        Err(String::from("broken takes 1 arguments, but was called with 2"))
+    );
+    assert_eq!(engine.eval("broken(2)")?, 3.0);
+
+    assert_eq!(
+        engine.eval("import \"lib.kls"),
+        Err(String::from("1:8: Unterminated string literal"))
+    );
+    assert_eq!(engine.eval("broken(3)")?, 4.0);
+    Ok(())
+}
+
+#[test]
+fn failed_compile_forgets_its_operators() -> Result<(), String> {
+    let context = Context::create();
+    let mut engine = Engine::without_prelude(&context)?;
+    engine.compile_str("def binary% 50 (a b) a - b;")?;
+    assert_eq!(engine.eval("10 % 4 * 2")?, 12.0);
+
+    // The source defines | and changes %, then fails to parse
+    assert!(engine.eval("def binary| 5 (a b) a; def binary% 5 (a b) b; def (").is_err());
+    assert_eq!(engine.eval("10 % 4 * 2")?, 12.0);
+    assert!(engine.eval("1 | 2").is_err());
+
+    // Or parses but fails to resolve
+    assert!(engine.eval("def binary% 5 (a b) b; def f(x) y;").is_err());
+    assert_eq!(engine.eval("10 % 4 * 2")?, 12.0);
+    Ok(())
+}
+
+#[test]
+fn operators_carry_over_between_sources() -> Result<(), String> {
+    let context = Context::create();
+    let mut engine = Engine::without_prelude(&context)?;
+    engine.compile_str("def binary| 5 (a b) if a then 1 else if b then 1 else 0;")?;
+    engine.compile_str("def unary!(v) if v then 0 else 1;")?;
+    assert_eq!(engine.eval("!0 | 0")?, 1.0);
+    assert_eq!(engine.eval("!1 | 0")?, 0.0);
+    Ok(())
+}
+
+#[test]
+fn later_sources_replace_definitions() -> Result<(), String> {
+    let context = Context::create();
+    let mut engine = Engine::new(&context)?;
+    engine.compile_str("def f(x) x + 1; def g(x) f(x);")?;
+    engine.compile_str("def f(x y) x * y;")?;
+    assert_eq!(engine.eval("f(3, 4)")?, 12.0);
+    assert_eq!(engine.call("f", &[2.0, 5.0])?, 10.0);
+
+    // Code compiled before keeps the definition it was compiled with
+    assert_eq!(engine.eval("g(3)")?, 4.0);
+    engine.compile_str("def f(x) x - 1;")?;
+    assert_eq!(engine.eval("f(3) + g(3)")?, 6.0);
+    Ok(())
+}
+
+#[test]
+fn host_closures() -> Result<(), String> {
+    let context = Context::create();
+    let mut engine = Engine::new(&context)?;
+    let count = Cell::new(0.0);
+    engine.registry.register("tick", move |step: f64| {
+        count.set(count.get() + step);
+        count.get()
+    });
+    assert_eq!(engine.eval("extern tick(step); tick(2) $ tick(3)")?, 5.0);
+
+    let err = engine.eval("extern tick(a b);").unwrap_err();
+    assert!(err.contains("host function takes 1"), "{}", err);
+    Ok(())
+}
+
+#[test]
+fn host_closures_named_like_math_externs() -> Result<(), String> {
+    let context = Context::create();
+    let mut engine = Engine::new(&context)?;
+    engine.registry.register("sqrt", |x: f64| x + 1.0);
+    engine.compile_str("def f(x) sqrt(x); extern sqrt(x);")?;
+    assert_eq!(engine.eval("var x = 3 in f(x)")?, 4.0);
+    Ok(())
+}
+
+#[test]
+fn captured_output() -> Result<(), String> {
+    let context = Context::create();
+    let mut engine = Engine::new(&context)?;
+    engine.set_output(OutputSink::Buffer(Vec::new()));
+    engine.eval("printd(42)")?;
+    assert_eq!(engine.take_output().contents().as_deref(), Some("42\n"));
+    Ok(())
+}
+
+#[test]
+fn assertions_and_tests() -> Result<(), String> {
+    let context = Context::create();
+    let mut engine = Engine::new(&context)?;
+    externs::take_assert_failures();
+    engine.compile_str(
+        "def sq(x) x * x;\ntest \"squares\" {\n  assert_eq(sq(3), 9)\n  assert_eq(sq(2), 5)\n}",
+    )?;
+    assert_eq!(
+        engine.tests(),
+        vec![(String::from("squares"), String::from("__test.0"))]
+    );
+
+    engine.call("__test.0", &[])?;
+    assert_eq!(
+        externs::take_assert_failures(),
+        vec![String::from("4:3: assert_eq(sq(2), 5) failed: 4 != 5")]
+    );
+
+    assert_eq!(engine.eval("assert(sq(2) < 5)")?, 1.0);
+    assert!(externs::take_assert_failures().is_empty());
+    Ok(())
+}
@@ -0,0 +1,50 @@
+// Checks how externs are found in the libraries loaded with --link-lib
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use rust_kaleidoscope::externs::FfiRegistry;
+
+// Build a library exporting `which`, which returns `value`
+fn build_library(name: &str, value: f64) -> PathBuf {
+    let dir = Path::new(env!("CARGO_TARGET_TMPDIR")).join("externs");
+    fs::create_dir_all(&dir).unwrap();
+    let source = dir.join(format!("{}.c", name));
+    fs::write(&source, format!("double which(void) {{ return {:?}; }}\n", value)).unwrap();
+    let out = dir.join(format!("{}.so", name));
+    let status = Command::new("cc")
+        .args(["-shared", "-fPIC", "-o"])
+        .arg(&out)
+        .arg(&source)
+        .status()
+        .expect("failed to run cc");
+    assert!(status.success(), "failed to build {}", out.display());
+    out
+}
+
+fn call_which(registry: &FfiRegistry, library: Option<&str>) -> f64 {
+    let addr = registry.resolve(library, "which", false).unwrap();
+    let which: extern "C" fn() -> f64 = unsafe { std::mem::transmute(addr) };
+    which()
+}
+
+#[test]
+fn first_loaded_library_wins() {
+    let first = build_library("libfirst", 1.0);
+    let second = build_library("libsecond", 2.0);
+
+    for _ in 0..5 {
+        let mut registry = FfiRegistry::new();
+        registry.load_library(&first).unwrap();
+        registry.load_library(&second).unwrap();
+        assert_eq!(registry.library_names(), ["libfirst", "libsecond"]);
+        assert_eq!(call_which(&registry, None), 1.0);
+        assert_eq!(call_which(&registry, Some("second")), 2.0);
+
+        let mut registry = FfiRegistry::new();
+        registry.load_library(&second).unwrap();
+        registry.load_library(&first).unwrap();
+        assert_eq!(call_which(&registry, None), 2.0);
+        assert_eq!(call_which(&registry, Some("libfirst")), 1.0);
+    }
+}
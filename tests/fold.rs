@@ -0,0 +1,110 @@
+// Checks that folding computes what running the program would, and leaves alone whatever it
+// can't compute without running it
+use std::path::Path;
+
+use rust_kaleidoscope::ast::{Expr, Function};
+use rust_kaleidoscope::externs::{self, FfiRegistry, OutputSink};
+use rust_kaleidoscope::fold;
+use rust_kaleidoscope::generator::ProgramGenerator;
+use rust_kaleidoscope::golden;
+use rust_kaleidoscope::interp::Interpreter;
+
+fn parse(source: &str) -> Vec<Function> {
+    golden::parse_program(Path::new("fold.kls"), source, &[]).unwrap()
+}
+
+fn folded(source: &str) -> Vec<Function> {
+    let mut functions = parse(source);
+    fold::fold_program(&mut functions).unwrap();
+    functions
+}
+
+fn body<'a>(functions: &'a [Function], name: &str) -> &'a Expr {
+    &functions.iter().rev().find(|f| f.name == name).unwrap().body
+}
+
+#[test]
+fn operators_and_calls_fold() {
+    let functions = folded("def sq(x) x * x\ndef f(x) x + sq(2 + 1) * 2\n");
+    assert_eq!(body(&functions, "f"), body(&parse("def f(x) x + 18\n"), "f"));
+}
+
+#[test]
+fn constant_conditions_choose_a_branch() {
+    let functions = folded("def f(x) if 2 < 1 then printd(x) else x * (1 + 1)\n");
+    assert_eq!(body(&functions, "f"), body(&parse("def f(x) x * 2\n"), "f"));
+
+    // Variables declared in the branch left out end with it
+    let functions = folded("def f(x) if 1 then x else (var y = 1 in y)\n");
+    assert_eq!(body(&functions, "f"), body(&parse("def f(x) x\n"), "f"));
+}
+
+#[test]
+fn observable_code_is_left_alone() {
+    let source = "def noisy(x) printd(x)\n\
+                  def spin(x) for i = 0, 1 in 0\n\
+                  def sq(x) x * x\n\
+                  def f(x) noisy(1) + spin(1)\n\
+                  def g(sq) sq(2)\n";
+    let functions = folded(source);
+    let parsed = parse(source);
+    for name in ["f", "g"] {
+        assert_eq!(body(&functions, name), body(&parsed, name), "{}", name);
+    }
+}
+
+#[test]
+fn consts_are_replaced_by_their_value() {
+    let functions = folded("const B = A * 2\nconst A = 1 + 2\ndef f(A) A + B\nB\n");
+    assert_eq!(body(&functions, "A"), &Expr::Number(3.0));
+    assert_eq!(body(&functions, "f"), body(&parse("def f(A) A + 6\n"), "f"));
+    assert_eq!(body(&functions, "_top_level_expr"), &Expr::Number(6.0));
+}
+
+#[test]
+fn consts_must_be_constant() {
+    let mut functions = parse("const X = printd(1)\n");
+    assert_eq!(
+        fold::fold_program(&mut functions),
+        Err(String::from("The value of const X is not known when compiling"))
+    );
+    let mut functions = parse("const X = Y + 1\nconst Y = X\n");
+    assert_eq!(
+        fold::fold_program(&mut functions),
+        Err(String::from("const X depends on its own value"))
+    );
+}
+
+// The result of running a program on the interpreter, and what it printed
+fn run(functions: &[Function], registry: &FfiRegistry) -> (Result<f64, String>, String) {
+    let previous = externs::set_output(OutputSink::Buffer(Vec::new()));
+    let result = Interpreter::new(functions, registry).and_then(|mut i| i.run());
+    let output = externs::set_output(previous).contents().unwrap_or_default();
+    (result, output)
+}
+
+// Generated programs call externs, loop, and declare and assign variables, so folding them
+// reaches most of the ways evaluation can stop
+#[test]
+fn folded_programs_run_the_same() {
+    let registry = FfiRegistry::new();
+    for seed in 0..300 {
+        let functions = ProgramGenerator::new(seed).generate();
+        let mut folded = functions.clone();
+        fold::fold_program(&mut folded).unwrap();
+        let before = run(&functions, &registry);
+        let after = run(&folded, &registry);
+        let same_result = match (&before.0, &after.0) {
+            (Ok(a), Ok(b)) => a.to_bits() == b.to_bits() || (a.is_nan() && b.is_nan()),
+            (a, b) => a == b,
+        };
+        assert!(
+            same_result && before.1 == after.1,
+            "seed {}: {:?} became {:?}\n{:#?}",
+            seed,
+            before,
+            after,
+            folded
+        );
+    }
+}
@@ -0,0 +1,77 @@
+// Checks that formatting keeps programs the same: every example formats to a program that parses
+// to the same functions, keeps its comments, and is left alone when formatted again
+mod common;
+
+use std::fs;
+use std::path::Path;
+
+use rust_kaleidoscope::ast::Function;
+use rust_kaleidoscope::formatter;
+use rust_kaleidoscope::golden;
+
+const ROOT: &str = env!("CARGO_MANIFEST_DIR");
+
+fn parse(path: &Path, source: &str) -> Vec<Function> {
+    let mut functions = golden::parse_program(path, source, &[])
+        .unwrap_or_else(|e| panic!("{}: {}\n{}", path.display(), e, source));
+    common::strip_assert_text(&mut functions);
+    functions
+}
+
+fn comments(source: &str) -> Vec<&str> {
+    source
+        .lines()
+        .filter_map(|line| line.find('#').map(|i| line[i..].trim_end()))
+        .collect()
+}
+
+#[test]
+fn examples_keep_their_meaning() {
+    let mut files = golden::collect_files(&Path::new(ROOT).join("examples")).unwrap();
+    files.extend(golden::collect_files(&Path::new(ROOT).join("tests").join("golden")).unwrap());
+    for file in files {
+        let source = fs::read_to_string(&file).unwrap();
+        // Programs that are expected not to compile may not parse either
+        if golden::parse_program(&file, &source, &[]).is_err() {
+            continue;
+        }
+        let formatted = formatter::format_source(&source, Some(&file), &[]).unwrap();
+        assert_eq!(parse(&file, &source), parse(&file, &formatted), "{}", file.display());
+        assert_eq!(comments(&source), comments(&formatted), "{}", file.display());
+
+        let again = formatter::format_source(&formatted, Some(&file), &[]).unwrap();
+        assert_eq!(formatted, again, "{} is not formatted the same twice", file.display());
+    }
+}
+
+#[test]
+fn layout() {
+    let source = "# Clamp\ndef clamp(x lo hi) if x<lo then lo else if hi<x then hi else x;\n\n\n\
+                  def binary% 50 (a b)  a-b*2 # op\n\
+                  def f(x) var a=1, b in for i=0,i<x in b=a+i $ a=b\n\
+                  const  K=2*3\n\
+                  f(  1 , 2, ) ;\n# end\n";
+    let expected = "# Clamp\n\
+                    def clamp(x lo hi)\n  if x < lo then\n    lo\n  else if hi < x then\n    hi\n  else\n    x;\n\n\
+                    def binary% 50 (a b) a - b * 2;  # op\n\
+                    def f(x)\n  var a = 1, b in\n  for i = 0, i < x in\n    b = a + i $ a = b;\n\
+                    const K = 2 * 3;\n\
+                    f(1, 2);\n# end\n";
+    let formatted = formatter::format_source(source, None, &[]).unwrap();
+    assert_eq!(formatted, expected);
+}
+
+#[test]
+fn long_arguments_wrap() {
+    let source = "def f(a b c d e) a\n\
+                  f(1111111111 + 1, 2222222222 + 2, 3333333333 + 3, 4444444444 + 4, 5555555555 + 5, 6)\n";
+    let expected = "def f(a b c d e) a;\n\
+                    f(1111111111 + 1, 2222222222 + 2, 3333333333 + 3, 4444444444 + 4,\n  \
+                    5555555555 + 5, 6);\n";
+    assert_eq!(formatter::format_source(source, None, &[]).unwrap(), expected);
+}
+
+#[test]
+fn programs_that_do_not_parse_are_refused() {
+    assert!(formatter::format_source("def f(x\n", None, &[]).is_err());
+}
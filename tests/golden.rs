@@ -0,0 +1,109 @@
+// Runs every annotated program in examples/ and tests/golden/ through the golden test runner on
+// every backend, the same as `rust_kaleidoscope test [--interp | --vm] --link-lib libkernels.so
+// examples tests/golden`
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use rust_kaleidoscope::golden::{self, Backend, Outcome};
+
+const ROOT: &str = env!("CARGO_MANIFEST_DIR");
+
+// Build the native library used by examples/ffi/kernels.kls
+fn build_kernels() -> PathBuf {
+    let out = Path::new(env!("CARGO_TARGET_TMPDIR")).join("libkernels.so");
+    let status = Command::new("cc")
+        .args(["-shared", "-fPIC", "-o"])
+        .arg(&out)
+        .arg(Path::new(ROOT).join("examples/ffi/kernels.c"))
+        .status()
+        .expect("failed to run cc");
+    assert!(status.success(), "failed to build libkernels.so");
+    out
+}
+
+fn run_dir(dir: &str, backend: Backend, libraries: &[PathBuf]) {
+    let files = golden::collect_files(&Path::new(ROOT).join(dir)).unwrap();
+    let mut failures = Vec::new();
+    for file in &files {
+        for (test, outcome) in golden::run_file(file, backend, libraries, &[]) {
+            let label = match test {
+                Some(name) => format!("{} \"{}\"", file.display(), name),
+                None => file.display().to_string(),
+            };
+            match outcome {
+                Outcome::Pass => {}
+                Outcome::Skip(reason) if reason == "no annotations or tests" => {}
+                Outcome::Skip(reason) => failures.push(format!("{}: skipped ({})", label, reason)),
+                Outcome::Fail(report) => failures.push(format!("{}:\n{}", label, report)),
+            }
+        }
+    }
+    assert!(failures.is_empty(), "\n{}", failures.join("\n\n"));
+}
+
+#[cfg(feature = "llvm")]
+#[test]
+fn examples() {
+    run_dir("examples", Backend::Jit, &[build_kernels()]);
+}
+
+#[cfg(feature = "llvm")]
+#[test]
+fn diagnostics() {
+    run_dir("tests/golden", Backend::Jit, &[]);
+}
+
+#[test]
+fn examples_interp() {
+    run_dir("examples", Backend::Interp, &[build_kernels()]);
+}
+
+#[test]
+fn diagnostics_interp() {
+    run_dir("tests/golden", Backend::Interp, &[]);
+}
+
+#[test]
+fn examples_vm() {
+    run_dir("examples", Backend::Vm, &[build_kernels()]);
+}
+
+#[test]
+fn diagnostics_vm() {
+    run_dir("tests/golden", Backend::Vm, &[]);
+}
+
+// Every example the README points to has to state its expected results
+#[test]
+fn readme_examples_are_annotated() {
+    let readme = fs::read_to_string(Path::new(ROOT).join("README.md")).unwrap();
+    let mut checked = 0;
+    for word in readme.split(|c: char| c.is_whitespace() || c == '`') {
+        let name = match word.strip_suffix(".kls") {
+            Some(n) if !n.is_empty() && !n.ends_with('*') => n,
+            _ => continue,
+        };
+        let path = Path::new(name).with_extension("kls");
+        let candidates = [
+            Path::new(ROOT).join(&path),
+            Path::new(ROOT).join("examples").join(&path),
+        ];
+        // Names that aren't files (such as my_program.kls in usage examples) are skipped
+        let Some(file) = candidates.iter().find(|p| p.is_file()) else {
+            continue;
+        };
+        if file.components().any(|c| c.as_os_str() == "lib") || file.ends_with("prelude.kls") {
+            continue;
+        }
+
+        let source = fs::read_to_string(file).unwrap();
+        assert!(
+            golden::parse_expectations(&source).is_some(),
+            "{} is mentioned in the README but has no # EXPECT/# OUTPUT annotations",
+            file.display()
+        );
+        checked += 1;
+    }
+    assert!(checked > 0);
+}
@@ -0,0 +1,34 @@
+// Checks that the interpreter reports what it can't run instead of crashing
+use std::path::Path;
+use std::thread;
+
+use rust_kaleidoscope::externs::FfiRegistry;
+use rust_kaleidoscope::golden;
+use rust_kaleidoscope::interp::Interpreter;
+
+fn run(source: &str) -> Result<f64, String> {
+    let functions = golden::parse_program(Path::new("interp.kls"), source, &[]).unwrap();
+    let registry = FfiRegistry::new();
+    Interpreter::new(&functions, &registry)?.run()
+}
+
+#[test]
+fn deep_recursion_is_an_error() {
+    // Test threads have a smaller stack than the main thread the limit is meant for
+    let outcome = thread::Builder::new()
+        .stack_size(32 << 20)
+        .spawn(|| {
+            let depth = |n: u32| {
+                run(&format!(
+                    "def f(n) if n < 1 then 0 else 1 + f(n - 1);\nvar x = {} in f(x);\n",
+                    n
+                ))
+            };
+            (depth(900), depth(100_000))
+        })
+        .unwrap()
+        .join()
+        .unwrap();
+    assert_eq!(outcome.0, Ok(900.0));
+    assert_eq!(outcome.1, Err(String::from("Calls are nested more than 1000 deep")));
+}
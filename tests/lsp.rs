@@ -0,0 +1,261 @@
+// Drives the language server through whole sessions, framed the way editors frame them, and
+// checks what it answers
+use std::io::Cursor;
+
+use rust_kaleidoscope::json::Json;
+use rust_kaleidoscope::lsp;
+
+const URI: &str = "file:///tmp/lsp-test.kls";
+
+fn frame(message: &Json) -> String {
+    let text = message.to_string();
+    format!("Content-Length: {}\r\n\r\n{}", text.len(), text)
+}
+
+fn request(id: u32, method: &str, params: Json) -> Json {
+    Json::object(vec![
+        ("jsonrpc", Json::string("2.0")),
+        ("id", Json::Number(id as f64)),
+        ("method", Json::string(method)),
+        ("params", params),
+    ])
+}
+
+fn notification(method: &str, params: Json) -> Json {
+    Json::object(vec![
+        ("jsonrpc", Json::string("2.0")),
+        ("method", Json::string(method)),
+        ("params", params),
+    ])
+}
+
+fn open(text: &str) -> Json {
+    notification(
+        "textDocument/didOpen",
+        Json::object(vec![(
+            "textDocument",
+            Json::object(vec![
+                ("uri", Json::string(URI)),
+                ("languageId", Json::string("kaleidoscope")),
+                ("version", Json::Number(1.0)),
+                ("text", Json::string(text)),
+            ]),
+        )]),
+    )
+}
+
+fn change(text: &str) -> Json {
+    notification(
+        "textDocument/didChange",
+        Json::object(vec![
+            (
+                "textDocument",
+                Json::object(vec![("uri", Json::string(URI)), ("version", Json::Number(2.0))]),
+            ),
+            (
+                "contentChanges",
+                Json::Array(vec![Json::object(vec![("text", Json::string(text))])]),
+            ),
+        ]),
+    )
+}
+
+fn at(id: u32, method: &str, line: u32, character: u32) -> Json {
+    request(
+        id,
+        method,
+        Json::object(vec![
+            ("textDocument", Json::object(vec![("uri", Json::string(URI))])),
+            (
+                "position",
+                Json::object(vec![
+                    ("line", Json::Number(line as f64)),
+                    ("character", Json::Number(character as f64)),
+                ]),
+            ),
+        ]),
+    )
+}
+
+// Run a session from initialize to exit, returning every message the server sent
+fn session(messages: Vec<Json>) -> Vec<Json> {
+    let mut input = frame(&request(0, "initialize", Json::object(Vec::new())));
+    input.push_str(&frame(&notification("initialized", Json::object(Vec::new()))));
+    for message in &messages {
+        input.push_str(&frame(message));
+    }
+    input.push_str(&frame(&request(999, "shutdown", Json::Null)));
+    input.push_str(&frame(&notification("exit", Json::Null)));
+
+    let mut output = Vec::new();
+    lsp::serve(&mut Cursor::new(input), &mut output, &[]).unwrap();
+    let mut output = String::from_utf8(output).unwrap();
+
+    let mut replies = Vec::new();
+    while !output.is_empty() {
+        let (header, rest) = output.split_once("\r\n\r\n").unwrap();
+        let length: usize = header
+            .strip_prefix("Content-Length: ")
+            .unwrap()
+            .parse()
+            .unwrap();
+        replies.push(Json::parse(&rest[..length]).unwrap());
+        output = rest[length..].to_string();
+    }
+    replies
+}
+
+fn response(replies: &[Json], id: u32) -> &Json {
+    replies
+        .iter()
+        .find(|r| r.get("id") == Some(&Json::Number(id as f64)))
+        .and_then(|r| r.get("result"))
+        .unwrap_or_else(|| panic!("No response to {} in {:?}", id, replies))
+}
+
+fn diagnostics(replies: &[Json]) -> Vec<&[Json]> {
+    replies
+        .iter()
+        .filter(|r| r.get("method").and_then(|m| m.as_str()) == Some("textDocument/publishDiagnostics"))
+        .map(|r| r.get("params").unwrap().get("diagnostics").unwrap().as_array().unwrap())
+        .collect()
+}
+
+fn start(range: &Json) -> (f64, f64) {
+    let start = range.get("start").unwrap();
+    (
+        start.get("line").unwrap().as_f64().unwrap(),
+        start.get("character").unwrap().as_f64().unwrap(),
+    )
+}
+
+#[test]
+fn initialize_and_shutdown() {
+    let replies = session(Vec::new());
+    let capabilities = response(&replies, 0).get("capabilities").unwrap();
+    assert_eq!(capabilities.get("textDocumentSync"), Some(&Json::Number(1.0)));
+    assert_eq!(capabilities.get("hoverProvider"), Some(&Json::Bool(true)));
+    assert_eq!(response(&replies, 999), &Json::Null);
+}
+
+#[test]
+fn unknown_requests_are_errors() {
+    let replies = session(vec![request(1, "workspace/symbol", Json::object(Vec::new()))]);
+    let error = replies
+        .iter()
+        .find(|r| r.get("id") == Some(&Json::Number(1.0)))
+        .and_then(|r| r.get("error"))
+        .unwrap();
+    assert_eq!(error.get("code"), Some(&Json::Number(-32601.0)));
+}
+
+#[test]
+fn diagnostics_follow_the_document() {
+    let replies = session(vec![
+        open("def f(x)\n  x +\n"),
+        change("def f(x)\n  x + y\n\nf(1)\n"),
+        change("def f(x)\n  x + 1\n\nf(1)\n"),
+    ]);
+    let published = diagnostics(&replies);
+    assert_eq!(published.len(), 3);
+
+    assert_eq!(published[0].len(), 1);
+    let message = published[0][0].get("message").unwrap().as_str().unwrap();
+    assert!(message.contains("primary expression"), "{}", message);
+
+    // The unknown variable is pointed at
+    assert_eq!(published[1].len(), 1);
+    let message = published[1][0].get("message").unwrap().as_str().unwrap();
+    assert!(message.contains("y"), "{}", message);
+    assert_eq!(start(published[1][0].get("range").unwrap()), (1.0, 6.0));
+
+    assert!(published[2].is_empty());
+}
+
+#[test]
+fn lexer_errors_become_diagnostics() {
+    let replies = session(vec![open("def f(x)\n  \"unterminated\n")]);
+    let published = diagnostics(&replies);
+    assert_eq!(published[0].len(), 1);
+    let message = published[0][0].get("message").unwrap().as_str().unwrap();
+    assert!(message.contains("Unterminated string literal"), "{}", message);
+    // The string is pointed at
+    assert_eq!(start(published[0][0].get("range").unwrap()), (1.0, 2.0));
+}
+
+#[test]
+fn operators_with_the_wrong_arity_become_diagnostics() {
+    let replies = session(vec![
+        open("def binary| 5 (a) a;\n"),
+        change("def binary| 5 (a b) a;\n"),
+    ]);
+    let published = diagnostics(&replies);
+    assert_eq!(published.len(), 2);
+    assert_eq!(published[0].len(), 1);
+    let message = published[0][0].get("message").unwrap().as_str().unwrap();
+    assert!(message.contains("requires exactly 2 arguments"), "{}", message);
+    assert!(published[1].is_empty());
+}
+
+#[test]
+fn navigation() {
+    let source = "def binary% 50 (LHS RHS)\n  LHS - RHS * 2\n\n\
+                  extern sin(x)\n\ndef twice(x)\n  x * 2\n\ntwice(sin(1)) % 0\n";
+    let replies = session(vec![
+        open(source),
+        at(1, "textDocument/definition", 8, 2),
+        at(2, "textDocument/hover", 8, 2),
+        at(3, "textDocument/hover", 8, 14),
+        at(4, "textDocument/hover", 8, 8),
+        at(5, "textDocument/completion", 0, 0),
+        at(6, "textDocument/documentSymbol", 0, 0),
+    ]);
+    assert!(diagnostics(&replies)[0].is_empty());
+
+    let definition = response(&replies, 1);
+    assert_eq!(definition.get("uri"), Some(&Json::string(URI)));
+    assert_eq!(start(definition.get("range").unwrap()), (5.0, 4.0));
+
+    let hover = |id| {
+        response(&replies, id)
+            .get("contents")
+            .and_then(|c| c.get("value"))
+            .and_then(|v| v.as_str())
+            .unwrap()
+            .to_string()
+    };
+    assert!(hover(2).contains("def twice(x)"), "{}", hover(2));
+    assert!(hover(3).contains("def binary% 50 (LHS RHS)"), "{}", hover(3));
+    assert!(hover(4).contains("extern sin(x)"), "{}", hover(4));
+
+    let labels: Vec<&str> = response(&replies, 5)
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|item| item.get("label").unwrap().as_str().unwrap())
+        .collect();
+    assert!(labels.contains(&"twice") && labels.contains(&"sin"), "{:?}", labels);
+    // Operators are used, not called
+    assert!(!labels.iter().any(|l| l.starts_with("binary")), "{:?}", labels);
+
+    let symbols: Vec<&str> = response(&replies, 6)
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|s| s.get("name").unwrap().as_str().unwrap())
+        .collect();
+    assert_eq!(symbols, vec!["binary%", "sin", "twice"]);
+}
+
+#[test]
+fn json_round_trip() {
+    let text = r#"{"a":[1,-2.5,true,null],"b":"line\n\"quoted\" é 😀","c":{}}"#;
+    let value = Json::parse(text).unwrap();
+    assert_eq!(
+        value.get("b").and_then(|b| b.as_str()),
+        Some("line\n\"quoted\" é 😀")
+    );
+    assert_eq!(Json::parse(&value.to_string()).unwrap(), value);
+    assert!(Json::parse("{\"a\":1,}").is_err());
+    assert!(Json::parse("[1] 2").is_err());
+}
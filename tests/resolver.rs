@@ -0,0 +1,29 @@
+// Checks where definitions may replace each other, which depends on where they came from
+use rust_kaleidoscope::lexer::LexerContext;
+use rust_kaleidoscope::parser::ParserContext;
+use rust_kaleidoscope::prelude;
+use rust_kaleidoscope::resolver;
+
+fn resolve(source: &str, with_prelude: bool) -> Result<(), String> {
+    let mut parser = ParserContext::new();
+    if with_prelude {
+        prelude::load(&mut parser)?;
+    }
+    let mut lexer = LexerContext::new();
+    lexer.lex(source)?;
+    parser.parse(&mut lexer)?;
+    resolver::resolve(&parser.functions)
+}
+
+#[test]
+fn only_prelude_definitions_are_replaced() {
+    // The first definition is the same as the prelude's, but the program wrote it
+    let source = "extern putchard(x);\ndef printnl() putchard(10);\ndef printnl() putchard(0);\n";
+    assert_eq!(
+        resolve(source, false),
+        Err(String::from("Redefinition of function: printnl"))
+    );
+
+    let source = "def printnl() putchard(0);\n";
+    assert_eq!(resolve(source, true), Ok(()));
+}
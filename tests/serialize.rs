@@ -0,0 +1,111 @@
+// Checks that the AST survives being written out: the S-expression and JSON forms read back to
+// the same functions, and printed source parses back to the same program
+mod common;
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use rust_kaleidoscope::ast::Function;
+use rust_kaleidoscope::generator::ProgramGenerator;
+use rust_kaleidoscope::golden;
+use rust_kaleidoscope::json::Json;
+use rust_kaleidoscope::parser::ParserContext;
+use rust_kaleidoscope::prelude;
+use rust_kaleidoscope::printer;
+use rust_kaleidoscope::serialize;
+
+const ROOT: &str = env!("CARGO_MANIFEST_DIR");
+
+fn prelude_len() -> usize {
+    let mut parser = ParserContext::new();
+    prelude::load(&mut parser).unwrap();
+    parser.functions.len()
+}
+
+// The functions a program defines itself, as parsed after the prelude
+fn parse_user(source: &str) -> Vec<Function> {
+    let mut functions = golden::parse_program(Path::new("roundtrip.kls"), source, &[])
+        .unwrap_or_else(|e| panic!("{}\n{}", e, source));
+    functions.drain(..prelude_len());
+    common::strip_assert_text(&mut functions);
+    functions
+}
+
+fn examples() -> Vec<PathBuf> {
+    golden::collect_files(&Path::new(ROOT).join("examples")).unwrap()
+}
+
+#[test]
+fn examples_round_trip_through_sexpr_and_json() {
+    for file in examples() {
+        let source = fs::read_to_string(&file).unwrap();
+        let functions = golden::parse_program(&file, &source, &[]).unwrap();
+
+        let sexpr = serialize::to_sexpr(&functions);
+        assert_eq!(serialize::from_sexpr(&sexpr).unwrap(), functions, "{}", file.display());
+
+        let json = serialize::to_json(&functions).to_string();
+        let read = serialize::from_json(&Json::parse(&json).unwrap()).unwrap();
+        assert_eq!(read, functions, "{}", file.display());
+    }
+}
+
+// Printed imports become definitions under qualified names, which only the module loader may
+// give, so programs with imports are left out
+#[test]
+fn printed_examples_parse_to_the_same_program() {
+    for file in examples() {
+        let source = fs::read_to_string(&file).unwrap();
+        if source.contains("import ") {
+            continue;
+        }
+        let functions = parse_user(&source);
+        let printed = printer::print_program(&functions);
+        assert_eq!(parse_user(&printed), functions, "{}:\n{}", file.display(), printed);
+    }
+}
+
+#[test]
+fn awkward_programs_print() {
+    let source = "def binary% 50 (a b) a - b\n\
+                  def f(x) (x - 1) - (x - 2) % 3 * 4 + !(x < 1)\n\
+                  def g(x) 1 + (if x then 2 else 3) + (var y = x in y * 2) + (\\a -> a)(x)\n\
+                  const K = g(1) * 2\n\
+                  f(1)\n\
+                  -f(2)\n\
+                  (g)(3)\n\
+                  test \"named\" { assert_eq(f(1), 1) (-1) }\n";
+    let functions = parse_user(source);
+    let printed = printer::print_program(&functions);
+    assert_eq!(parse_user(&printed), functions, "{}", printed);
+}
+
+#[test]
+fn odd_names_survive_sexpr() {
+    let source = "def binary( 5 (a b) a\ndef f(inf) inf\nf(1)\n";
+    let functions = golden::parse_program(Path::new("odd.kls"), source, &[]).unwrap();
+    let sexpr = serialize::to_sexpr(&functions);
+    assert!(sexpr.contains("(function f (\"inf\") \"inf\")"), "{}", sexpr);
+    assert_eq!(serialize::from_sexpr(&sexpr).unwrap(), functions);
+}
+
+#[test]
+fn malformed_input_is_refused() {
+    assert!(serialize::from_sexpr("(function f (x)").is_err());
+    assert!(serialize::from_sexpr("(function f (x) (frobnicate x))").is_err());
+    assert!(serialize::from_sexpr("(function f (x) x :unknown)").is_err());
+    assert!(serialize::from_json(&Json::parse("[{\"name\":\"f\"}]").unwrap()).is_err());
+}
+
+// Generated programs contain negative numbers, which are printed as negations, so they are
+// compared once they have been through the parser
+#[test]
+fn generated_programs_print_and_parse_back() {
+    for seed in 0..300 {
+        let generated = ProgramGenerator::new(seed).generate();
+        let printed = printer::print_program(&generated);
+        let parsed = parse_user(&printed);
+        let reprinted = printer::print_program(&parsed);
+        assert_eq!(parse_user(&reprinted), parsed, "seed {}:\n{}", seed, reprinted);
+    }
+}
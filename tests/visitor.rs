@@ -0,0 +1,140 @@
+// Small passes written on the visitor traits, checking that the walks reach every expression
+// and report scopes the way the language has them
+use std::path::Path;
+
+use rust_kaleidoscope::ast::{Expr, Function};
+use rust_kaleidoscope::externs::FfiRegistry;
+use rust_kaleidoscope::golden;
+use rust_kaleidoscope::interp::Interpreter;
+use rust_kaleidoscope::lexer::Token;
+use rust_kaleidoscope::visitor::{fold_expr_children, walk_expr, walk_expr_mut, Folder, MutVisitor, Scope, Visitor};
+
+fn parse(source: &str) -> Vec<Function> {
+    golden::parse_program(Path::new("visitor.kls"), source, &[]).unwrap()
+}
+
+fn function<'a>(functions: &'a [Function], name: &str) -> &'a Function {
+    functions.iter().find(|f| f.name == name).unwrap()
+}
+
+// Names of the functions called, in evaluation order
+#[derive(Default)]
+struct Calls(Vec<String>);
+
+impl Visitor for Calls {
+    fn visit_expr(&mut self, e: &Expr) {
+        if let Expr::Call { identifier, .. } = e {
+            self.0.push(identifier.clone());
+        }
+        walk_expr(self, e);
+    }
+}
+
+#[test]
+fn visitor_reaches_every_expression() {
+    let functions = parse(
+        "def f(x) x\n\
+         def g(x) if f(1) then (for i = f(2), f(3), f(4) in f(5)) else (var a = f(6) in \\y -> f(7))\n",
+    );
+    let mut calls = Calls::default();
+    calls.visit_function(function(&functions, "g"));
+    assert_eq!(calls.0, vec!["f", "f", "f", "f", "f", "f", "f"]);
+}
+
+#[test]
+fn scopes_follow_the_language() {
+    let functions = parse(
+        "def f(x) for i = i, i < x in var a = a + i, b = a in \\c -> a + b + c + d + i + x\n",
+    );
+    assert_eq!(
+        function(&functions, "f").body.free_variables(),
+        vec!["i", "x", "a", "d"]
+    );
+}
+
+#[test]
+fn inner_bindings_end_before_outer_ones() {
+    let mut scope = Scope::default();
+    scope.bind("x");
+    scope.bind("y");
+    scope.bind("x");
+    scope.unbind("x");
+    assert!(scope.contains("x"));
+    scope.unbind("y");
+    scope.unbind("x");
+    assert!(!scope.contains("x"));
+}
+
+// Renames a variable wherever it is not shadowed
+struct Rename {
+    from: &'static str,
+    to: &'static str,
+    shadowed: usize,
+}
+
+impl MutVisitor for Rename {
+    fn visit_expr_mut(&mut self, e: &mut Expr) {
+        if let Expr::Variable(name) = e
+            && name == self.from
+            && self.shadowed == 0
+        {
+            *name = self.to.to_string();
+        }
+        walk_expr_mut(self, e);
+    }
+
+    fn bind(&mut self, name: &str) {
+        if name == self.from {
+            self.shadowed += 1;
+        }
+    }
+
+    fn unbind(&mut self, name: &str) {
+        if name == self.from {
+            self.shadowed -= 1;
+        }
+    }
+}
+
+#[test]
+fn mut_visitor_respects_shadowing() {
+    let mut functions = parse("def f(y) y + (var x = 1 in x) + x\n");
+    let mut rename = Rename {
+        from: "x",
+        to: "y",
+        shadowed: 0,
+    };
+    functions.iter_mut().for_each(|f| rename.visit_function_mut(f));
+    let expected = parse("def f(y) y + (var x = 1 in x) + y\n");
+    assert_eq!(function(&functions, "f"), function(&expected, "f"));
+}
+
+// Desugars unary minus into a subtraction from zero
+struct Negation;
+
+impl Folder for Negation {
+    fn fold_expr(&mut self, e: Expr) -> Expr {
+        match fold_expr_children(self, e) {
+            Expr::Unary { op: '-', left } => Expr::BinOp {
+                left: Box::new(Expr::Number(0.0)),
+                op: Token::Minus('-'),
+                right: left,
+            },
+            e => e,
+        }
+    }
+}
+
+#[test]
+fn folder_rewrites_expressions() {
+    let functions: Vec<Function> = parse("def f(x) -(x * -x)\nf(3)\n")
+        .into_iter()
+        .map(|f| Negation.fold_function(f))
+        .collect();
+    let f = function(&functions, "f");
+    assert!(!format!("{:?}", f.body).contains("Unary"), "{:?}", f.body);
+
+    let registry = FfiRegistry::new();
+    let mut interp = Interpreter::new(&functions, &registry).unwrap();
+    assert_eq!(interp.run().unwrap(), 9.0);
+}
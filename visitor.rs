@@ -0,0 +1,298 @@
+use crate::ast::{Expr, Function};
+
+// Traversals of the AST, for passes that only care about some kinds of expression. A pass
+// overrides the methods it needs and calls the matching walk function to carry on into the
+// children, or leaves it out to stop there. Children are visited in evaluation order.
+//
+// The walks also report the names that expressions bind: bind() is called when a for loop
+// variable, a var binding or a lambda parameter comes into scope, and unbind() when it goes
+// out, innermost first. A var initializer sees the bindings before it, and a for loop's start
+// is outside the loop. Function arguments are bound around the body by walk_function
+
+pub trait Visitor {
+    fn visit_function(&mut self, f: &Function) {
+        walk_function(self, f);
+    }
+
+    fn visit_expr(&mut self, e: &Expr) {
+        walk_expr(self, e);
+    }
+
+    fn bind(&mut self, _name: &str) {}
+
+    fn unbind(&mut self, _name: &str) {}
+}
+
+pub fn walk_function<V: Visitor + ?Sized>(v: &mut V, f: &Function) {
+    f.args.iter().for_each(|a| v.bind(a));
+    v.visit_expr(&f.body);
+    f.args.iter().rev().for_each(|a| v.unbind(a));
+}
+
+pub fn walk_expr<V: Visitor + ?Sized>(v: &mut V, e: &Expr) {
+    match e {
+        Expr::Number(_) | Expr::Variable(_) | Expr::None => {}
+        Expr::BinOp { left, right, .. } => {
+            v.visit_expr(left);
+            v.visit_expr(right);
+        }
+        Expr::Call { args, .. } | Expr::Assert { args, .. } | Expr::Block(args) => {
+            args.iter().for_each(|a| v.visit_expr(a));
+        }
+        Expr::If {
+            condition,
+            then,
+            els,
+        } => {
+            v.visit_expr(condition);
+            v.visit_expr(then);
+            v.visit_expr(els);
+        }
+        Expr::For {
+            ident,
+            start,
+            end,
+            step,
+            body,
+        } => {
+            v.visit_expr(start);
+            v.bind(ident);
+            v.visit_expr(end);
+            if let Some(step) = step {
+                v.visit_expr(step);
+            }
+            v.visit_expr(body);
+            v.unbind(ident);
+        }
+        Expr::Var { varnames, body } => {
+            for (name, init) in varnames {
+                if let Some(init) = init {
+                    v.visit_expr(init);
+                }
+                v.bind(name);
+            }
+            v.visit_expr(body);
+            varnames.iter().rev().for_each(|(name, _)| v.unbind(name));
+        }
+        Expr::Lambda { params, body } => {
+            params.iter().for_each(|p| v.bind(p));
+            v.visit_expr(body);
+            params.iter().rev().for_each(|p| v.unbind(p));
+        }
+        Expr::Unary { left: expr, .. } | Expr::Located { expr, .. } => v.visit_expr(expr),
+    }
+}
+
+// Visitor that may change the tree in place, including replacing whole expressions
+pub trait MutVisitor {
+    fn visit_function_mut(&mut self, f: &mut Function) {
+        walk_function_mut(self, f);
+    }
+
+    fn visit_expr_mut(&mut self, e: &mut Expr) {
+        walk_expr_mut(self, e);
+    }
+
+    fn bind(&mut self, _name: &str) {}
+
+    fn unbind(&mut self, _name: &str) {}
+}
+
+pub fn walk_function_mut<V: MutVisitor + ?Sized>(v: &mut V, f: &mut Function) {
+    f.args.iter().for_each(|a| v.bind(a));
+    v.visit_expr_mut(&mut f.body);
+    f.args.iter().rev().for_each(|a| v.unbind(a));
+}
+
+pub fn walk_expr_mut<V: MutVisitor + ?Sized>(v: &mut V, e: &mut Expr) {
+    match e {
+        Expr::Number(_) | Expr::Variable(_) | Expr::None => {}
+        Expr::BinOp { left, right, .. } => {
+            v.visit_expr_mut(left);
+            v.visit_expr_mut(right);
+        }
+        Expr::Call { args, .. } | Expr::Assert { args, .. } | Expr::Block(args) => {
+            args.iter_mut().for_each(|a| v.visit_expr_mut(a));
+        }
+        Expr::If {
+            condition,
+            then,
+            els,
+        } => {
+            v.visit_expr_mut(condition);
+            v.visit_expr_mut(then);
+            v.visit_expr_mut(els);
+        }
+        Expr::For {
+            ident,
+            start,
+            end,
+            step,
+            body,
+        } => {
+            v.visit_expr_mut(start);
+            v.bind(ident);
+            v.visit_expr_mut(end);
+            if let Some(step) = step {
+                v.visit_expr_mut(step);
+            }
+            v.visit_expr_mut(body);
+            v.unbind(ident);
+        }
+        Expr::Var { varnames, body } => {
+            for (name, init) in varnames.iter_mut() {
+                if let Some(init) = init {
+                    v.visit_expr_mut(init);
+                }
+                v.bind(name);
+            }
+            v.visit_expr_mut(body);
+            varnames.iter().rev().for_each(|(name, _)| v.unbind(name));
+        }
+        Expr::Lambda { params, body } => {
+            params.iter().for_each(|p| v.bind(p));
+            v.visit_expr_mut(body);
+            params.iter().rev().for_each(|p| v.unbind(p));
+        }
+        Expr::Unary { left: expr, .. } | Expr::Located { expr, .. } => v.visit_expr_mut(expr),
+    }
+}
+
+// Rebuilds the tree from owned nodes, for passes that turn expressions into different ones,
+// such as desugaring. fold_expr() gets each expression before its children are folded
+pub trait Folder {
+    fn fold_function(&mut self, f: Function) -> Function {
+        fold_function_children(self, f)
+    }
+
+    fn fold_expr(&mut self, e: Expr) -> Expr {
+        fold_expr_children(self, e)
+    }
+
+    fn bind(&mut self, _name: &str) {}
+
+    fn unbind(&mut self, _name: &str) {}
+}
+
+pub fn fold_function_children<F: Folder + ?Sized>(folder: &mut F, mut f: Function) -> Function {
+    f.args.iter().for_each(|a| folder.bind(a));
+    f.body = folder.fold_expr(f.body);
+    f.args.iter().rev().for_each(|a| folder.unbind(a));
+    f
+}
+
+pub fn fold_expr_children<F: Folder + ?Sized>(folder: &mut F, e: Expr) -> Expr {
+    let fold = |e: Box<Expr>, folder: &mut F| Box::new(folder.fold_expr(*e));
+    match e {
+        Expr::Number(_) | Expr::Variable(_) | Expr::None => e,
+        Expr::BinOp { left, op, right } => Expr::BinOp {
+            left: fold(left, folder),
+            op,
+            right: fold(right, folder),
+        },
+        Expr::Call { identifier, args } => Expr::Call {
+            identifier,
+            args: args.into_iter().map(|a| folder.fold_expr(a)).collect(),
+        },
+        Expr::If {
+            condition,
+            then,
+            els,
+        } => Expr::If {
+            condition: fold(condition, folder),
+            then: fold(then, folder),
+            els: fold(els, folder),
+        },
+        Expr::For {
+            ident,
+            start,
+            end,
+            step,
+            body,
+        } => {
+            let start = fold(start, folder);
+            folder.bind(&ident);
+            let end = fold(end, folder);
+            let step = step.map(|step| fold(step, folder));
+            let body = fold(body, folder);
+            folder.unbind(&ident);
+            Expr::For {
+                ident,
+                start,
+                end,
+                step,
+                body,
+            }
+        }
+        Expr::Unary { op, left } => Expr::Unary {
+            op,
+            left: fold(left, folder),
+        },
+        Expr::Var { varnames, body } => {
+            let varnames: Vec<(String, Option<Expr>)> = varnames
+                .into_iter()
+                .map(|(name, init)| {
+                    let init = init.map(|init| folder.fold_expr(init));
+                    folder.bind(&name);
+                    (name, init)
+                })
+                .collect();
+            let body = fold(body, folder);
+            varnames.iter().rev().for_each(|(name, _)| folder.unbind(name));
+            Expr::Var { varnames, body }
+        }
+        Expr::Lambda { params, body } => {
+            params.iter().for_each(|p| folder.bind(p));
+            let body = fold(body, folder);
+            params.iter().rev().for_each(|p| folder.unbind(p));
+            Expr::Lambda { params, body }
+        }
+        Expr::Assert {
+            args,
+            text,
+            location,
+        } => Expr::Assert {
+            args: args.into_iter().map(|a| folder.fold_expr(a)).collect(),
+            text,
+            location,
+        },
+        Expr::Block(exprs) => Expr::Block(exprs.into_iter().map(|e| folder.fold_expr(e)).collect()),
+        Expr::Located { line, col, expr } => Expr::Located {
+            line,
+            col,
+            expr: fold(expr, folder),
+        },
+    }
+}
+
+// The names bound around the expression being visited, for passes that need to tell variables
+// from functions. A pass forwards bind() and unbind() to it. Bindings end innermost first, so
+// unbinding a name ends its last binding and uncovers any binding of it outside
+#[derive(Debug, Default)]
+pub struct Scope {
+    names: Vec<String>,
+}
+
+impl Scope {
+    pub fn contains(&self, name: &str) -> bool {
+        self.names.iter().any(|n| n == name)
+    }
+
+    pub fn names(&self) -> &[String] {
+        &self.names
+    }
+
+    pub fn bind(&mut self, name: &str) {
+        self.names.push(name.to_string());
+    }
+
+    pub fn unbind(&mut self, name: &str) {
+        if let Some(i) = self.names.iter().rposition(|n| n == name) {
+            self.names.remove(i);
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.names.clear();
+    }
+}
@@ -0,0 +1,219 @@
+use std::collections::HashMap;
+
+use crate::bytecode::{Op, Program};
+use crate::externs::{self, FfiRegistry, NativeFunction};
+
+// A stack machine running compiled bytecode. Calls push frames on a call stack of their own
+// instead of recursing, so deep recursion in a program only grows the VM's stacks
+
+// Function values are NaN-boxed like in the interpreter: a quiet NaN with this tag in the high
+// bits and the index of the closure in the low bits
+const CLOSURE_TAG: u64 = 0x7ffc_0000_0000_0000;
+const CLOSURE_TAG_MASK: u64 = 0xffff_0000_0000_0000;
+
+struct Closure {
+    chunk: u32,
+    captures: Vec<f64>,
+    lambda: bool,
+}
+
+struct Frame {
+    chunk: usize,
+    pc: usize,
+    // Position of the frame's first slot on the value stack
+    base: usize,
+}
+
+pub struct Vm<'a> {
+    program: &'a Program,
+    // Resolved externs, by chunk
+    natives: Vec<Option<NativeFunction<'a>>>,
+    closures: Vec<Closure>,
+    function_closures: HashMap<u32, f64>,
+    stack: Vec<f64>,
+    frames: Vec<Frame>,
+}
+
+impl<'a> Vm<'a> {
+    // Resolve the externs of a program against the registry
+    pub fn new(program: &'a Program, ffi_registry: &FfiRegistry) -> Result<Self, String> {
+        let natives = program
+            .chunks
+            .iter()
+            .map(|chunk| {
+                chunk
+                    .native
+                    .as_ref()
+                    .map(|f| ffi_registry.native_function(f))
+                    .transpose()
+            })
+            .collect::<Result<_, _>>()?;
+
+        Ok(Vm {
+            program,
+            natives,
+            closures: Vec::new(),
+            function_closures: HashMap::new(),
+            stack: Vec::new(),
+            frames: Vec::new(),
+        })
+    }
+
+    // Run the program, returning the value of its last top-level expression (0 if there is none)
+    pub fn run(&mut self) -> Result<f64, String> {
+        match self.program.main {
+            Some(main) => self.call(main, &[]),
+            None => Ok(0.0),
+        }
+    }
+
+    // Call a chunk of the program, such as one of its tests
+    pub fn call(&mut self, chunk: u32, args: &[f64]) -> Result<f64, String> {
+        self.stack.clear();
+        self.frames.clear();
+        self.stack.extend(args);
+        self.enter(chunk, args.len(), &[], false)?;
+        if self.frames.is_empty() {
+            // An extern, which has already run
+            return Ok(self.stack.pop().unwrap_or(0.0));
+        }
+        self.execute()
+    }
+
+    // Start a call of a chunk whose arguments are on top of the stack. Externs are called right
+    // away, leaving their result on the stack
+    fn enter(&mut self, index: u32, argc: usize, captures: &[f64], lambda: bool) -> Result<(), String> {
+        let chunk = &self.program.chunks[index as usize];
+        if let Some(native) = &self.natives[index as usize] {
+            let args = self.stack.split_off(self.stack.len() - argc);
+            self.stack.push(native.call(&chunk.name, &args)?);
+            return Ok(());
+        }
+        if chunk.arity as usize != argc {
+            let name = if lambda { "Lambda" } else { chunk.name.as_str() };
+            return Err(format!(
+                "{} takes {} arguments, but was called with {}",
+                name, chunk.arity, argc
+            ));
+        }
+        if chunk.code.is_empty() {
+            return Err(format!("{} has no code", chunk.name));
+        }
+
+        let base = self.stack.len() - argc;
+        self.stack.splice(base..base, captures.iter().copied());
+        self.stack.resize(base + chunk.locals as usize, 0.0);
+        self.frames.push(Frame {
+            chunk: index as usize,
+            pc: 0,
+            base,
+        });
+        Ok(())
+    }
+
+    fn function_closure(&mut self, chunk: u32) -> f64 {
+        if let Some(value) = self.function_closures.get(&chunk) {
+            return *value;
+        }
+        let value = self.new_closure(Closure {
+            chunk,
+            captures: Vec::new(),
+            lambda: false,
+        });
+        self.function_closures.insert(chunk, value);
+        value
+    }
+
+    fn new_closure(&mut self, closure: Closure) -> f64 {
+        self.closures.push(closure);
+        f64::from_bits(CLOSURE_TAG | (self.closures.len() - 1) as u64)
+    }
+
+    // Compiled code, and code loaded from a file once Program::load has checked it, never pops
+    // more than it pushed
+    fn pop(&mut self) -> f64 {
+        self.stack.pop().expect("value stack underflow")
+    }
+
+    fn execute(&mut self) -> Result<f64, String> {
+        let program = self.program;
+        loop {
+            let frame = self.frames.last_mut().expect("no frame to run");
+            let op = program.chunks[frame.chunk].code[frame.pc];
+            frame.pc += 1;
+            let base = frame.base;
+
+            match op {
+                Op::Const(value) => self.stack.push(value),
+                Op::Load(slot) => self.stack.push(self.stack[base + slot as usize]),
+                Op::Store(slot) => {
+                    let value = *self.stack.last().expect("value stack underflow");
+                    self.stack[base + slot as usize] = value;
+                }
+                Op::Pop => {
+                    self.pop();
+                }
+                Op::Add | Op::Sub | Op::Mul | Op::Div | Op::Less | Op::Greater => {
+                    let rhs = self.pop();
+                    let lhs = self.pop();
+                    self.stack.push(match op {
+                        Op::Add => lhs + rhs,
+                        Op::Sub => lhs - rhs,
+                        Op::Mul => lhs * rhs,
+                        Op::Div => lhs / rhs,
+                        Op::Less if lhs >= rhs => 0.0,
+                        Op::Greater if lhs <= rhs => 0.0,
+                        _ => 1.0,
+                    });
+                }
+                Op::Jump(target) => self.frames.last_mut().unwrap().pc = target as usize,
+                Op::JumpIfFalse(target) => {
+                    let cond = self.pop();
+                    if cond.is_nan() || cond == 0.0 {
+                        self.frames.last_mut().unwrap().pc = target as usize;
+                    }
+                }
+                Op::Call(index, argc) => self.enter(index, argc as usize, &[], false)?,
+                Op::CallValue(argc) => {
+                    let value = self.stack.remove(self.stack.len() - argc as usize - 1);
+                    let bits = value.to_bits();
+                    let index = (bits & !CLOSURE_TAG_MASK) as usize;
+                    if bits & CLOSURE_TAG_MASK != CLOSURE_TAG || index >= self.closures.len() {
+                        return Err(format!("Called value {} is not a function", value));
+                    }
+                    let closure = &self.closures[index];
+                    let (chunk, captures, lambda) =
+                        (closure.chunk, closure.captures.clone(), closure.lambda);
+                    self.enter(chunk, argc as usize, &captures, lambda)?;
+                }
+                Op::Function(index) => {
+                    let value = self.function_closure(index);
+                    self.stack.push(value);
+                }
+                Op::Closure(chunk, captures) => {
+                    let captures = self.stack.split_off(self.stack.len() - captures as usize);
+                    let value = self.new_closure(Closure {
+                        chunk,
+                        captures,
+                        lambda: true,
+                    });
+                    self.stack.push(value);
+                }
+                Op::Assert(what, argc) => {
+                    let args = self.stack.split_off(self.stack.len() - argc as usize);
+                    let what = &program.strings[what as usize];
+                    self.stack.push(externs::check_assert(what, &args));
+                }
+                Op::Return => {
+                    let result = self.pop();
+                    self.stack.truncate(base);
+                    self.frames.pop();
+                    if self.frames.is_empty() {
+                        return Ok(result);
+                    }
+                    self.stack.push(result);
+                }
+            }
+        }
+    }
+}